@@ -59,6 +59,26 @@ impl AABB {
         true
     }
 
+    // the part of [tmin, tmax] the ray spends inside the box, if any
+    pub fn clip(self, r: Ray, tmin: f64, tmax: f64) -> Option<(f64, f64)> {
+        let mut t_in = tmin;
+        let mut t_out = tmax;
+        for i in 0..3 {
+            let inv_d = 1.0 / r.dir.get(i);
+            let mut t0 = (self.min.get(i) - r.beg.get(i)) * inv_d;
+            let mut t1 = (self.max.get(i) - r.beg.get(i)) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_in = t_in.max(t0);
+            t_out = t_out.min(t1);
+            if t_out <= t_in {
+                return None;
+            }
+        }
+        Some((t_in, t_out))
+    }
+
     pub fn surrounding_box(box1: AABB, box2: AABB) -> AABB {
         let small = Vec3::new(
             AABB::min(box1.min.x, box2.min.x),
@@ -0,0 +1,168 @@
+use crate::checkpoint::Checkpoint;
+use crate::clamp;
+use crate::Vec3;
+use std::cmp::Ordering;
+
+// pixels darker than this are measured against it, so near-black noise does not eat the budget
+const DIM: f64 = 0.01;
+
+// spends a fixed sample budget where the pixel estimates are still noisy
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    // relative standard error below which a pixel counts as converged
+    pub threshold: f64,
+    // samples before the variance estimate of a pixel is trusted
+    pub min_spp: u32,
+    pub max_spp: u32,
+}
+
+impl Adaptive {
+    // relative standard error of the mean luminance of pixel k; splatted light paths are
+    // not part of it
+    pub fn error(acc: &Checkpoint, k: usize) -> f64 {
+        let n = acc.count[k] as f64;
+        let mean = acc.sum_y[k] / n;
+        acc.variance(k).sqrt() / mean.max(DIM)
+    }
+
+    // samples per pixel for the next pass, noisiest pixels first once the budget runs short;
+    // None when every pixel has converged or the budget is spent
+    pub fn plan(&self, acc: &Checkpoint, pass_spp: u32, budget: u64) -> Option<Vec<u32>> {
+        let used = acc.total();
+        if used >= budget {
+            return None;
+        }
+        let mut active: Vec<(f64, usize)> = (0..acc.count.len())
+            .filter(|&k| acc.count[k] < self.max_spp)
+            .filter_map(|k| {
+                let err = if acc.count[k] < self.min_spp {
+                    f64::INFINITY
+                } else {
+                    Self::error(acc, k)
+                };
+                if err > self.threshold {
+                    Some((err, k))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if active.is_empty() {
+            return None;
+        }
+
+        let left = ((budget - used) / pass_spp as u64).max(1) as usize;
+        if active.len() > left {
+            active.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+            active.truncate(left);
+        }
+        let mut counts = vec![0; acc.count.len()];
+        for (_, k) in active {
+            counts[k] = pass_spp.min(self.max_spp - acc.count[k]);
+        }
+        Some(counts)
+    }
+}
+
+// samples per pixel from blue (fewest) over green to red (most)
+pub fn heatmap(counts: &[u32]) -> Vec<Vec3> {
+    let lo = counts.iter().copied().min().unwrap_or(0) as f64;
+    let hi = counts.iter().copied().max().unwrap_or(0) as f64;
+    let ramp = |x: f64| clamp(1.5 - x.abs(), 0.0, 1.0);
+    counts
+        .iter()
+        .map(|&c| {
+            let t = if hi > lo {
+                (c as f64 - lo) / (hi - lo)
+            } else {
+                0.0
+            };
+            Vec3::new(
+                ramp(4.0 * t - 3.0),
+                ramp(4.0 * t - 2.0),
+                ramp(4.0 * t - 1.0),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTIVE: Adaptive = Adaptive {
+        threshold: 0.05,
+        min_spp: 8,
+        max_spp: 64,
+    };
+
+    // a row of pixels, each with a sample count and the standard deviation of luminance
+    // about a mean of 0.5
+    fn row(pixels: &[(u32, f64)]) -> Checkpoint {
+        let mut acc = Checkpoint::new(Vec::new(), pixels.len() as u32, 1);
+        for (k, &(count, sd)) in pixels.iter().enumerate() {
+            let n = count as f64;
+            acc.count[k] = count;
+            acc.sum_y[k] = 0.5 * n;
+            acc.sum_sq[k] = n * (sd * sd + 0.25);
+        }
+        acc
+    }
+
+    fn noisy() -> Checkpoint {
+        row(&[
+            // converged
+            (32, 0.0),
+            // too few samples to tell
+            (4, 0.0),
+            (32, 0.5),
+            // the noisiest
+            (32, 1.0),
+            (32, 0.2),
+            // a few samples short of max_spp
+            (60, 1.0),
+            // at max_spp
+            (64, 1.0),
+        ])
+    }
+
+    #[test]
+    fn plan_follows_the_error() {
+        let acc = noisy();
+        assert!(Adaptive::error(&acc, 0) < ADAPTIVE.threshold);
+        assert!(Adaptive::error(&acc, 4) > ADAPTIVE.threshold);
+        let counts = ADAPTIVE.plan(&acc, 16, 10_000).unwrap();
+        assert_eq!(counts, vec![0, 16, 16, 16, 16, 4, 0]);
+    }
+
+    #[test]
+    fn short_budget_keeps_the_noisiest() {
+        let acc = noisy();
+        // room for two pixels, the one under min_spp counts as noisier than any other
+        let counts = ADAPTIVE.plan(&acc, 16, acc.total() + 32).unwrap();
+        assert_eq!(counts, vec![0, 16, 0, 16, 0, 0, 0]);
+        // a pass always goes to at least one pixel while any budget is left
+        let counts = ADAPTIVE.plan(&acc, 16, acc.total() + 1).unwrap();
+        assert_eq!(counts, vec![0, 16, 0, 0, 0, 0, 0]);
+        assert_eq!(ADAPTIVE.plan(&acc, 16, acc.total()), None);
+    }
+
+    #[test]
+    fn converged_image_needs_no_pass() {
+        let acc = row(&[(32, 0.0), (8, 0.01), (64, 1.0)]);
+        assert_eq!(ADAPTIVE.plan(&acc, 16, 10_000), None);
+    }
+
+    #[test]
+    fn heatmap_runs_from_blue_to_red() {
+        // the jet ramp, dark blue to green to dark red
+        let colors = heatmap(&[4, 8, 12]);
+        assert_eq!(colors[0], Vec3::new(0.0, 0.0, 0.5));
+        assert_eq!(colors[1], Vec3::new(0.5, 1.0, 0.5));
+        assert_eq!(colors[2], Vec3::new(0.5, 0.0, 0.0));
+        // equal counts have nothing to tell apart
+        assert!(heatmap(&[5, 5])
+            .iter()
+            .all(|&c| c == Vec3::new(0.0, 0.0, 0.5)));
+    }
+}
@@ -0,0 +1,643 @@
+use crate::diagnostics;
+use crate::environment::Background;
+use crate::integrator::{is_black, is_light, russian_roulette, sample_emission, Heuristic};
+use crate::render::Splats;
+use crate::sampler::Sampler;
+use crate::Camera;
+use crate::HitRecord;
+use crate::Object;
+use crate::Pdf;
+use crate::Ray;
+use crate::RenderSettings;
+use crate::ScatterPdf;
+use crate::Vec3;
+use crate::INFINITY;
+
+// bidirectional path tracer after Veach's thesis and pbrt: a camera subpath and a light
+// subpath are traced independently, every pair of their vertices is connected and each
+// of these strategies is weighted against all the others that could produce the same path
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+struct Vertex<'a> {
+    kind: VertexKind,
+    p: Vec3,
+    // geometric normal, only its line matters, unused at the camera
+    n: Vec3,
+    // throughput of the subpath up to this vertex, excluding scattering here
+    beta: Vec3,
+    // area densities of sampling this vertex from its predecessor (fwd) and from its
+    // successor when the path is walked the other way (rev)
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // scattering here is specular, so no connection can go through the vertex
+    delta: bool,
+    // radiance emitted by lights and by emitters a camera subpath runs into
+    le: Vec3,
+    rec: Option<HitRecord<'a>>,
+    r_in: Ray,
+    attenuation: Vec3,
+    scatter_pdf: Option<ScatterPdf>,
+}
+
+impl<'a> Vertex<'a> {
+    // the first vertex of either subpath has no incoming ray, its r_in only carries the time
+    // of the path
+    fn camera(p: Vec3, time: f64) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            p,
+            n: Vec3::zero(),
+            beta: Vec3::new(1.0, 1.0, 1.0),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            le: Vec3::zero(),
+            rec: None,
+            r_in: Ray::new(Vec3::zero(), Vec3::zero(), time),
+            attenuation: Vec3::zero(),
+            scatter_pdf: None,
+        }
+    }
+
+    fn light(rec: HitRecord<'a>, le: Vec3, pdf_pos: f64, time: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            p: rec.p,
+            n: rec.normal,
+            beta: if pdf_pos > 0.0 {
+                le / pdf_pos
+            } else {
+                Vec3::zero()
+            },
+            pdf_fwd: pdf_pos,
+            pdf_rev: 0.0,
+            delta: false,
+            le,
+            rec: Some(rec),
+            r_in: Ray::new(Vec3::zero(), Vec3::zero(), time),
+            attenuation: Vec3::zero(),
+            scatter_pdf: None,
+        }
+    }
+
+    fn surface(rec: HitRecord<'a>, r_in: Ray, beta: Vec3) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            p: rec.p,
+            n: rec.normal,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            le: Vec3::zero(),
+            rec: Some(rec),
+            r_in,
+            attenuation: Vec3::zero(),
+            scatter_pdf: None,
+        }
+    }
+
+    // lights and diffuse surfaces; specular ones and emitters without a bsdf are left out
+    fn connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => self.scatter_pdf.is_some(),
+            _ => true,
+        }
+    }
+
+    // bsdf times the cosine towards q, for a surface vertex
+    fn fcos(&self, q: Vec3) -> Vec3 {
+        let rec = self.rec.as_ref().unwrap();
+        let scattered = Ray::new(self.p, q - self.p, self.r_in.time);
+        self.attenuation * rec.mat.unwrap().scattering_pdf(self.r_in, rec, scattered)
+    }
+}
+
+// turns a solid angle density at `from` into an area density at `to`
+fn convert_density(pdf: f64, from: &Vertex, to: &Vertex) -> f64 {
+    let w = to.p - from.p;
+    let dist_sq = w.length_squared();
+    if dist_sq == 0.0 {
+        return 0.0;
+    }
+    let mut pdf = pdf / dist_sq;
+    if to.kind != VertexKind::Camera {
+        pdf *= (to.n * w).abs() / dist_sq.sqrt();
+    }
+    pdf
+}
+
+// area density of the light at v emitting towards next, lights emit from both sides with
+// a cosine distribution
+fn pdf_light(v: &Vertex, next: &Vertex) -> f64 {
+    let w = next.p - v.p;
+    let len = w.length();
+    if len == 0.0 {
+        return 0.0;
+    }
+    let pdf_dir = (v.n * w).abs() / len / (2.0 * std::f64::consts::PI);
+    convert_density(pdf_dir, v, next)
+}
+
+pub struct Context<'a> {
+    settings: &'a RenderSettings,
+    cam: &'a Camera,
+    background: &'a Background,
+    world: &'a dyn Object,
+    lights: Option<&'a dyn Object>,
+    // film area at unit distance covered by the pixels, render_tile maps the image
+    // slightly past the viewport
+    film_area: f64,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        settings: &'a RenderSettings,
+        cam: &'a Camera,
+        background: &'a Background,
+        world: &'a dyn Object,
+        lights: Option<&'a dyn Object>,
+    ) -> Self {
+        let w = settings.image_width as f64;
+        let h = settings.image_height as f64;
+        Self {
+            settings,
+            cam,
+            background,
+            world,
+            lights,
+            film_area: cam.film_area() * w * h / ((w - 1.0) * (h - 1.0)),
+        }
+    }
+
+    // index of the pixel whose camera rays include the one leaving p on the lens along dir,
+    // the inverse of the (u, v) render_tile picks
+    fn pixel(&self, p: Vec3, dir: Vec3) -> Option<usize> {
+        let (u, v) = self.cam.film_uv(p, dir)?;
+        let width = self.settings.image_width as f64;
+        let height = self.settings.image_height as f64;
+        let col = (u * (width - 1.0)).floor();
+        let row = height - (v * (height - 1.0)).floor();
+        if col < 0.0 || col >= width || row < 0.0 || row >= height {
+            return None;
+        }
+        Some(row as usize * self.settings.image_width as usize + col as usize)
+    }
+
+    // solid angle density of the camera tracing a ray from p on the lens along dir
+    fn camera_pdf(&self, p: Vec3, dir: Vec3) -> f64 {
+        if self.pixel(p, dir).is_none() {
+            return 0.0;
+        }
+        let cos = -(dir.unit() * self.cam.w);
+        1.0 / (self.film_area * cos * cos * cos)
+    }
+
+    // area density of v sampling next, none of the bsdfs here depend on the incoming side
+    fn pdf(&self, v: &Vertex, next: &Vertex) -> f64 {
+        let dir = next.p - v.p;
+        let pdf = match v.kind {
+            VertexKind::Light => return pdf_light(v, next),
+            VertexKind::Camera => self.camera_pdf(v.p, dir),
+            VertexKind::Surface => match &v.scatter_pdf {
+                Some(p) => p.value(dir),
+                None => 0.0,
+            },
+        };
+        convert_density(pdf, v, next)
+    }
+
+    fn visible(&self, a: Vec3, b: Vec3, time: f64) -> bool {
+        let d = b - a;
+        let dist = d.length();
+        self.world
+            .hit(Ray::new(a, d / dist, time), 0.001, dist - 0.001)
+            .is_none()
+    }
+
+    // extends path from its last vertex along ray, up to max_len vertices; returns the
+    // background radiance picked up if the walk leaves the scene
+    fn random_walk(
+        &self,
+        mut ray: Ray,
+        mut beta: Vec3,
+        mut pdf_fwd: f64,
+        max_len: usize,
+        path: &mut Vec<Vertex<'a>>,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let mut bounce = 0;
+        while path.len() < max_len {
+            let rec = match self.world.hit(ray, 0.001, INFINITY) {
+                Some(rec) => rec,
+                None => return Vec3::elemul(beta, self.background.radiance(ray.dir)),
+            };
+            let mat = rec.mat.unwrap();
+            let mut v = Vertex::surface(rec, ray, beta);
+            v.pdf_fwd = convert_density(pdf_fwd, path.last().unwrap(), &v);
+            v.le = mat.emitted(&rec, rec.u, rec.v, rec.p);
+            let s = mat.scatter(ray, &rec, sampler);
+            if !s.jud {
+                diagnostics::log(|| {
+                    format!(
+                        "{:?} vertex {}: {} at {}, pdf fwd {}, beta {}, emitting {}",
+                        path[0].kind,
+                        path.len(),
+                        mat.name(),
+                        v.p,
+                        v.pdf_fwd,
+                        v.beta,
+                        v.le
+                    )
+                });
+                path.push(v);
+                break;
+            }
+            v.attenuation = s.attenustion;
+            let pdf_rev = if s.is_specular {
+                v.delta = true;
+                pdf_fwd = 0.0;
+                beta = Vec3::elemul(beta, s.attenustion);
+                ray = s.scattered;
+                0.0
+            } else {
+                let sp = s.pdf.unwrap();
+                let dir = sp.generate(sampler);
+                let scattered = Ray::new(rec.p, dir, ray.time);
+                pdf_fwd = sp.value(dir);
+                let pdf_rev = sp.value(-ray.dir);
+                if pdf_fwd > 0.0 {
+                    beta = Vec3::elemul(
+                        beta,
+                        s.attenustion * (mat.scattering_pdf(ray, &rec, scattered) / pdf_fwd),
+                    );
+                }
+                v.scatter_pdf = Some(sp);
+                ray = scattered;
+                pdf_rev
+            };
+            let prev = path.len() - 1;
+            let rev = convert_density(pdf_rev, &v, &path[prev]);
+            path[prev].pdf_rev = rev;
+            diagnostics::log(|| {
+                format!(
+                    "{:?} vertex {}: {} at {}, normal {}, pdf fwd {}, beta {}, delta {}",
+                    path[0].kind,
+                    path.len(),
+                    mat.name(),
+                    v.p,
+                    v.n,
+                    v.pdf_fwd,
+                    v.beta,
+                    v.delta
+                )
+            });
+            path.push(v);
+            if pdf_fwd <= 0.0 && !s.is_specular {
+                break;
+            }
+            match russian_roulette(self.settings, bounce, beta, sampler) {
+                Some(p) => beta = beta / p,
+                None => break,
+            }
+            bounce += 1;
+        }
+        Vec3::zero()
+    }
+
+    // one sample for the camera ray through (u, v); light subpaths connecting straight to the
+    // camera may land on any pixel, those contributions are added to splat instead
+    pub fn sample(&self, u: f64, v: f64, splat: &mut Splats, sampler: &mut dyn Sampler) -> Vec3 {
+        let max_depth = self.settings.max_depth.max(0) as usize;
+        let ray = self.cam.get_ray(u, v, sampler);
+        let mut camera = Vec::with_capacity(max_depth + 2);
+        camera.push(Vertex::camera(ray.beg, ray.time));
+        let pdf_dir = self.camera_pdf(ray.beg, ray.dir);
+        let mut radiance = self.random_walk(
+            ray,
+            Vec3::new(1.0, 1.0, 1.0),
+            pdf_dir,
+            max_depth + 2,
+            &mut camera,
+            sampler,
+        );
+
+        let mut light = Vec::with_capacity(max_depth + 1);
+        if let Some(e) = self
+            .lights
+            .and_then(|l| sample_emission(l, ray.time, sampler))
+        {
+            light.push(Vertex::light(e.rec, e.le, e.pdf_pos, ray.time));
+            self.random_walk(
+                e.ray,
+                e.beta(),
+                e.pdf_dir,
+                max_depth + 1,
+                &mut light,
+                sampler,
+            );
+        }
+
+        // s = 1 samples a fresh point on the lights, so it is available without a light subpath
+        let max_s = if self.lights.is_some() {
+            light.len().max(1)
+        } else {
+            0
+        };
+        for t in 1..=camera.len() {
+            for s in 0..=max_s {
+                let depth = (s + t) as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.settings.max_depth {
+                    continue;
+                }
+                let l = self.connect(&light, &camera, s, t, splat, sampler);
+                diagnostics::log(|| format!("s {} t {}: {}", s, t, l));
+                radiance += l;
+            }
+        }
+        radiance
+    }
+
+    // joins the first s light and the first t camera vertices into one path
+    fn connect(
+        &self,
+        light: &[Vertex<'a>],
+        camera: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+        splat: &mut Splats,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let pt = &camera[t - 1];
+        let time = pt.r_in.time;
+        if s == 0 {
+            if pt.kind != VertexKind::Surface || is_black(pt.le) {
+                return Vec3::zero();
+            }
+            let l = Vec3::elemul(pt.beta, pt.le);
+            return l * self.mis_weight(light, camera, None, s, t);
+        }
+
+        if t == 1 {
+            let qs = &light[s - 1];
+            if !qs.connectible() {
+                return Vec3::zero();
+            }
+            let lens = self.cam.sample_lens(sampler);
+            let dir = qs.p - lens;
+            let pixel = match self.pixel(lens, dir) {
+                Some(pixel) => pixel,
+                None => return Vec3::zero(),
+            };
+            // importance over the film is 1 / (film_area * cos^4), the lens pdf cancels
+            let cos = -(dir.unit() * self.cam.w);
+            let l = Vec3::elemul(qs.beta, qs.fcos(lens))
+                / (self.film_area * cos * cos * cos * dir.length_squared());
+            if is_black(l) || !self.visible(qs.p, lens, time) {
+                return Vec3::zero();
+            }
+            let sampled = Vertex::camera(lens, time);
+            splat.add(
+                pixel,
+                l * self.mis_weight(light, camera, Some(&sampled), s, t),
+            );
+            return Vec3::zero();
+        }
+
+        if !pt.connectible() {
+            return Vec3::zero();
+        }
+
+        if s == 1 {
+            // next-event estimation through the light list, as the path tracer does it
+            let lights = self.lights.unwrap();
+            let dir = lights.random(pt.p, sampler);
+            let pdf = lights.pdf_value(pt.p, dir);
+            let shadow_ray = Ray::new(pt.p, dir, time);
+            let lrec = match lights.hit(shadow_ray, 0.001, INFINITY) {
+                Some(lrec) => lrec,
+                None => return Vec3::zero(),
+            };
+            if pdf <= 0.0 {
+                return Vec3::zero();
+            }
+            let le = lrec.mat.unwrap().emitted(&lrec, lrec.u, lrec.v, lrec.p);
+            let l = Vec3::elemul(Vec3::elemul(pt.beta, pt.fcos(lrec.p)), le) / pdf;
+            if is_black(l)
+                || self
+                    .world
+                    .hit(shadow_ray, 0.001, lrec.t * (1.0 - 1e-6))
+                    .is_some()
+            {
+                return Vec3::zero();
+            }
+            // weighted as if the point came from sample_surface, like every other strategy
+            // assumes for the start of a light subpath
+            let sampled = Vertex::light(lrec, le, lights.surface_pdf(pt.p, dir), time);
+            return l * self.mis_weight(light, camera, Some(&sampled), s, t);
+        }
+
+        let qs = &light[s - 1];
+        if !qs.connectible() {
+            return Vec3::zero();
+        }
+        let d = qs.p - pt.p;
+        let l = Vec3::elemul(
+            Vec3::elemul(qs.beta, qs.fcos(pt.p)),
+            Vec3::elemul(pt.beta, pt.fcos(qs.p)),
+        ) / d.length_squared();
+        if is_black(l) || !self.visible(pt.p, qs.p, time) {
+            return Vec3::zero();
+        }
+        l * self.mis_weight(light, camera, None, s, t)
+    }
+
+    // weight of strategy (s, t) among all strategies that could sample the same path, sampled
+    // stands in for the connection vertex a strategy with s == 1 or t == 1 picked itself
+    fn mis_weight(
+        &self,
+        light: &[Vertex<'a>],
+        camera: &[Vertex<'a>],
+        sampled: Option<&Vertex<'a>>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light[s - 1]),
+        };
+        let pt = if t == 1 {
+            sampled.unwrap()
+        } else {
+            &camera[t - 1]
+        };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+
+        // (pdf_fwd, pdf_rev, delta) of every vertex as this strategy sees the path
+        let mut cam: Vec<(f64, f64, bool)> = (0..t)
+            .map(|i| {
+                let v = if i == t - 1 { pt } else { &camera[i] };
+                (v.pdf_fwd, v.pdf_rev, v.delta)
+            })
+            .collect();
+        let mut lig: Vec<(f64, f64, bool)> = (0..s)
+            .map(|i| {
+                let v = if i == s - 1 { qs.unwrap() } else { &light[i] };
+                (v.pdf_fwd, v.pdf_rev, v.delta)
+            })
+            .collect();
+
+        cam[t - 1].1 = match qs {
+            Some(qs) => self.pdf(qs, pt),
+            None => {
+                // only emitters on the light list can start a light subpath
+                let origin = match (self.lights, pt_minus) {
+                    (Some(lights), Some(pm))
+                        if is_light(Ray::new(pm.p, pt.p - pm.p, pm.r_in.time), 1.0, lights) =>
+                    {
+                        lights.surface_pdf(pm.p, pt.p - pm.p)
+                    }
+                    _ => 0.0,
+                };
+                if origin == 0.0 {
+                    return 1.0;
+                }
+                origin
+            }
+        };
+        cam[t - 1].2 = false;
+        if let Some(pm) = pt_minus {
+            cam[t - 2].1 = match qs {
+                Some(_) => self.pdf(pt, pm),
+                None => pdf_light(pt, pm),
+            };
+        }
+        if let Some(qs) = qs {
+            lig[s - 1].1 = self.pdf(pt, qs);
+            lig[s - 1].2 = false;
+            if let Some(qm) = qs_minus {
+                lig[s - 2].1 = self.pdf(qs, qm);
+            }
+        }
+
+        let h = |r: f64| match self.settings.mis {
+            Heuristic::Balance => r,
+            Heuristic::Power => r * r,
+        };
+        let remap0 = |p: f64| if p != 0.0 { p } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(cam[i].1) / remap0(cam[i].0);
+            if !cam[i].2 && !cam[i - 1].2 {
+                sum += h(ri);
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(lig[i].1) / remap0(lig[i].0);
+            let prev_delta = i > 0 && lig[i - 1].2;
+            if !lig[i].2 && !prev_delta {
+                sum += h(ri);
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::integrator::Integrator;
+    use crate::render::{self, Pass};
+    use crate::sampler::{SamplerKind, Sampling};
+    use crate::{Background, Camera, HittableList, Object, RenderSettings, Vec3};
+    use crate::{DiffuseLight, Lambertian, SolidColor, XYRect};
+    use indicatif::ProgressBar;
+    use std::sync::Arc;
+
+    const SIZE: u32 = 16;
+    const SPP: u32 = 256;
+
+    // mean over the image of a render of a gray floor lit by a small square light above it,
+    // seen at an angle so the light's underside is in view too
+    fn mean(integrator: Integrator) -> Vec3 {
+        let floor = XYRect::new(
+            -4.0,
+            4.0,
+            -4.0,
+            4.0,
+            0.0,
+            Lambertian::new(SolidColor::new(Vec3::new(0.5, 0.5, 0.5))),
+        );
+        let light: Arc<dyn Object> = Arc::new(XYRect::new(
+            -0.2,
+            0.2,
+            -0.2,
+            0.2,
+            1.0,
+            DiffuseLight::new(SolidColor::new(Vec3::new(10.0, 10.0, 10.0))),
+        ));
+        let mut world = HittableList::new();
+        world.add(Arc::new(floor));
+        world.add(light.clone());
+        let cam = Camera::new(
+            Vec3::new(0.0, -3.0, 0.5),
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, 0.0, 1.0),
+            60.0,
+            1.0,
+            0.0,
+            3.0,
+        );
+        let settings = RenderSettings {
+            samples_per_pixel: SPP,
+            threads: 4,
+            ..RenderSettings::test(SIZE, SIZE, integrator)
+        };
+        let pixel_count = (SIZE * SIZE) as usize;
+        let pass = Pass {
+            counts: vec![SPP; pixel_count],
+            first: vec![0; pixel_count],
+            sampling: Sampling::new(SamplerKind::Independent, 1, SPP),
+            round: 0,
+        };
+        let samples = render::render(
+            &settings,
+            cam,
+            Background::Color(Vec3::zero()),
+            Arc::new(world),
+            Some(light),
+            Arc::new(pass),
+            &ProgressBar::hidden(),
+        );
+        let mut sum = Vec3::zero();
+        for k in 0..pixel_count {
+            sum += samples.sum[k] / samples.weight[k] + samples.splat[k] / SPP as f64;
+        }
+        sum / pixel_count as f64
+    }
+
+    #[test]
+    fn converges_to_the_path_tracer() {
+        let path = mean(Integrator::Path);
+        let bdpt = mean(Integrator::Bdpt);
+        assert!(path.x > 0.01, "the floor is lit, {}", path);
+        assert!(
+            (bdpt.x - path.x).abs() < 0.02 * path.x,
+            "bdpt {} against path {}",
+            bdpt,
+            path
+        );
+    }
+}
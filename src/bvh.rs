@@ -5,6 +5,7 @@ use crate::Ray;
 use crate::AABB;
 use std::sync::Arc;
 extern crate rand;
+use rand::rngs::StdRng;
 use rand::Rng;
 
 pub struct BvhNode {
@@ -21,17 +22,22 @@ impl BvhNode {
             right: r,
         }
     }
-    pub fn new(world: HittableList, t0: f64, t1: f64) -> Self {
-        BvhNode::build(world.objects, world.num as u32, t0, t1)
+    pub fn new(world: HittableList, t0: f64, t1: f64, rng: &mut StdRng) -> Self {
+        BvhNode::build(world.objects, world.num as u32, t0, t1, rng)
     }
 
-    pub fn random_int(min: u32, max: u32) -> u32 {
-        let mut rng = rand::thread_rng();
+    pub fn random_int(min: u32, max: u32, rng: &mut StdRng) -> u32 {
         rng.gen_range(min, max + 1) as u32
     }
 
-    pub fn build(mut objects: Vec<Arc<dyn Object>>, object_span: u32, t0: f64, t1: f64) -> Self {
-        let axis = BvhNode::random_int(0, 2);
+    pub fn build(
+        mut objects: Vec<Arc<dyn Object>>,
+        object_span: u32,
+        t0: f64,
+        t1: f64,
+        rng: &mut StdRng,
+    ) -> Self {
+        let axis = BvhNode::random_int(0, 2, rng);
         let left: Arc<dyn Object>;
         let right: Arc<dyn Object>;
         if object_span == 1 as u32 {
@@ -39,26 +45,32 @@ impl BvhNode {
             right = left.clone();
         } else if object_span == 2 as u32 {
             objects.sort_by(|a, b| {
-                let bo_a = a.bounding_box().unwrap().min.get(axis);
-                let bo_b = b.bounding_box().unwrap().min.get(axis);
+                let bo_a = a.bounding_box(t0, t1).unwrap().min.get(axis);
+                let bo_b = b.bounding_box(t0, t1).unwrap().min.get(axis);
                 bo_a.partial_cmp(&bo_b).unwrap()
             });
             right = objects.remove(1);
             left = objects.remove(0);
         } else {
             objects.sort_by(|a, b| {
-                let bo_a = a.bounding_box().unwrap().min.get(axis);
-                let bo_b = b.bounding_box().unwrap().min.get(axis);
+                let bo_a = a.bounding_box(t0, t1).unwrap().min.get(axis);
+                let bo_b = b.bounding_box(t0, t1).unwrap().min.get(axis);
                 bo_a.partial_cmp(&bo_b).unwrap()
             });
             let mid = object_span / 2;
             let (object1, object2) = objects.split_at_mut(mid as usize);
 
-            left = Arc::new(BvhNode::build(object1.to_vec(), mid, t0, t1));
-            right = Arc::new(BvhNode::build(object2.to_vec(), object_span - mid, t0, t1));
+            left = Arc::new(BvhNode::build(object1.to_vec(), mid, t0, t1, rng));
+            right = Arc::new(BvhNode::build(
+                object2.to_vec(),
+                object_span - mid,
+                t0,
+                t1,
+                rng,
+            ));
         }
-        let box_left = left.bounding_box();
-        let box_right = right.bounding_box();
+        let box_left = left.bounding_box(t0, t1);
+        let box_right = right.bounding_box(t0, t1);
         BvhNode::new_(
             AABB::surrounding_box(box_left.unwrap(), box_right.unwrap()),
             left,
@@ -99,7 +111,21 @@ impl Object for BvhNode {
         }
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(self.box_)
     }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        if !self.box_.hit(r, t_min, t_max) {
+            return 1.0;
+        }
+        let left = self.left.transmittance(r, t_min, t_max);
+        // a single object sits on both sides; compared by data pointer, as the vtables of one
+        // type need not be unique
+        let same = Arc::as_ptr(&self.left) as *const u8 == Arc::as_ptr(&self.right) as *const u8;
+        if left == 0.0 || same {
+            return left;
+        }
+        left * self.right.transmittance(r, t_min, t_max)
+    }
 }
@@ -1,3 +1,4 @@
+use crate::sampler::Sampler;
 use crate::Ray;
 use crate::Vec3;
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -10,6 +11,9 @@ pub struct Camera {
     pub v: Vec3,
     pub w: Vec3,
     pub lens_radius: f64,
+    // the shutter is open from time0 to time1, each ray leaves at a random moment in between
+    pub time0: f64,
+    pub time1: f64,
 }
 
 impl Camera {
@@ -43,15 +47,63 @@ impl Camera {
             u: uu,
             v: vv,
             lens_radius: aperture / 2.0,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 
-    pub fn get_ray(&self, u: f64, v: f64) -> Ray {
-        let rd = Vec3::random_in_unit_disk() * self.lens_radius;
+    pub fn with_shutter(mut self, time0: f64, time1: f64) -> Self {
+        self.time0 = time0;
+        self.time1 = time1;
+        self
+    }
+
+    // a moment while the shutter is open; draws no sample when it opens for an instant only
+    pub fn sample_time(&self, s: &mut dyn Sampler) -> f64 {
+        if self.time1 > self.time0 {
+            self.time0 + (self.time1 - self.time0) * s.get_1d()
+        } else {
+            self.time0
+        }
+    }
+
+    pub fn get_ray(&self, u: f64, v: f64, s: &mut dyn Sampler) -> Ray {
+        let rd = Vec3::random_in_unit_disk(s) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin - offset,
+            self.sample_time(s),
         )
     }
+
+    // a point on the lens, distributed as the ray origins of get_ray
+    pub fn sample_lens(&self, s: &mut dyn Sampler) -> Vec3 {
+        let rd = Vec3::random_in_unit_disk(s) * self.lens_radius;
+        self.origin + self.u * rd.x + self.v * rd.y
+    }
+
+    fn focus_dist(&self) -> f64 {
+        (self.origin - self.lower_left_corner) * self.w
+    }
+
+    // area of the viewport scaled to unit distance from the lens
+    pub fn film_area(&self) -> f64 {
+        let d = self.focus_dist();
+        self.horizontal.length() * self.vertical.length() / (d * d)
+    }
+
+    // the (u, v) for which get_ray may return the ray leaving p on the lens along dir
+    pub fn film_uv(&self, p: Vec3, dir: Vec3) -> Option<(f64, f64)> {
+        let dir = dir.unit();
+        let cos = -(dir * self.w);
+        if cos <= 0.0 {
+            return None;
+        }
+        let q = p + dir * (self.focus_dist() / cos) - self.lower_left_corner;
+        Some((
+            q * self.horizontal / self.horizontal.length_squared(),
+            q * self.vertical / self.vertical.length_squared(),
+        ))
+    }
 }
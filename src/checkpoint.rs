@@ -0,0 +1,337 @@
+use crate::render::Samples;
+use crate::Vec3;
+use std::fs;
+use std::io::{self, Write};
+
+const MAGIC: &str = "raytracer checkpoint 4";
+const RECORD: usize = 9 * 8 + 4;
+
+// running sums of the samples taken so far, saved between passes so a render can be resumed
+//
+// the file is a text header, one `key value` per line, ending in a `data` line and followed
+// by one little-endian record per pixel, row by row from the top: the filtered r, g, b sums,
+// the filter weight, the sums of luminance and squared luminance and the r, g, b splat sums
+// as f64, then the sample count as u32
+pub struct Checkpoint {
+    // the command line arguments that shape the image, parsed again on resume
+    pub args: Vec<String>,
+    pub width: u32,
+    pub height: u32,
+    // renders added so far, each pass of a photon mapping render shoots its own photons
+    pub passes: u32,
+    pub sum: Vec<Vec3>,
+    pub weight: Vec<f64>,
+    pub sum_y: Vec<f64>,
+    pub sum_sq: Vec<f64>,
+    pub splat: Vec<Vec3>,
+    pub count: Vec<u32>,
+}
+
+impl Checkpoint {
+    pub fn new(args: Vec<String>, width: u32, height: u32) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            args,
+            width,
+            height,
+            passes: 0,
+            sum: vec![Vec3::zero(); n],
+            weight: vec![0.0; n],
+            sum_y: vec![0.0; n],
+            sum_sq: vec![0.0; n],
+            splat: vec![Vec3::zero(); n],
+            count: vec![0; n],
+        }
+    }
+
+    // adds a render that took counts[k] samples for pixel k
+    pub fn add(&mut self, samples: &Samples, counts: &[u32]) {
+        for (s, x) in self.sum.iter_mut().zip(&samples.sum) {
+            *s += *x;
+        }
+        for (s, x) in self.weight.iter_mut().zip(&samples.weight) {
+            *s += *x;
+        }
+        for (s, x) in self.sum_y.iter_mut().zip(&samples.sum_y) {
+            *s += *x;
+        }
+        for (s, x) in self.sum_sq.iter_mut().zip(&samples.sum_sq) {
+            *s += *x;
+        }
+        for (s, x) in self.splat.iter_mut().zip(&samples.splat) {
+            *s += *x;
+        }
+        for (c, x) in self.count.iter_mut().zip(counts) {
+            *c += *x;
+        }
+        self.passes += 1;
+    }
+
+    // samples taken over the whole image
+    pub fn total(&self) -> u64 {
+        self.count.iter().map(|&c| c as u64).sum()
+    }
+
+    // variance of the mean luminance of the samples taken for pixel k
+    pub fn variance(&self, k: usize) -> f64 {
+        let n = self.count[k] as f64;
+        if n < 2.0 {
+            return f64::INFINITY;
+        }
+        let mean = self.sum_y[k] / n;
+        (self.sum_sq[k] / n - mean * mean).max(0.0) / (n - 1.0)
+    }
+
+    // filtered radiance per pixel; negative lobes can leave a pixel with no weight to divide by
+    pub fn image(&self) -> Vec<Vec3> {
+        let mean_count = self.total() as f64 / self.count.len() as f64;
+        self.sum
+            .iter()
+            .zip(&self.splat)
+            .zip(&self.weight)
+            .map(|((s, splat), &w)| {
+                let mut col = if w > 0.0 { *s / w } else { Vec3::zero() };
+                if mean_count > 0.0 {
+                    col += *splat / mean_count;
+                }
+                col
+            })
+            .collect()
+    }
+
+    // writes next to `path` first, a render killed while saving keeps the previous checkpoint
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = Vec::with_capacity(self.sum.len() * RECORD + 256);
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "width {}", self.width)?;
+        writeln!(out, "height {}", self.height)?;
+        writeln!(out, "passes {}", self.passes)?;
+        for arg in &self.args {
+            writeln!(out, "arg {}", arg)?;
+        }
+        writeln!(out, "data")?;
+        for k in 0..self.count.len() {
+            let (s, p) = (self.sum[k], self.splat[k]);
+            let (w, y, sq) = (self.weight[k], self.sum_y[k], self.sum_sq[k]);
+            for x in &[s.x, s.y, s.z, w, y, sq, p.x, p.y, p.z] {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            out.extend_from_slice(&self.count[k].to_le_bytes());
+        }
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, &out)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let bad = |msg: &str| format!("{}: not a valid checkpoint, {}", path, msg);
+
+        let mut args = Vec::new();
+        let mut width = None;
+        let mut height = None;
+        let mut passes = None;
+        let mut pos = 0;
+        let mut first = true;
+        loop {
+            let end = match bytes[pos..].iter().position(|&b| b == b'\n') {
+                Some(k) => pos + k,
+                None => return Err(bad("the header is cut short")),
+            };
+            let line = std::str::from_utf8(&bytes[pos..end]).map_err(|_| bad("bad header"))?;
+            pos = end + 1;
+            if first {
+                if line != MAGIC {
+                    return Err(bad("unknown format"));
+                }
+                first = false;
+                continue;
+            }
+            if line == "data" {
+                break;
+            }
+            let (key, value) = match line.find(' ') {
+                Some(k) => (&line[..k], &line[k + 1..]),
+                None => (line, ""),
+            };
+            match key {
+                "width" => width = value.parse::<u32>().ok(),
+                "height" => height = value.parse::<u32>().ok(),
+                "passes" => passes = value.parse::<u32>().ok(),
+                "arg" => args.push(value.to_string()),
+                _ => return Err(bad(&format!("unknown header line `{}`", line))),
+            }
+        }
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (w, h),
+            _ => return Err(bad("the image size is missing")),
+        };
+        let passes = passes.ok_or_else(|| bad("the pass count is missing"))?;
+
+        let n = (width as usize)
+            .checked_mul(height as usize)
+            .ok_or_else(|| bad("the image size is too large"))?;
+        let data = &bytes[pos..];
+        if n.checked_mul(RECORD) != Some(data.len()) {
+            return Err(bad("the pixel data does not match the image size"));
+        }
+        let f = |k: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&data[k..k + 8]);
+            f64::from_le_bytes(b)
+        };
+        let mut sum = Vec::with_capacity(n);
+        let mut weight = Vec::with_capacity(n);
+        let mut sum_y = Vec::with_capacity(n);
+        let mut sum_sq = Vec::with_capacity(n);
+        let mut splat = Vec::with_capacity(n);
+        let mut count = Vec::with_capacity(n);
+        for rec in 0..n {
+            let k = rec * RECORD;
+            sum.push(Vec3::new(f(k), f(k + 8), f(k + 16)));
+            weight.push(f(k + 24));
+            sum_y.push(f(k + 32));
+            sum_sq.push(f(k + 40));
+            splat.push(Vec3::new(f(k + 48), f(k + 56), f(k + 64)));
+            let mut b = [0; 4];
+            b.copy_from_slice(&data[k + 72..k + 76]);
+            count.push(u32::from_le_bytes(b));
+        }
+        Ok(Self {
+            args,
+            width,
+            height,
+            passes,
+            sum,
+            weight,
+            sum_y,
+            sum_sq,
+            splat,
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::output::temp;
+
+    fn header(lines: &[&str]) -> Vec<u8> {
+        let mut b = Vec::new();
+        for line in lines {
+            b.extend(line.as_bytes());
+            b.push(b'\n');
+        }
+        b
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let args = vec![String::from("--scene"), String::from("cornell_box")];
+        let mut ck = Checkpoint::new(args.clone(), 2, 1);
+        ck.sum[1] = Vec3::new(1.0, 2.0, 3.0);
+        ck.weight[1] = 4.0;
+        ck.sum_y[1] = 5.0;
+        ck.sum_sq[1] = 6.0;
+        ck.splat[1] = Vec3::new(7.0, 8.0, 9.0);
+        ck.count[1] = 10;
+        ck.passes = 3;
+        let path = temp("checkpoint-round-trip");
+        ck.save(&path).unwrap();
+
+        // the header, then a record of zeros for the first pixel and the values for the second
+        let bytes = fs::read(&path).unwrap();
+        let mut expected = header(&[
+            MAGIC,
+            "width 2",
+            "height 1",
+            "passes 3",
+            "arg --scene",
+            "arg cornell_box",
+            "data",
+        ]);
+        expected.extend_from_slice(&[0u8; RECORD]);
+        for k in 1..10 {
+            expected.extend(&(k as f64).to_le_bytes());
+        }
+        expected.extend(&10u32.to_le_bytes());
+        assert_eq!(bytes, expected);
+
+        let back = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(back.args, args);
+        assert_eq!((back.width, back.height, back.passes), (2, 1, 3));
+        assert_eq!(back.weight, ck.weight);
+        assert_eq!(back.sum_y, ck.sum_y);
+        assert_eq!(back.sum_sq, ck.sum_sq);
+        assert_eq!(back.count, ck.count);
+        for k in 0..2 {
+            assert_eq!(back.sum[k].x, ck.sum[k].x);
+            assert_eq!(back.sum[k].z, ck.sum[k].z);
+            assert_eq!(back.splat[k].y, ck.splat[k].y);
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_errors() {
+        let cases: Vec<(Vec<u8>, &str)> = vec![
+            (b"raytracer".to_vec(), "the header is cut short"),
+            (
+                header(&["raytracer checkpoint 1", "data"]),
+                "unknown format",
+            ),
+            ([MAGIC.as_bytes(), b"\n\xff\n"].concat(), "bad header"),
+            (
+                header(&[MAGIC, "depth 4", "data"]),
+                "unknown header line `depth 4`",
+            ),
+            (
+                header(&[MAGIC, "width 1", "passes 1", "data"]),
+                "the image size is missing",
+            ),
+            (
+                header(&[MAGIC, "width 1", "height x", "passes 1", "data"]),
+                "the image size is missing",
+            ),
+            (
+                header(&[MAGIC, "width 1", "height 1", "data"]),
+                "the pass count is missing",
+            ),
+            (
+                header(&[
+                    MAGIC,
+                    "width 4294967295",
+                    "height 4294967295",
+                    "passes 1",
+                    "data",
+                ]),
+                "the pixel data does not match the image size",
+            ),
+            // one record where two are needed
+            (
+                [
+                    header(&[MAGIC, "width 2", "height 1", "passes 1", "data"]),
+                    vec![0; RECORD],
+                ]
+                .concat(),
+                "the pixel data does not match the image size",
+            ),
+        ];
+        for (k, (bytes, msg)) in cases.into_iter().enumerate() {
+            let path = temp(&format!("checkpoint-bad-{}", k));
+            fs::write(&path, &bytes).unwrap();
+            let e = Checkpoint::load(&path).err();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(
+                e,
+                Some(format!("{}: not a valid checkpoint, {}", path, msg))
+            );
+        }
+        match Checkpoint::load(&temp("checkpoint-missing")) {
+            Err(e) => assert!(e.starts_with("cannot read")),
+            Ok(_) => panic!("loaded a missing checkpoint"),
+        }
+    }
+}
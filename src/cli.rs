@@ -0,0 +1,567 @@
+use crate::environment::Background;
+use crate::features::Aov;
+use crate::filter::Filter;
+use crate::integrator::{Heuristic, Integrator};
+use crate::sampler::SamplerKind;
+use crate::tonemap::Operator;
+use crate::Scene;
+use crate::Vec3;
+use std::str::FromStr;
+
+pub const USAGE: &str = "usage: raytracer [options]
+
+options:
+    --scene <name>          built-in scene: random_scene, night, cornell_box, cornell_glass,
+                            cornell_smoke (default: cornell_box)
+    --scene-file <path>     load a JSON scene description instead of a built-in scene
+    --width <px>            image width
+    --aspect <ratio>        aspect ratio, either a number or w/h such as 3/2
+    --spp <n>               samples per pixel (default: 1000)
+    --depth <n>             maximum ray depth, a safety limit only (default: 50)
+    --rr-depth <n>          bounces before russian roulette starts (default: 5)
+    --mis <heuristic>       weighting of light and material samples: balance, power (default: power)
+    --integrator <name>     path (path tracing), bdpt (bidirectional path tracing), photon
+                            (photon mapping) or sppm (progressive photon mapping, one pass per
+                            sample) (default: path)
+    --photons <n>           photons per photon map (default: 200000)
+    --photon-radius <r>     photon gather radius, where sppm starts (default: 1/100 of the
+                            scene's bounding box diagonal)
+    --threads <n>           worker threads (default: 8)
+    --tile-size <px>        tile edge length (default: 16)
+    --filter <name>         pixel reconstruction filter: box, tent, gaussian, mitchell, lanczos
+                            (default: box)
+    --filter-radius <px>    filter radius in pixels (default: 0.5 for box, 1 for tent, 1.5 for
+                            gaussian, 2 for mitchell, 3 for lanczos)
+    --sampler <name>        sample sequence: independent, stratified, halton, sobol (owen
+                            scrambled), blue_noise (default: independent)
+    --spectral              trace wavelengths instead of rgb, so dielectrics with cauchy or
+                            sellmeier coefficients disperse light (path integrator only)
+    --seed <n>              seed of the generated scenes and of the render, the same seed and
+                            options give the same image whatever --threads is (default: 0)
+    --lookfrom <x,y,z>      camera position
+    --lookat <x,y,z>        camera target
+    --vup <x,y,z>           camera up vector
+    --vfov <deg>            vertical field of view in degrees
+    --aperture <a>          lens aperture
+    --focus-dist <d>        focus distance
+    --background <r,g,b>    background color
+    --environment <path>    light the scene with an equirectangular .hdr, .exr or .pfm image
+                            instead of a background color
+    --env-intensity <x>     multiplies the radiance of --environment or --sky (default: 1)
+    --env-rotation <deg>    turns --environment about the y axis (default: 0)
+    --sky <elev,azim>       light the scene with a clear sky and the sun at this elevation and
+                            azimuth in degrees, azimuth 0 is towards +x and 90 towards +z;
+                            radiance is in kcd/m^2, so lower --exposure
+    --turbidity <t>         haze of the --sky, from 2 (clear) to 10 (hazy) (default: 3)
+    --ground-albedo <a>     reflectance of the ground below the --sky horizon (default: 0.3)
+    --tonemap <op>          tone mapping for 8-bit outputs: linear, reinhard, reinhard_extended,
+                            aces, hable (default: linear)
+    --exposure <stops>      exposure adjustment applied before tone mapping (default: 0)
+    --white <radiance>      white point for reinhard_extended and hable
+    -o, --output <path>     output image, .hdr/.pfm/.exr keep linear radiance (default: output/test.png)
+    --adaptive <error>      stop sampling pixels whose relative standard error drops below this,
+                            such as 0.02, and spend the --spp budget on the noisy ones instead
+    --min-spp <n>           samples before a pixel may stop with --adaptive (default: 32)
+    --max-spp <n>           most samples a pixel gets with --adaptive (default: 4 times --spp)
+    --heatmap <path>        write an image of the samples taken per pixel
+    --aov <passes>          auxiliary passes from the first hit, a comma-separated list of
+                            depth, normal, albedo, position, uv and id, or all; layers of the
+                            output for .exr, otherwise files next to it such as test.depth.png
+    --denoise               filter the output guided by first-hit albedo, normal and depth
+    --bad-pixels <path>     write an image marking pixels that had NaN (magenta), infinite
+                            (yellow) or negative (cyan) samples, which are left out of the image
+    --trace-bad             print the path of the first NaN, infinite or negative sample
+    --checkpoint <path>     render in passes and save the accumulated samples to this file,
+                            the output image is written along with it
+    --checkpoint-every <s>  seconds between checkpoints (default: 60)
+    --pass-spp <n>          samples per pixel in each pass with --checkpoint or --adaptive
+                            (default: 16)
+    --resume <path>         continue a checkpointed render up to --spp, by default the --spp
+                            it was started with; only --spp, --threads, --tile-size, the
+                            output, checkpoint, adaptive sampling, aov, denoising, diagnostics
+                            and tone mapping options may be given again, the rest comes from
+                            the checkpoint
+    -h, --help              print this message";
+
+pub struct Options {
+    pub help: bool,
+    pub scene: String,
+    pub scene_file: Option<String>,
+    pub output: String,
+    pub samples_per_pixel: u32,
+    pub max_depth: i32,
+    pub rr_depth: i32,
+    pub mis: Heuristic,
+    pub integrator: Integrator,
+    pub photons: usize,
+    pub photon_radius: Option<f64>,
+    pub threads: usize,
+    pub tile_size: u32,
+    pub filter: Filter,
+    pub filter_radius: Option<f64>,
+    pub sampler: SamplerKind,
+    pub spectral: bool,
+    pub seed: u64,
+    pub image_width: Option<u32>,
+    pub aspect_ratio: Option<f64>,
+    pub lookfrom: Option<Vec3>,
+    pub lookat: Option<Vec3>,
+    pub vup: Option<Vec3>,
+    pub vfov: Option<f64>,
+    pub aperture: Option<f64>,
+    pub dist_to_focus: Option<f64>,
+    pub background: Option<Vec3>,
+    pub environment: Option<String>,
+    pub env_intensity: Option<f64>,
+    pub env_rotation: Option<f64>,
+    pub sky: Option<(f64, f64)>,
+    pub turbidity: Option<f64>,
+    pub ground_albedo: Option<f64>,
+    pub tonemap: Operator,
+    pub exposure: f64,
+    pub white: Option<f64>,
+    pub adaptive: Option<f64>,
+    pub min_spp: u32,
+    pub max_spp: Option<u32>,
+    pub heatmap: Option<String>,
+    pub aovs: Vec<Aov>,
+    pub denoise: bool,
+    pub bad_pixels: Option<String>,
+    pub trace_bad: bool,
+    pub checkpoint: Option<String>,
+    pub checkpoint_every: f64,
+    pub pass_spp: u32,
+    pub resume: Option<String>,
+}
+
+// options that take no value
+const SWITCHES: &[&str] = &["-h", "--help", "--spectral", "--denoise", "--trace-bad"];
+
+// options that may differ between a checkpointed render and its resumption
+pub const RESUMABLE: &[&str] = &[
+    "--spp",
+    "--threads",
+    "--tile-size",
+    "-o",
+    "--output",
+    "--adaptive",
+    "--min-spp",
+    "--max-spp",
+    "--heatmap",
+    "--aov",
+    "--denoise",
+    "--bad-pixels",
+    "--trace-bad",
+    "--checkpoint",
+    "--checkpoint-every",
+    "--pass-spp",
+    "--resume",
+    "--tonemap",
+    "--exposure",
+    "--white",
+];
+
+// splits the arguments into those that shape the image and those in RESUMABLE
+pub fn split_resumable(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut fixed = Vec::new();
+    let mut resumable = Vec::new();
+    let mut k = 0;
+    while k < args.len() {
+        let n = if SWITCHES.contains(&args[k].as_str()) {
+            1
+        } else {
+            2
+        };
+        let end = (k + n).min(args.len());
+        if RESUMABLE.contains(&args[k].as_str()) {
+            resumable.extend_from_slice(&args[k..end]);
+        } else {
+            fixed.extend_from_slice(&args[k..end]);
+        }
+        k = end;
+    }
+    (fixed, resumable)
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            help: false,
+            scene: String::from("cornell_box"),
+            scene_file: None,
+            output: String::from("output/test.png"),
+            samples_per_pixel: 1000,
+            max_depth: 50,
+            rr_depth: 5,
+            mis: Heuristic::Power,
+            integrator: Integrator::Path,
+            photons: 200_000,
+            photon_radius: None,
+            threads: 8,
+            tile_size: 16,
+            filter: Filter::Box,
+            filter_radius: None,
+            sampler: SamplerKind::Independent,
+            spectral: false,
+            seed: 0,
+            image_width: None,
+            aspect_ratio: None,
+            lookfrom: None,
+            lookat: None,
+            vup: None,
+            vfov: None,
+            aperture: None,
+            dist_to_focus: None,
+            background: None,
+            environment: None,
+            env_intensity: None,
+            env_rotation: None,
+            sky: None,
+            turbidity: None,
+            ground_albedo: None,
+            tonemap: Operator::Linear,
+            exposure: 0.0,
+            white: None,
+            adaptive: None,
+            min_spp: 32,
+            max_spp: None,
+            heatmap: None,
+            aovs: Vec::new(),
+            denoise: false,
+            bad_pixels: None,
+            trace_bad: false,
+            checkpoint: None,
+            checkpoint_every: 60.0,
+            pass_spp: 16,
+            resume: None,
+        }
+    }
+}
+
+fn number<T: FromStr>(flag: &str, s: &str) -> Result<T, String> {
+    s.trim()
+        .parse::<T>()
+        .map_err(|_| format!("invalid value `{}` for {}", s, flag))
+}
+
+fn ratio(flag: &str, s: &str) -> Result<f64, String> {
+    let ret = match s.find('/') {
+        Some(k) => number::<f64>(flag, &s[..k])? / number::<f64>(flag, &s[k + 1..])?,
+        None => number::<f64>(flag, s)?,
+    };
+    if ret.is_finite() && ret > 0.0 {
+        Ok(ret)
+    } else {
+        Err(format!("invalid value `{}` for {}", s, flag))
+    }
+}
+
+fn vec3(flag: &str, s: &str) -> Result<Vec3, String> {
+    let v: Vec<&str> = s.split(',').collect();
+    if v.len() != 3 {
+        return Err(format!(
+            "{} expects three comma-separated numbers, got `{}`",
+            flag, s
+        ));
+    }
+    Ok(Vec3::new(
+        number(flag, v[0])?,
+        number(flag, v[1])?,
+        number(flag, v[2])?,
+    ))
+}
+
+fn aovs(s: &str) -> Result<Vec<Aov>, String> {
+    if s == "all" {
+        return Ok(Aov::ALL.to_vec());
+    }
+    let mut ret = Vec::new();
+    for name in s.split(',') {
+        match Aov::from_name(name.trim()) {
+            Some(aov) if !ret.contains(&aov) => ret.push(aov),
+            Some(_) => {}
+            None => {
+                return Err(format!(
+                    "unknown pass `{}`, expected all or some of: {}",
+                    name,
+                    Aov::NAMES.join(", ")
+                ))
+            }
+        }
+    }
+    Ok(ret)
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut opts = Options::default();
+        while let Some(flag) = args.next() {
+            if SWITCHES.contains(&flag.as_str()) {
+                match flag.as_str() {
+                    "--spectral" => opts.spectral = true,
+                    "--denoise" => opts.denoise = true,
+                    "--trace-bad" => opts.trace_bad = true,
+                    _ => opts.help = true,
+                }
+                continue;
+            }
+            let value = match args.next() {
+                Some(v) => v,
+                None => return Err(format!("missing value for {}", flag)),
+            };
+            let f = flag.as_str();
+            match f {
+                "--scene" => {
+                    if !Scene::NAMES.contains(&value.as_str()) {
+                        return Err(format!(
+                            "unknown scene `{}`, expected one of: {}",
+                            value,
+                            Scene::NAMES.join(", ")
+                        ));
+                    }
+                    opts.scene = value;
+                }
+                "--scene-file" => opts.scene_file = Some(value),
+                "-o" | "--output" => opts.output = value,
+                "--spp" => opts.samples_per_pixel = number(f, &value)?,
+                "--depth" => opts.max_depth = number(f, &value)?,
+                "--rr-depth" => opts.rr_depth = number(f, &value)?,
+                "--mis" => {
+                    opts.mis = match Heuristic::from_name(&value) {
+                        Some(h) => h,
+                        None => {
+                            return Err(format!(
+                                "unknown heuristic `{}`, expected one of: {}",
+                                value,
+                                Heuristic::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--integrator" => {
+                    opts.integrator = match Integrator::from_name(&value) {
+                        Some(i) => i,
+                        None => {
+                            return Err(format!(
+                                "unknown integrator `{}`, expected one of: {}",
+                                value,
+                                Integrator::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--photons" => opts.photons = number(f, &value)?,
+                "--photon-radius" => opts.photon_radius = Some(ratio(f, &value)?),
+                "--seed" => opts.seed = number(f, &value)?,
+                "--threads" => opts.threads = number(f, &value)?,
+                "--tile-size" => opts.tile_size = number(f, &value)?,
+                "--filter" => {
+                    opts.filter = match Filter::from_name(&value) {
+                        Some(filter) => filter,
+                        None => {
+                            return Err(format!(
+                                "unknown filter `{}`, expected one of: {}",
+                                value,
+                                Filter::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--filter-radius" => opts.filter_radius = Some(ratio(f, &value)?),
+                "--sampler" => {
+                    opts.sampler = match SamplerKind::from_name(&value) {
+                        Some(kind) => kind,
+                        None => {
+                            return Err(format!(
+                                "unknown sampler `{}`, expected one of: {}",
+                                value,
+                                SamplerKind::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--width" => opts.image_width = Some(number(f, &value)?),
+                "--aspect" => opts.aspect_ratio = Some(ratio(f, &value)?),
+                "--lookfrom" => opts.lookfrom = Some(vec3(f, &value)?),
+                "--lookat" => opts.lookat = Some(vec3(f, &value)?),
+                "--vup" => opts.vup = Some(vec3(f, &value)?),
+                "--vfov" => opts.vfov = Some(number(f, &value)?),
+                "--aperture" => opts.aperture = Some(number(f, &value)?),
+                "--focus-dist" => opts.dist_to_focus = Some(number(f, &value)?),
+                "--background" => opts.background = Some(vec3(f, &value)?),
+                "--environment" => opts.environment = Some(value),
+                "--env-intensity" => opts.env_intensity = Some(number(f, &value)?),
+                "--env-rotation" => opts.env_rotation = Some(number(f, &value)?),
+                "--sky" => {
+                    let v: Vec<&str> = value.split(',').collect();
+                    if v.len() != 2 {
+                        return Err(format!(
+                            "{} expects an elevation and an azimuth, got `{}`",
+                            f, value
+                        ));
+                    }
+                    opts.sky = Some((number(f, v[0])?, number(f, v[1])?));
+                }
+                "--turbidity" => opts.turbidity = Some(number(f, &value)?),
+                "--ground-albedo" => opts.ground_albedo = Some(number(f, &value)?),
+                "--tonemap" => {
+                    opts.tonemap = match Operator::from_name(&value) {
+                        Some(op) => op,
+                        None => {
+                            return Err(format!(
+                                "unknown tone mapping operator `{}`, expected one of: {}",
+                                value,
+                                Operator::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--exposure" => opts.exposure = number(f, &value)?,
+                "--white" => opts.white = Some(ratio(f, &value)?),
+                "--adaptive" => opts.adaptive = Some(ratio(f, &value)?),
+                "--min-spp" => opts.min_spp = number(f, &value)?,
+                "--max-spp" => opts.max_spp = Some(number(f, &value)?),
+                "--heatmap" => opts.heatmap = Some(value),
+                "--aov" => opts.aovs = aovs(&value)?,
+                "--bad-pixels" => opts.bad_pixels = Some(value),
+                "--checkpoint" => opts.checkpoint = Some(value),
+                "--checkpoint-every" => opts.checkpoint_every = number(f, &value)?,
+                "--pass-spp" => opts.pass_spp = number(f, &value)?,
+                "--resume" => opts.resume = Some(value),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if opts.samples_per_pixel == 0 {
+            return Err(String::from("--spp must be at least 1"));
+        }
+        if opts.photons == 0 {
+            return Err(String::from("--photons must be at least 1"));
+        }
+        if opts.threads == 0 {
+            return Err(String::from("--threads must be at least 1"));
+        }
+        if opts.tile_size == 0 {
+            return Err(String::from("--tile-size must be at least 1"));
+        }
+        if opts.max_spp == Some(0) {
+            return Err(String::from("--max-spp must be at least 1"));
+        }
+        if opts.pass_spp == 0 {
+            return Err(String::from("--pass-spp must be at least 1"));
+        }
+        if opts.checkpoint_every.is_nan() || opts.checkpoint_every < 0.0 {
+            return Err(String::from("--checkpoint-every must not be negative"));
+        }
+        let backgrounds = [
+            opts.background.is_some(),
+            opts.environment.is_some(),
+            opts.sky.is_some(),
+        ];
+        if backgrounds.iter().filter(|&&b| b).count() > 1 {
+            return Err(String::from(
+                "only one of --background, --environment and --sky can be given",
+            ));
+        }
+        if opts.env_rotation.is_some() && opts.environment.is_none() {
+            return Err(String::from("--env-rotation needs --environment"));
+        }
+        if opts.env_intensity.is_some() && opts.environment.is_none() && opts.sky.is_none() {
+            return Err(String::from("--env-intensity needs --environment or --sky"));
+        }
+        if opts.sky.is_none() && (opts.turbidity.is_some() || opts.ground_albedo.is_some()) {
+            return Err(String::from("--turbidity and --ground-albedo need --sky"));
+        }
+        if matches!(opts.env_intensity, Some(x) if x.is_nan() || x < 0.0) {
+            return Err(String::from("--env-intensity must not be negative"));
+        }
+        Ok(opts)
+    }
+
+    pub fn apply(&self, scene: &mut Scene) {
+        if let Some(w) = self.image_width {
+            scene.image_width = w;
+        }
+        if let Some(a) = self.aspect_ratio {
+            scene.aspect_ratio = a;
+        }
+        if let Some(v) = self.lookfrom {
+            scene.lookfrom = v;
+        }
+        if let Some(v) = self.lookat {
+            scene.lookat = v;
+        }
+        if let Some(v) = self.vup {
+            scene.vup = v;
+        }
+        if let Some(f) = self.vfov {
+            scene.vfov = f;
+        }
+        if let Some(a) = self.aperture {
+            scene.aperture = a;
+        }
+        if let Some(d) = self.dist_to_focus {
+            scene.dist_to_focus = d;
+        }
+        if let Some(b) = self.background {
+            scene.background = Background::Color(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Options, String> {
+        Options::parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn options_are_read() {
+        let opts =
+            parse("--scene night --width 320 --aspect 3/2 --spp 64 --threads 2 -o x.png").unwrap();
+        assert_eq!(opts.scene, "night");
+        assert_eq!(opts.image_width, Some(320));
+        assert!((opts.aspect_ratio.unwrap() - 1.5).abs() < 1e-12);
+        assert_eq!((opts.samples_per_pixel, opts.threads), (64, 2));
+        assert_eq!(opts.output, "x.png");
+
+        let opts = parse("--lookfrom 1,2,3 --spectral --seed 9").unwrap();
+        assert!(opts.spectral);
+        assert_eq!(opts.seed, 9);
+        assert_eq!(opts.lookfrom, Some(Vec3::new(1.0, 2.0, 3.0)));
+        assert!(parse("").is_ok());
+    }
+
+    #[test]
+    fn bad_options_are_errors() {
+        let cases = [
+            ("--scene moon", "unknown scene `moon`"),
+            ("--width", "missing value for --width"),
+            ("--width wide", "invalid value `wide` for --width"),
+            ("--aspect 3/0", "invalid value `3/0` for --aspect"),
+            (
+                "--lookfrom 1,2",
+                "--lookfrom expects three comma-separated numbers",
+            ),
+            ("--spp 0", "--spp must be at least 1"),
+            ("--threads 0", "--threads must be at least 1"),
+            ("--tile-size 0", "--tile-size must be at least 1"),
+            ("--frobnicate 1", "unknown option --frobnicate"),
+            (
+                "--background 1,1,1 --sky 30,0",
+                "only one of --background, --environment and --sky can be given",
+            ),
+            (
+                "--turbidity 4",
+                "--turbidity and --ground-albedo need --sky",
+            ),
+        ];
+        for (line, msg) in cases.iter() {
+            match parse(line) {
+                Err(e) => assert!(e.starts_with(msg), "`{}` gave `{}`", line, e),
+                Ok(_) => panic!("`{}` was accepted", line),
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::features::Features;
+use crate::tonemap::luminance;
+use crate::Vec3;
+
+// edge-avoiding a-trous wavelet filter (Dammertz et al. 2010) with the variance guided
+// luminance weight of SVGF (Schied et al. 2017)
+
+const ITERATIONS: usize = 5;
+// 1d B3 spline taps for offsets 0, 1 and 2
+const KERNEL: [f64; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// standard deviations of luminance that still count as the same signal
+const SIGMA_L: f64 = 4.0;
+const SIGMA_N: i32 = 128;
+// relative depth difference
+const SIGMA_Z: f64 = 0.1;
+const SIGMA_A: f64 = 0.1;
+// albedo below this is not divided out
+const EPS: f64 = 1e-3;
+
+// filters the radiance `img` given the variance of each pixel's mean luminance; texture
+// detail is kept by filtering radiance divided by albedo and multiplying it back afterwards
+pub fn denoise(
+    img: &[Vec3],
+    variance: &[f64],
+    features: &Features,
+    width: usize,
+    height: usize,
+) -> Vec<Vec3> {
+    let demod = |c: f64, a: f64| if a > EPS { c / a } else { c };
+    let mut cur: Vec<Vec3> = img
+        .iter()
+        .zip(&features.albedo)
+        .map(|(c, a)| Vec3::new(demod(c.x, a.x), demod(c.y, a.y), demod(c.z, a.z)))
+        .collect();
+    let mut var: Vec<f64> = variance
+        .iter()
+        .zip(&features.albedo)
+        .map(|(v, a)| {
+            let y = luminance(*a);
+            if y > EPS {
+                v / (y * y)
+            } else {
+                *v
+            }
+        })
+        .collect();
+
+    for it in 0..ITERATIONS {
+        let step = 1 << it;
+        let blurred = blur(&var, width, height);
+        let mut next = vec![Vec3::zero(); cur.len()];
+        let mut next_var = vec![0.0; cur.len()];
+        for j in 0..height {
+            for i in 0..width {
+                let center = j * width + i;
+                let center_lum = luminance(cur[center]);
+                let sigma = SIGMA_L * blurred[center].max(0.0).sqrt() + 1e-10;
+                let mut sum = Vec3::zero();
+                let mut wsum = 0.0;
+                let mut vsum = 0.0;
+                for dy in -2i64..=2 {
+                    for dx in -2i64..=2 {
+                        let tx = i as i64 + dx * step;
+                        let ty = j as i64 + dy * step;
+                        if tx < 0 || ty < 0 || tx >= width as i64 || ty >= height as i64 {
+                            continue;
+                        }
+                        let tap = ty as usize * width + tx as usize;
+                        let kernel_w = KERNEL[dx.abs() as usize] * KERNEL[dy.abs() as usize];
+                        let weight = if tap == center {
+                            kernel_w
+                        } else {
+                            let wl = (-(center_lum - luminance(cur[tap])).abs() / sigma).exp();
+                            let wn = (features.normal[center] * features.normal[tap])
+                                .max(0.0)
+                                .powi(SIGMA_N);
+                            let dz = (features.depth[center] - features.depth[tap]).abs();
+                            let wz = (-dz / (SIGMA_Z * features.depth[center] + 1e-10)).exp();
+                            let da = features.albedo[center] - features.albedo[tap];
+                            let wa = (-(da * da) / (SIGMA_A * SIGMA_A)).exp();
+                            kernel_w * wl * wn * wz * wa
+                        };
+                        sum += cur[tap] * weight;
+                        wsum += weight;
+                        vsum += weight * weight * var[tap];
+                    }
+                }
+                next[center] = sum / wsum;
+                next_var[center] = vsum / (wsum * wsum);
+            }
+        }
+        cur = next;
+        var = next_var;
+    }
+
+    let remod = |c: f64, a: f64| if a > EPS { c * a } else { c };
+    cur.iter()
+        .zip(&features.albedo)
+        .map(|(c, a)| Vec3::new(remod(c.x, a.x), remod(c.y, a.y), remod(c.z, a.z)))
+        .collect()
+}
+
+// 3x3 gaussian, steadies the variance estimate of a single pixel
+fn blur(values: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let taps = [0.25, 0.5, 0.25];
+    let mut ret = vec![0.0; values.len()];
+    for j in 0..height {
+        for i in 0..width {
+            let mut sum = 0.0;
+            let mut wsum = 0.0;
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    let tx = i + dx;
+                    let ty = j + dy;
+                    if tx < 1 || ty < 1 || tx > width || ty > height {
+                        continue;
+                    }
+                    let weight = taps[dx] * taps[dy];
+                    sum += weight * values[(ty - 1) * width + tx - 1];
+                    wsum += weight;
+                }
+            }
+            ret[j * width + i] = sum / wsum;
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 8;
+
+    // features of a flat gray wall facing the camera at distance 2
+    fn flat() -> Features {
+        let n = WIDTH * HEIGHT;
+        Features {
+            albedo: vec![Vec3::new(0.5, 0.5, 0.5); n],
+            normal: vec![Vec3::new(0.0, 0.0, 1.0); n],
+            depth: vec![2.0; n],
+            position: vec![Vec3::zero(); n],
+            uv: vec![Vec3::zero(); n],
+            id: vec![1; n],
+        }
+    }
+
+    // `left` on the left half of the image and `right` on the right
+    fn halves<T: Copy>(left: T, right: T) -> Vec<T> {
+        (0..WIDTH * HEIGHT)
+            .map(|p| if p % WIDTH < WIDTH / 2 { left } else { right })
+            .collect()
+    }
+
+    fn max_difference(a: &[Vec3], b: &[Vec3]) -> f64 {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| {
+                let d = a - b;
+                d.x.abs().max(d.y.abs()).max(d.z.abs())
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let img = vec![Vec3::new(0.3, 0.2, 0.1); WIDTH * HEIGHT];
+        let variance = vec![0.05; WIDTH * HEIGHT];
+        let out = denoise(&img, &variance, &flat(), WIDTH, HEIGHT);
+        assert!(max_difference(&out, &img) < 1e-12);
+    }
+
+    #[test]
+    fn feature_edges_are_not_blurred_across() {
+        // a step in radiance, with a variance so large that luminance alone would not
+        // stop the filter from smoothing it away
+        let img = halves(Vec3::new(0.1, 0.1, 0.1), Vec3::new(0.9, 0.9, 0.9));
+        let variance = vec![100.0; WIDTH * HEIGHT];
+
+        let out = denoise(&img, &variance, &flat(), WIDTH, HEIGHT);
+        assert!(
+            max_difference(&out, &img) > 0.1,
+            "flat features keep the step"
+        );
+
+        let mut albedo_edge = flat();
+        albedo_edge.albedo = halves(Vec3::new(0.2, 0.2, 0.2), Vec3::new(0.9, 0.9, 0.9));
+        let out = denoise(&img, &variance, &albedo_edge, WIDTH, HEIGHT);
+        assert!(max_difference(&out, &img) < 1e-6);
+
+        let mut normal_edge = flat();
+        normal_edge.normal = halves(Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        let out = denoise(&img, &variance, &normal_edge, WIDTH, HEIGHT);
+        assert!(max_difference(&out, &img) < 1e-6);
+    }
+}
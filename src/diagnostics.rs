@@ -0,0 +1,160 @@
+use crate::tonemap::luminance;
+use crate::Vec3;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// what can be wrong with a radiance sample, worst first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    Nan,
+    Inf,
+    Negative,
+}
+
+pub fn check(c: Vec3) -> Option<Fault> {
+    if c.x.is_nan() || c.y.is_nan() || c.z.is_nan() {
+        Some(Fault::Nan)
+    } else if c.x.is_infinite() || c.y.is_infinite() || c.z.is_infinite() {
+        Some(Fault::Inf)
+    } else if c.x < 0.0 || c.y < 0.0 || c.z < 0.0 {
+        Some(Fault::Negative)
+    } else {
+        None
+    }
+}
+
+// samples left out of a pixel, or of a whole render
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Faults {
+    pub nan: u64,
+    pub inf: u64,
+    pub negative: u64,
+}
+
+impl Faults {
+    pub fn add(&mut self, fault: Fault) {
+        match fault {
+            Fault::Nan => self.nan += 1,
+            Fault::Inf => self.inf += 1,
+            Fault::Negative => self.negative += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: Faults) {
+        self.nan += other.nan;
+        self.inf += other.inf;
+        self.negative += other.negative;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.nan + self.inf + self.negative
+    }
+
+    pub fn worst(&self) -> Option<Fault> {
+        if self.nan > 0 {
+            Some(Fault::Nan)
+        } else if self.inf > 0 {
+            Some(Fault::Inf)
+        } else if self.negative > 0 {
+            Some(Fault::Negative)
+        } else {
+            None
+        }
+    }
+}
+
+// dimmed grayscale of the image with the pixels that lost samples marked, magenta for NaN,
+// yellow for Inf and cyan for negative radiance
+pub fn marked(img: &[Vec3], faults: &[Faults]) -> Vec<Vec3> {
+    img.iter()
+        .zip(faults)
+        .map(|(c, f)| match f.worst() {
+            Some(Fault::Nan) => Vec3::new(1.0, 0.0, 1.0),
+            Some(Fault::Inf) => Vec3::new(1.0, 1.0, 0.0),
+            Some(Fault::Negative) => Vec3::new(0.0, 1.0, 1.0),
+            None => {
+                let y = 0.3 * luminance(*c).min(1.0);
+                Vec3::new(y, y, y)
+            }
+        })
+        .collect()
+}
+
+// with tracing on, every thread keeps a log of the sample it is working on and the first
+// sample found to be bad has its log printed
+static TRACE: AtomicBool = AtomicBool::new(false);
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static LOG: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+pub fn enable_trace() {
+    TRACE.store(true, Ordering::Relaxed);
+}
+
+// starts the log of a new sample
+pub fn begin() {
+    if TRACE.load(Ordering::Relaxed) {
+        LOG.with(|log| log.borrow_mut().clear());
+    }
+}
+
+// adds a line to the log of the current sample, `f` only runs with tracing on
+pub fn log<F: FnOnce() -> String>(f: F) {
+    if TRACE.load(Ordering::Relaxed) {
+        LOG.with(|log| log.borrow_mut().push(f()));
+    }
+}
+
+// prints the log of the current sample if it is the first bad one
+pub fn report(pixel: (usize, usize), fault: Fault, c: Vec3) {
+    if !TRACE.load(Ordering::Relaxed) || REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    LOG.with(|log| {
+        eprintln!(
+            "first bad sample: {:?} radiance ({}, {}, {}) at pixel ({}, {})",
+            fault, c.x, c.y, c.z, pixel.0, pixel.1
+        );
+        for line in log.borrow().iter() {
+            eprintln!("    {}", line);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_classified_worst_first() {
+        let nan = f64::NAN;
+        assert_eq!(check(Vec3::new(0.0, 1.0, 2.0)), None);
+        assert_eq!(check(Vec3::new(0.0, -0.0, 0.0)), None);
+        assert_eq!(check(Vec3::new(0.5, nan, 0.5)), Some(Fault::Nan));
+        assert_eq!(check(Vec3::new(f64::INFINITY, 0.5, 0.5)), Some(Fault::Inf));
+        assert_eq!(check(Vec3::new(0.5, 0.5, -f64::INFINITY)), Some(Fault::Inf));
+        assert_eq!(check(Vec3::new(0.5, -1e-9, 0.5)), Some(Fault::Negative));
+        // a sample with several faults counts as the worst of them
+        assert_eq!(check(Vec3::new(-1.0, f64::INFINITY, nan)), Some(Fault::Nan));
+        assert_eq!(check(Vec3::new(-1.0, f64::INFINITY, 0.0)), Some(Fault::Inf));
+    }
+
+    #[test]
+    fn worst_fault_of_a_pixel() {
+        let mut faults = Faults::default();
+        assert_eq!(faults.worst(), None);
+        faults.add(Fault::Negative);
+        assert_eq!(faults.worst(), Some(Fault::Negative));
+        faults.add(Fault::Inf);
+        assert_eq!(faults.worst(), Some(Fault::Inf));
+        let mut other = Faults::default();
+        other.add(Fault::Nan);
+        other.add(Fault::Negative);
+        faults.merge(other);
+        assert_eq!(faults.worst(), Some(Fault::Nan));
+        assert_eq!((faults.nan, faults.inf, faults.negative), (1, 1, 2));
+        assert_eq!(faults.total(), 4);
+    }
+}
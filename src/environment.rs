@@ -0,0 +1,676 @@
+use crate::material::ScaRet;
+use crate::sampler::Sampler;
+use crate::sky::Sky;
+use crate::tonemap::luminance;
+use crate::HitRecord;
+use crate::Material;
+use crate::Object;
+use crate::Ray;
+use crate::Vec3;
+use crate::AABB;
+use crate::INFINITY;
+use image::codecs::hdr::HdrDecoder;
+use std::f64::consts::PI;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+// what rays that leave the scene see
+#[derive(Clone)]
+pub enum Background {
+    Color(Vec3),
+    Map(Arc<Environment>),
+    Sky(Arc<Sky>),
+}
+
+impl Background {
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        match self {
+            Background::Color(c) => *c,
+            Background::Map(env) => env.radiance(dir),
+            Background::Sky(sky) => sky.radiance(dir),
+        }
+    }
+
+    // whether the path tracer samples it along with the lights, a plain color is left to
+    // the rays that escape
+    pub fn is_light(&self) -> bool {
+        match self {
+            Background::Color(_) => false,
+            Background::Map(_) | Background::Sky(_) => true,
+        }
+    }
+}
+
+// stands beyond everything else, so the shadow rays of directions drawn from the background
+// reach it
+impl Object for Background {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let t = 0.5 * INFINITY / r.dir.length();
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        Some(HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
+            p: r.at(t),
+            normal: -r.dir.unit(),
+            t,
+            front_face: true,
+            mat: Some(self),
+            u: 0.0,
+            v: 0.0,
+        })
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        None
+    }
+
+    fn pdf_value(&self, _o: Vec3, d: Vec3) -> f64 {
+        match self {
+            Background::Color(_) => 0.0,
+            Background::Map(env) => env.pdf(d),
+            Background::Sky(sky) => sky.pdf(d),
+        }
+    }
+
+    fn random(&self, _o: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        match self {
+            Background::Color(_) => panic!("a background color is not sampled as a light"),
+            Background::Map(env) => env.sample(s),
+            Background::Sky(sky) => sky.sample(s),
+        }
+    }
+}
+
+impl Material for Background {
+    fn scatter(&self, _r_in: Ray, _rec: &HitRecord, _s: &mut dyn Sampler) -> ScaRet {
+        ScaRet {
+            scattered: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            attenustion: Vec3::zero(),
+            pdf: Option::None,
+            is_specular: false,
+            jud: false,
+        }
+    }
+
+    fn emitted(&self, rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
+        self.radiance(-rec.normal)
+    }
+
+    fn name(&self) -> &'static str {
+        "background"
+    }
+}
+
+// an equirectangular image of the light arriving from every direction, laid out like the
+// texture coordinates of a sphere: u goes around the y axis and v from straight down to
+// straight up; directions are drawn in proportion to the luminance of the texels, so the
+// map can go into the light list and be sampled like any other light
+pub struct Environment {
+    width: usize,
+    height: usize,
+    // rows from the top, already multiplied by the intensity
+    texels: Vec<Vec3>,
+    // turn of the map about the y axis
+    sin_theta: f64,
+    cos_theta: f64,
+    // what each texel is drawn in proportion to, its luminance times the solid angle it covers
+    weights: Vec<f64>,
+    mean: f64,
+    // cumulative distributions of the rows and of the texels within each row, width + 1
+    // entries per row
+    rows: Vec<f64>,
+    cols: Vec<f64>,
+}
+
+impl Environment {
+    // rotation is in degrees
+    pub fn load(path: &Path, intensity: f64, rotation: f64) -> Result<Self, String> {
+        let (width, height, texels) = read_image(path)?;
+        if texels
+            .iter()
+            .any(|c| !(c.x.is_finite() && c.y.is_finite() && c.z.is_finite()))
+        {
+            return Err(format!(
+                "{}: the image has non-finite texels",
+                path.display()
+            ));
+        }
+        Ok(Self::new(width, height, texels, intensity, rotation))
+    }
+
+    pub fn new(
+        width: usize,
+        height: usize,
+        texels: Vec<Vec3>,
+        intensity: f64,
+        rotation: f64,
+    ) -> Self {
+        let texels: Vec<Vec3> = texels
+            .into_iter()
+            .map(|c| Vec3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0)) * intensity)
+            .collect();
+        let mut weights: Vec<f64> = texels.iter().map(|&c| luminance(c)).collect();
+        // a black map is still drawn from, by solid angle alone
+        if weights.iter().all(|&w| w <= 0.0) {
+            weights.iter_mut().for_each(|w| *w = 1.0);
+        }
+        for (j, row) in weights.chunks_mut(width).enumerate() {
+            let sin = ((j as f64 + 0.5) / height as f64 * PI).sin();
+            row.iter_mut().for_each(|w| *w *= sin);
+        }
+        let mean = weights.iter().sum::<f64>() / weights.len() as f64;
+        let mut cols = Vec::with_capacity(height * (width + 1));
+        let mut sums = Vec::with_capacity(height);
+        for row in weights.chunks(width) {
+            sums.push(row.iter().sum());
+            cols.extend(cdf(row));
+        }
+        let theta = rotation.to_radians();
+        Self {
+            width,
+            height,
+            texels,
+            sin_theta: theta.sin(),
+            cos_theta: theta.cos(),
+            rows: cdf(&sums),
+            weights,
+            mean,
+            cols,
+        }
+    }
+
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let (k, _) = self.texel(dir);
+        self.texels[k]
+    }
+
+    // pdf per solid angle with which sample picks dir
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let (k, cos) = self.texel(dir);
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        // the map covers 2pi by pi in angles and a texel row covers cos times its height
+        self.weights[k] / self.mean / (2.0 * PI * PI * cos)
+    }
+
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let (pick_row, pick_col) = sampler.get_2d();
+        let (row, row_offset) = sample_cdf(&self.rows, pick_row);
+        let (col, col_offset) = sample_cdf(
+            &self.cols[row * (self.width + 1)..(row + 1) * (self.width + 1)],
+            pick_col,
+        );
+        let local = direction(
+            (col as f64 + col_offset) / self.width as f64,
+            1.0 - (row as f64 + row_offset) / self.height as f64,
+        );
+        Vec3::new(
+            self.cos_theta * local.x + self.sin_theta * local.z,
+            local.y,
+            -self.sin_theta * local.x + self.cos_theta * local.z,
+        )
+    }
+
+    // the texel dir falls in and the cosine of its elevation
+    fn texel(&self, dir: Vec3) -> (usize, f64) {
+        let dir = dir.unit();
+        let local = Vec3::new(
+            self.cos_theta * dir.x - self.sin_theta * dir.z,
+            dir.y,
+            self.sin_theta * dir.x + self.cos_theta * dir.z,
+        );
+        let sin_elevation = crate::clamp(local.y, -1.0, 1.0);
+        let phi = local.z.atan2(local.x);
+        let u = 1.0 - (phi + PI) / (2.0 * PI);
+        let v = (sin_elevation.asin() + PI / 2.0) / PI;
+        let col = ((u * self.width as f64).max(0.0) as usize).min(self.width - 1);
+        let row = (((1.0 - v) * self.height as f64).max(0.0) as usize).min(self.height - 1);
+        (
+            row * self.width + col,
+            (1.0 - sin_elevation * sin_elevation).sqrt(),
+        )
+    }
+}
+
+// the direction at (u, v) of an unturned map
+pub fn direction(u: f64, v: f64) -> Vec3 {
+    let phi = (1.0 - u) * 2.0 * PI - PI;
+    let elevation = v * PI - PI / 2.0;
+    Vec3::new(
+        elevation.cos() * phi.cos(),
+        elevation.sin(),
+        elevation.cos() * phi.sin(),
+    )
+}
+
+// normalised running sums starting at zero, even steps when everything is zero
+fn cdf(weights: &[f64]) -> Vec<f64> {
+    let total: f64 = weights.iter().sum();
+    let n = weights.len() as f64;
+    let mut ret = Vec::with_capacity(weights.len() + 1);
+    let mut sum = 0.0;
+    ret.push(0.0);
+    for (k, w) in weights.iter().enumerate() {
+        sum += w;
+        ret.push(if total > 0.0 {
+            sum / total
+        } else {
+            (k + 1) as f64 / n
+        });
+    }
+    *ret.last_mut().unwrap() = 1.0;
+    ret
+}
+
+// the entry of the distribution u falls in and how far into it
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let mut lo = 0;
+    let mut hi = cdf.len() - 1;
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] <= u {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let x = (u - cdf[lo]) / (cdf[lo + 1] - cdf[lo]);
+    (lo, crate::clamp(x, 0.0, 1.0 - 1e-9))
+}
+
+// the width, height and pixels, rows from the top, of a .hdr, .exr or .pfm image
+pub fn read_image(path: &Path) -> Result<(usize, usize, Vec<Vec3>), String> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    let image = match ext.as_str() {
+        "hdr" => read_hdr(path),
+        "pfm" => read(path).and_then(|b| read_pfm(&b)),
+        "exr" => read(path).and_then(|b| read_exr(&b)),
+        _ => Err(String::from("expected a .hdr, .exr or .pfm image")),
+    };
+    image.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| e.to_string())
+}
+
+// Radiance RGBE, decoded by the image crate
+fn read_hdr(path: &Path) -> Result<(usize, usize, Vec<Vec3>), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let decoder = HdrDecoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let meta = decoder.metadata();
+    let data = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+    let texels = data
+        .iter()
+        .map(|p| Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64))
+        .collect();
+    image_size(meta.width as usize, meta.height as usize, texels)
+}
+
+// portable float map, PF for rgb and Pf for gray, rows stored from the bottom up
+fn read_pfm(b: &[u8]) -> Result<(usize, usize, Vec<Vec3>), String> {
+    // the three header lines
+    let mut header = Vec::new();
+    let mut at = 0;
+    while header.len() < 3 {
+        let end = b[at..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .ok_or_else(|| String::from("truncated header"))?;
+        header.push(String::from_utf8_lossy(&b[at..at + end]).trim().to_string());
+        at += end + 1;
+    }
+    let channels = match header[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(String::from("not a portable float map")),
+    };
+    let size: Vec<usize> = header[1]
+        .split_whitespace()
+        .map(|s| {
+            s.parse()
+                .map_err(|_| format!("bad image size `{}`", header[1]))
+        })
+        .collect::<Result<_, _>>()?;
+    if size.len() != 2 {
+        return Err(format!("bad image size `{}`", header[1]));
+    }
+    let (width, height) = (size[0], size[1]);
+    let scale: f64 = header[2]
+        .parse()
+        .map_err(|_| format!("bad scale `{}`", header[2]))?;
+    let size = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(4 * channels))
+        .and_then(|n| n.checked_add(at))
+        .ok_or_else(|| format!("bad image size `{}`", header[1]))?;
+    if b.len() < size {
+        return Err(String::from("truncated data"));
+    }
+    let value = |k: usize| {
+        let w = [
+            b[at + 4 * k],
+            b[at + 4 * k + 1],
+            b[at + 4 * k + 2],
+            b[at + 4 * k + 3],
+        ];
+        // a negative scale means little-endian
+        if scale < 0.0 {
+            f32::from_le_bytes(w) as f64
+        } else {
+            f32::from_be_bytes(w) as f64
+        }
+    };
+    let mut texels = Vec::with_capacity(width * height);
+    for j in (0..height).rev() {
+        for i in 0..width {
+            let k = (j * width + i) * channels;
+            texels.push(if channels == 3 {
+                Vec3::new(value(k), value(k + 1), value(k + 2))
+            } else {
+                Vec3::new(value(k), value(k), value(k))
+            });
+        }
+    }
+    image_size(width, height, texels)
+}
+
+// single-part scanline OpenEXR without compression, with half or float R, G and B channels or
+// a single Y channel
+fn read_exr(b: &[u8]) -> Result<(usize, usize, Vec<Vec3>), String> {
+    if !b.starts_with(&[0x76, 0x2f, 0x31, 0x01]) {
+        return Err(String::from("not an OpenEXR file"));
+    }
+    // tiled, long names and multi-part
+    if int(b, 4)? & (0x200 | 0x800 | 0x1000) != 0 {
+        return Err(String::from(
+            "only single-part scanline images are supported",
+        ));
+    }
+
+    // (name, pixel type) of every channel, in the order their rows are stored
+    let mut channels = Vec::new();
+    let mut window = None;
+    let mut at = 8;
+    loop {
+        let name = string(b, &mut at)?;
+        if name.is_empty() {
+            break;
+        }
+        let ty = string(b, &mut at)?;
+        let size = int(b, at)?.max(0) as usize;
+        let value = bytes(b, at + 4, size)?;
+        at += 4 + size;
+        match (name.as_str(), ty.as_str()) {
+            ("channels", "chlist") => {
+                let mut k = 0;
+                loop {
+                    let name = string(value, &mut k)?;
+                    if name.is_empty() {
+                        break;
+                    }
+                    if int(value, k + 8)? != 1 || int(value, k + 12)? != 1 {
+                        return Err(format!("channel {} is subsampled", name));
+                    }
+                    channels.push((name, int(value, k)?));
+                    k += 16;
+                }
+            }
+            ("compression", _) if value.first() != Some(&0) => {
+                return Err(String::from("only uncompressed images are supported"));
+            }
+            ("dataWindow", "box2i") => {
+                window = Some((
+                    int(value, 0)?,
+                    int(value, 4)?,
+                    int(value, 8)?,
+                    int(value, 12)?,
+                ));
+            }
+            _ => {}
+        }
+    }
+    let (x0, y0, x1, y1) = window.ok_or_else(|| String::from("missing dataWindow"))?;
+    if x1 < x0 || y1 < y0 {
+        return Err(String::from("empty dataWindow"));
+    }
+    let width = (x1 as i64 - x0 as i64 + 1) as usize;
+    let height = (y1 as i64 - y0 as i64 + 1) as usize;
+    let find = |name: &str| channels.iter().position(|(n, _)| n == name);
+    let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(String::from("expected R, G and B channels or a Y channel")),
+    };
+    // where the row of each channel starts within a scanline
+    let mut starts = Vec::new();
+    let mut line_size: usize = 0;
+    for (name, ty) in channels.iter() {
+        starts.push(line_size);
+        let size = match ty {
+            1 => 2,
+            0 | 2 => 4,
+            _ => return Err(format!("channel {} has an unknown pixel type", name)),
+        };
+        line_size = width
+            .checked_mul(size)
+            .and_then(|n| n.checked_add(line_size))
+            .ok_or_else(|| String::from("bad image size"))?;
+    }
+    // every scanline is in the file before the image is allocated
+    match line_size.checked_mul(height) {
+        Some(n) if n <= b.len() => {}
+        _ => return Err(truncated()),
+    }
+
+    // one scanline per chunk, found through the offset table
+    let mut texels = vec![Vec3::zero(); width * height];
+    for row in 0..height {
+        let low = int(b, at + 8 * row)? as u32 as u64;
+        let high = int(b, at + 8 * row + 4)? as u32 as u64;
+        let chunk = (high << 32 | low) as usize;
+        let y = int(b, chunk)?;
+        if y < y0 || y > y1 {
+            return Err(format!("scanline {} lies outside the dataWindow", y));
+        }
+        let data = bytes(b, chunk.saturating_add(8), line_size)?;
+        let j = (y - y0) as usize;
+        for i in 0..width {
+            let mut c = [0.0; 3];
+            for (x, &ch) in c.iter_mut().zip(rgb.iter()) {
+                let start = starts[ch];
+                *x = match channels[ch].1 {
+                    1 => half(u16::from_le_bytes([
+                        data[start + 2 * i],
+                        data[start + 2 * i + 1],
+                    ])),
+                    0 => int(data, start + 4 * i)? as u32 as f64,
+                    _ => f32::from_bits(int(data, start + 4 * i)? as u32) as f64,
+                };
+            }
+            texels[j * width + i] = Vec3::new(c[0], c[1], c[2]);
+        }
+    }
+    image_size(width, height, texels)
+}
+
+fn truncated() -> String {
+    String::from("truncated file")
+}
+
+// len bytes at `at`
+fn bytes(b: &[u8], at: usize, len: usize) -> Result<&[u8], String> {
+    at.checked_add(len)
+        .and_then(|end| b.get(at..end))
+        .ok_or_else(truncated)
+}
+
+// little-endian 32 bits at `at`
+fn int(b: &[u8], at: usize) -> Result<i32, String> {
+    let w = bytes(b, at, 4)?;
+    Ok(i32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+}
+
+// the zero-terminated string at `at`, which moves past it
+fn string(b: &[u8], at: &mut usize) -> Result<String, String> {
+    let rest = b.get(*at..).ok_or_else(truncated)?;
+    let end = rest.iter().position(|&c| c == 0).ok_or_else(truncated)?;
+    *at += end + 1;
+    Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+}
+
+// IEEE 754 half precision
+fn half(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = (h >> 10 & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f64;
+    sign * match exp {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa > 0.0 => f64::NAN,
+        31 => f64::INFINITY,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exp - 15),
+    }
+}
+
+fn image_size(
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+) -> Result<(usize, usize, Vec<Vec3>), String> {
+    if width == 0 || height == 0 || texels.len() != width * height {
+        return Err(String::from("the image is empty"));
+    }
+    Ok((width, height, texels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{Fixed, Pcg32};
+
+    // a dim map with a bright spot, turned about the y axis
+    fn spotted() -> Environment {
+        let (width, height) = (16, 8);
+        let mut texels = vec![Vec3::new(0.1, 0.2, 0.3); width * height];
+        texels[2 * width + 5] = Vec3::new(50.0, 40.0, 30.0);
+        texels[6 * width + 12] = Vec3::new(0.0, 0.0, 0.0);
+        Environment::new(width, height, texels, 2.0, 30.0)
+    }
+
+    // the header of an uncompressed half rgb image over the given dataWindow
+    fn exr_header(window: [i32; 4]) -> Vec<u8> {
+        let mut b = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        let mut attribute = |name: &str, ty: &str, value: &[u8]| {
+            for s in [name, ty].iter() {
+                b.extend(s.as_bytes());
+                b.push(0);
+            }
+            b.extend(&(value.len() as i32).to_le_bytes());
+            b.extend(value);
+        };
+        let mut chlist = Vec::new();
+        for name in ["B", "G", "R"].iter() {
+            chlist.extend(name.as_bytes());
+            chlist.push(0);
+            for &v in [1, 0, 1, 1].iter() {
+                chlist.extend(&(v as i32).to_le_bytes());
+            }
+        }
+        chlist.push(0);
+        attribute("channels", "chlist", &chlist);
+        attribute("compression", "compression", &[0]);
+        let mut value = Vec::new();
+        for &v in window.iter() {
+            value.extend(&v.to_le_bytes());
+        }
+        attribute("dataWindow", "box2i", &value);
+        b.push(0);
+        b
+    }
+
+    #[test]
+    fn oversized_headers_are_errors() {
+        let pfm = format!("PF\n{} 2\n-1\n", usize::MAX);
+        assert!(read_pfm(pfm.as_bytes()).is_err());
+        assert!(read_pfm(b"PF\n100000 100000\n-1\n").is_err());
+
+        let max = i32::MAX;
+        for &window in [[i32::MIN, 0, max, 0], [0, 0, max, max], [0, 0, 0, max]].iter() {
+            assert!(read_exr(&exr_header(window)).is_err());
+        }
+        // an offset past the end of the file
+        let mut b = exr_header([0, 0, 0, 0]);
+        b.extend(&u64::MAX.to_le_bytes());
+        b.extend(&[0; 14]);
+        assert_eq!(read_exr(&b).err(), Some(truncated()));
+    }
+
+    #[test]
+    fn reads_a_small_exr() {
+        let mut b = exr_header([0, 0, 0, 0]);
+        let offset = (b.len() + 8) as u64;
+        b.extend(&offset.to_le_bytes());
+        b.extend(&0i32.to_le_bytes());
+        b.extend(&6i32.to_le_bytes());
+        // 0.25, 0.5 and 1 in half, stored b, g, r
+        for &h in [0x3400u16, 0x3800, 0x3c00].iter() {
+            b.extend(&h.to_le_bytes());
+        }
+        let (width, height, texels) = read_exr(&b).unwrap();
+        assert_eq!((width, height), (1, 1));
+        assert_eq!((texels[0].x, texels[0].y, texels[0].z), (1.0, 0.5, 0.25));
+    }
+
+    #[test]
+    fn pdf_matches_the_sampled_density() {
+        let env = spotted();
+        let mut rng = Pcg32::new(5, 0);
+        for _ in 0..1000 {
+            let (pick_row, pick_col) = (rng.next_f64(), rng.next_f64());
+            let dir = env.sample(&mut Fixed(pick_row, pick_col));
+            // the chance of the texel times the texels per unit of the angles, over the
+            // cosine of the elevation the sample was placed at
+            let (row, row_offset) = sample_cdf(&env.rows, pick_row);
+            let cols = &env.cols[row * (env.width + 1)..(row + 1) * (env.width + 1)];
+            let (col, _) = sample_cdf(cols, pick_col);
+            let chance = (env.rows[row + 1] - env.rows[row]) * (cols[col + 1] - cols[col]);
+            let elevation = PI / 2.0 - (row as f64 + row_offset) / env.height as f64 * PI;
+            let texels = (env.width * env.height) as f64;
+            let expected = chance * texels / (2.0 * PI * PI * elevation.cos());
+            let pdf = env.pdf(dir);
+            assert!(
+                (pdf - expected).abs() <= 1e-6 * expected,
+                "{} against {} towards {}",
+                pdf,
+                expected,
+                dir
+            );
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let env = spotted();
+        // midpoints of a grid over the angles, finer than the texels
+        let (nu, nv) = (512, 256);
+        let mut sum = 0.0;
+        for j in 0..nv {
+            for i in 0..nu {
+                let (u, v) = ((i as f64 + 0.5) / nu as f64, (j as f64 + 0.5) / nv as f64);
+                let cos = (v * PI - PI / 2.0).cos();
+                sum += env.pdf(direction(u, v)) * cos;
+            }
+        }
+        let integral = sum * (2.0 * PI / nu as f64) * (PI / nv as f64);
+        assert!((integral - 1.0).abs() < 1e-2, "{}", integral);
+    }
+}
@@ -0,0 +1,484 @@
+use crate::environment::Background;
+use crate::render::{pixel_uv, run_tiles, RenderSettings};
+use crate::sampler::{Sampler, Sampling};
+use crate::Camera;
+use crate::Object;
+use crate::Vec3;
+use crate::INFINITY;
+use std::sync::Arc;
+
+// first-hit features for the aov passes and the denoiser, gathered by a pass of their own
+// rather than by the integrators: these only hand back radiance, and a fixed number of rays
+// per pixel keeps the features the same however adaptive sampling spreads the beauty samples
+// and whether the render was resumed from a checkpoint, which does not store them; a few
+// camera rays with no bounces cost little next to the render itself
+
+// jittered camera rays per pixel that the feature buffers average over
+pub const SAMPLES: u32 = 16;
+
+// auxiliary passes written next to the beauty image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Depth,
+    Normal,
+    Albedo,
+    Position,
+    Uv,
+    Id,
+}
+
+impl Aov {
+    pub const NAMES: [&'static str; 6] = ["depth", "normal", "albedo", "position", "uv", "id"];
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Position,
+        Aov::Uv,
+        Aov::Id,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "depth" => Some(Aov::Depth),
+            "normal" => Some(Aov::Normal),
+            "albedo" => Some(Aov::Albedo),
+            "position" => Some(Aov::Position),
+            "uv" => Some(Aov::Uv),
+            "id" => Some(Aov::Id),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        Aov::NAMES[self as usize]
+    }
+
+    // channel names in a multi-layer image, taken from x, y and z of the pass in order
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Uv => &["U", "V"],
+            Aov::Id => &["id"],
+        }
+    }
+}
+
+// what the camera sees first through each pixel, row by row from the top
+pub struct Features {
+    pub albedo: Vec<Vec3>,
+    // unit length and facing the camera, zero where all the rays miss
+    pub normal: Vec<Vec3>,
+    // mean distance from the lens over the rays that hit, zero where all of them miss
+    pub depth: Vec<f64>,
+    pub position: Vec<Vec3>,
+    // surface (u, v) in x and y
+    pub uv: Vec<Vec3>,
+    // id of the object most of the pixel's rays hit, 0 for the background
+    pub id: Vec<u32>,
+}
+
+impl Features {
+    // the raw values of a pass, single channel passes are repeated over x, y and z
+    pub fn pass(&self, aov: Aov) -> Vec<Vec3> {
+        match aov {
+            Aov::Depth => self.depth.iter().map(|&d| Vec3::new(d, d, d)).collect(),
+            Aov::Normal => self.normal.clone(),
+            Aov::Albedo => self.albedo.clone(),
+            Aov::Position => self.position.clone(),
+            Aov::Uv => self.uv.clone(),
+            Aov::Id => self
+                .id
+                .iter()
+                .map(|&id| Vec3::new(id as f64, id as f64, id as f64))
+                .collect(),
+        }
+    }
+
+    // a pass mapped into [0, 1] for 8-bit images
+    pub fn display(&self, aov: Aov) -> Vec<Vec3> {
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        match aov {
+            Aov::Depth => {
+                let far = self.depth.iter().copied().fold(0.0, f64::max);
+                let far = if far > 0.0 { far } else { 1.0 };
+                self.depth
+                    .iter()
+                    .map(|&d| {
+                        let x = d / far;
+                        Vec3::new(x, x, x)
+                    })
+                    .collect()
+            }
+            Aov::Normal => self
+                .normal
+                .iter()
+                .map(|&n| {
+                    if n.length_squared() > 0.0 {
+                        (n + one) * 0.5
+                    } else {
+                        n
+                    }
+                })
+                .collect(),
+            Aov::Albedo => self.albedo.clone(),
+            Aov::Position => {
+                let hits: Vec<(Vec3, f64)> = self
+                    .position
+                    .iter()
+                    .copied()
+                    .zip(self.depth.iter().copied())
+                    .collect();
+                let mut lo = Vec3::new(INFINITY, INFINITY, INFINITY);
+                let mut hi = -lo;
+                for &(p, d) in &hits {
+                    if d > 0.0 {
+                        lo = Vec3::new(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z));
+                        hi = Vec3::new(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z));
+                    }
+                }
+                let scale =
+                    |x: f64, lo: f64, hi: f64| if hi > lo { (x - lo) / (hi - lo) } else { 0.0 };
+                hits.iter()
+                    .map(|&(p, d)| {
+                        if d > 0.0 {
+                            Vec3::new(
+                                scale(p.x, lo.x, hi.x),
+                                scale(p.y, lo.y, hi.y),
+                                scale(p.z, lo.z, hi.z),
+                            )
+                        } else {
+                            Vec3::zero()
+                        }
+                    })
+                    .collect()
+            }
+            Aov::Uv => self.uv.clone(),
+            Aov::Id => self.id.iter().map(|&id| id_color(id)).collect(),
+        }
+    }
+}
+
+// a distinct hue per id, stepping around the color wheel by the golden ratio; black for
+// the background
+fn id_color(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::zero();
+    }
+    let hue = (id as f64 * 0.618_033_988_749_895).fract() * 6.0;
+    let ramp = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, ramp, 0.0),
+        1 => (ramp, 1.0, 0.0),
+        2 => (0.0, 1.0, ramp),
+        3 => (0.0, ramp, 1.0),
+        4 => (ramp, 0.0, 1.0),
+        _ => (1.0, 0.0, ramp),
+    };
+    // saturation 0.75, value 0.9
+    let shade = |v: f64| 0.9 * (0.25 + 0.75 * v);
+    Vec3::new(shade(r), shade(g), shade(b))
+}
+
+// averages the first hits of `samples` camera rays per pixel; misses see the background
+pub fn render(
+    settings: &RenderSettings,
+    sampling: &Sampling,
+    cam: Camera,
+    background: Background,
+    world: Arc<dyn Object>,
+    samples: u32,
+) -> Features {
+    let width = settings.image_width as usize;
+    let pixel_count = width * settings.image_height as usize;
+    let mut ret = Features {
+        albedo: vec![Vec3::zero(); pixel_count],
+        normal: vec![Vec3::zero(); pixel_count],
+        depth: vec![0.0; pixel_count],
+        position: vec![Vec3::zero(); pixel_count],
+        uv: vec![Vec3::zero(); pixel_count],
+        id: vec![0; pixel_count],
+    };
+    let settings = *settings;
+    let sampling = sampling.clone();
+    run_tiles(
+        &settings,
+        move || sampling.sampler(),
+        move |sampler: &mut Box<dyn Sampler>, tile| {
+            let mut res = Vec::new();
+            for j in tile.y0..tile.y1 {
+                for i in tile.x0..tile.x1 {
+                    let mut albedo = Vec3::zero();
+                    let mut normal = Vec3::zero();
+                    let mut depth = 0.0;
+                    let mut position = Vec3::zero();
+                    let mut uv = Vec3::zero();
+                    let mut hits = 0;
+                    let mut ids = Vec::with_capacity(samples as usize);
+                    for s in 0..samples {
+                        sampler.start((i, j), s);
+                        let (u, v) = pixel_uv(&settings, i, j, &mut **sampler);
+                        let r = cam.get_ray(u, v, &mut **sampler);
+                        match world.hit(r, 0.001, INFINITY) {
+                            Some(rec) => {
+                                if let Some(mat) = rec.mat {
+                                    albedo += mat.albedo(&rec);
+                                }
+                                normal += rec.normal;
+                                depth += rec.t * r.dir.length();
+                                position += rec.p;
+                                uv += Vec3::new(rec.u, rec.v, 0.0);
+                                hits += 1;
+                                ids.push(rec.id);
+                            }
+                            None => {
+                                albedo += background.radiance(r.dir);
+                                ids.push(0);
+                            }
+                        }
+                    }
+                    let n = samples as f64;
+                    // depths, points and uvs are only meaningful over the rays that hit something
+                    let m = (hits as f64).max(1.0);
+                    // the mean of differing normals is shorter than one, the denoiser compares
+                    // them by their dot product
+                    if normal.length_squared() > 0.0 {
+                        normal = normal.unit();
+                    }
+                    res.push((
+                        albedo / n,
+                        normal,
+                        depth / m,
+                        position / m,
+                        uv / m,
+                        majority(&mut ids),
+                    ));
+                }
+            }
+            res
+        },
+        |tile, res| {
+            let tile_width = (tile.x1 - tile.x0) as usize;
+            for (k, (albedo, normal, depth, position, uv, id)) in res.into_iter().enumerate() {
+                let i = tile.x0 as usize + k % tile_width;
+                let j = tile.y0 as usize + k / tile_width;
+                let p = j * width + i;
+                ret.albedo[p] = albedo;
+                ret.normal[p] = normal;
+                ret.depth[p] = depth;
+                ret.position[p] = position;
+                ret.uv[p] = uv;
+                ret.id[p] = id;
+            }
+        },
+    );
+    ret
+}
+
+// the most frequent id, the smallest among ties
+fn majority(ids: &mut [u32]) -> u32 {
+    ids.sort_unstable();
+    let mut best = (0, 0);
+    let mut k = 0;
+    while k < ids.len() {
+        let mut end = k;
+        while end < ids.len() && ids[end] == ids[k] {
+            end += 1;
+        }
+        if end - k > best.1 {
+            best = (ids[k], end - k);
+        }
+        k = end;
+    }
+    best.0
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::integrator::Integrator;
+    use crate::render::film_uv;
+    use crate::sampler::SamplerKind;
+    use crate::{Lambertian, SolidColor, Sphere, Tagged};
+
+    const SIZE: u32 = 9;
+    const CENTER: Vec3 = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: -5.0,
+    };
+
+    fn settings() -> RenderSettings {
+        RenderSettings::test(SIZE, SIZE, Integrator::Path)
+    }
+
+    fn camera() -> Camera {
+        Camera::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            1.0,
+            0.0,
+            1.0,
+        )
+    }
+
+    // a unit sphere 5 in front of a pinhole camera at the origin, which fills the middle of
+    // the image and leaves the top left corner to the background
+    fn sphere_features() -> Features {
+        let sphere = Sphere::new(
+            CENTER,
+            1.0,
+            Lambertian::new(SolidColor::new(Vec3::new(0.2, 0.4, 0.6))),
+        );
+        render(
+            &settings(),
+            &Sampling::new(SamplerKind::Independent, 1, SAMPLES),
+            camera(),
+            Background::Color(Vec3::zero()),
+            Arc::new(Tagged::new(7, sphere)),
+            SAMPLES,
+        )
+    }
+
+    // distance to the sphere along the camera ray through (jx, jy) of pixel (i, j), if it
+    // hits, and the normal there
+    fn exact(i: u32, j: u32, jx: f64, jy: f64) -> Option<(f64, Vec3)> {
+        let cam = camera();
+        let (u, v) = film_uv(&settings(), i, j, jx, jy);
+        let dir = (cam.lower_left_corner + cam.horizontal * u + cam.vertical * v).unit();
+        let along = dir * CENTER;
+        let disc = along * along - (CENTER.length_squared() - 1.0);
+        if disc < 0.0 {
+            return None;
+        }
+        let dist = along - disc.sqrt();
+        Some((dist, dir * dist - CENTER))
+    }
+
+    #[test]
+    fn first_hits_of_a_sphere() {
+        let f = sphere_features();
+        let mut inside = 0;
+        for j in 0..SIZE {
+            for i in 0..SIZE {
+                let corners = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+                if !corners
+                    .iter()
+                    .all(|&(jx, jy)| exact(i, j, jx, jy).is_some())
+                {
+                    continue;
+                }
+                inside += 1;
+                // the mean of the pixel's rays lies between the nearest and the farthest
+                // of them, and its normal no farther from the middle one than theirs
+                let (_, middle) = exact(i, j, 0.5, 0.5).unwrap();
+                let (mut near, mut far, mut spread) = (INFINITY, 0.0, 1.0);
+                for &(jx, jy) in corners.iter() {
+                    let (depth, normal) = exact(i, j, jx, jy).unwrap();
+                    near = depth.min(near);
+                    far = depth.max(far);
+                    spread = (normal * middle).min(spread);
+                }
+                let k = (j * SIZE + i) as usize;
+                assert!(
+                    f.depth[k] >= near && f.depth[k] <= far,
+                    "pixel ({}, {}) at depth {}, outside [{}, {}]",
+                    i,
+                    j,
+                    f.depth[k],
+                    near,
+                    far
+                );
+                assert!((f.normal[k].length() - 1.0).abs() < 1e-9);
+                assert!(
+                    f.normal[k] * middle >= spread - 1e-9,
+                    "pixel ({}, {}) has normal {}, the middle ray finds {}",
+                    i,
+                    j,
+                    f.normal[k],
+                    middle
+                );
+                assert_eq!(f.id[k], 7);
+                assert!((f.albedo[k] - Vec3::new(0.2, 0.4, 0.6)).length() < 1e-9);
+            }
+        }
+        assert!(inside >= 9, "{} pixels see only the sphere", inside);
+
+        // pixels that no ray of a fine grid over them finds the sphere from see the background
+        let mut outside = 0;
+        for j in 0..SIZE {
+            for i in 0..SIZE {
+                let grid = (0..=8).flat_map(|a| (0..=8).map(move |b| (a, b)));
+                if grid
+                    .map(|(a, b)| exact(i, j, a as f64 / 8.0, b as f64 / 8.0))
+                    .any(|hit| hit.is_some())
+                {
+                    continue;
+                }
+                outside += 1;
+                let k = (j * SIZE + i) as usize;
+                assert_eq!(f.depth[k], 0.0);
+                assert_eq!(f.normal[k].length_squared(), 0.0);
+                assert_eq!(f.id[k], 0);
+            }
+        }
+        assert!(outside >= 4, "{} pixels miss the sphere", outside);
+
+        // pixels on the silhouette average the depths of the rays that hit, which lie between
+        // the front of the sphere and its rim, not with zeros from the rays that miss
+        let (front, rim) = (
+            CENTER.length() - 1.0,
+            (CENTER.length_squared() - 1.0).sqrt(),
+        );
+        let mut edge = 0;
+        for j in 0..SIZE {
+            for i in 0..SIZE {
+                let k = (j * SIZE + i) as usize;
+                let corners = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+                let hits = corners
+                    .iter()
+                    .filter(|&&(jx, jy)| exact(i, j, jx, jy).is_some())
+                    .count();
+                if hits == 0 || hits == corners.len() || f.depth[k] == 0.0 {
+                    continue;
+                }
+                edge += 1;
+                assert!(
+                    f.depth[k] >= front - 1e-9 && f.depth[k] <= rim + 1e-9,
+                    "pixel ({}, {}) at depth {}, outside [{}, {}]",
+                    i,
+                    j,
+                    f.depth[k],
+                    front,
+                    rim
+                );
+                assert!((f.normal[k].length() - 1.0).abs() < 1e-9);
+                assert!((f.position[k] - CENTER).length() < 1.0 + 1e-9);
+            }
+        }
+        assert!(edge >= 4, "{} pixels on the silhouette", edge);
+    }
+
+    #[test]
+    fn passes_take_the_features_apart() {
+        let f = sphere_features();
+        let center = (SIZE / 2 * SIZE + SIZE / 2) as usize;
+        let depth = f.pass(Aov::Depth)[center];
+        assert_eq!(
+            (depth.x, depth.y, depth.z),
+            (f.depth[center], f.depth[center], f.depth[center])
+        );
+        assert_eq!(f.pass(Aov::Id)[center].x, 7.0);
+        // depth is scaled by the farthest hit, normals from [-1, 1] to [0, 1]
+        let shown = f.display(Aov::Depth)[center].x;
+        assert!(shown > 0.0 && shown <= 1.0);
+        let shown = f.display(Aov::Normal)[center];
+        assert!((shown - (f.normal[center] + Vec3::new(1.0, 1.0, 1.0)) * 0.5).length() < 1e-12);
+        assert_eq!(f.display(Aov::Id)[0].length_squared(), 0.0);
+    }
+}
@@ -0,0 +1,162 @@
+use std::f64::consts::PI;
+
+// how camera samples are weighted into the pixels around them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl Filter {
+    pub const NAMES: [&'static str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "box" => Some(Filter::Box),
+            "tent" => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            "lanczos" => Some(Filter::Lanczos),
+            _ => None,
+        }
+    }
+
+    // in pixels
+    pub fn default_radius(self) -> f64 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+            Filter::Lanczos => 3.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PixelFilter {
+    pub filter: Filter,
+    pub radius: f64,
+}
+
+impl PixelFilter {
+    pub fn new(filter: Filter, radius: Option<f64>) -> Self {
+        Self {
+            filter,
+            radius: radius.unwrap_or_else(|| filter.default_radius()),
+        }
+    }
+
+    // pixels on each side of its own that a sample can reach
+    pub fn margin(&self) -> u32 {
+        (self.radius - 0.5).ceil().max(0.0) as u32
+    }
+
+    // the filters are separable, a sample dx, dy pixels away from a pixel center is weighted
+    // by eval1(dx) * eval1(dy); mitchell and lanczos go negative away from the center. The
+    // support is (-r, r], so a box sample on the border of two pixels counts in one of them
+    pub fn eval1(&self, x: f64) -> f64 {
+        let r = self.radius;
+        if x <= -r || x > r {
+            return 0.0;
+        }
+        let x = x.abs();
+        match self.filter {
+            Filter::Box => 1.0,
+            Filter::Tent => r - x,
+            Filter::Gaussian => {
+                // standard deviation of half a pixel, shifted to reach zero at the radius
+                let g = |x: f64| (-2.0 * x * x).exp();
+                g(x) - g(r)
+            }
+            Filter::Mitchell => mitchell(2.0 * x / r),
+            Filter::Lanczos => sinc(x) * sinc(x / r),
+        }
+    }
+}
+
+// Mitchell-Netravali cubic with B = C = 1/3 over [0, 2]
+fn mitchell(x: f64) -> f64 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let ret = if x > 1.0 {
+        (-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)
+    };
+    ret / 6.0
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    fn all(radius: Option<f64>) -> Vec<PixelFilter> {
+        Filter::NAMES
+            .iter()
+            .map(|name| PixelFilter::new(Filter::from_name(name).unwrap(), radius))
+            .collect()
+    }
+
+    #[test]
+    fn filters_vanish_at_and_beyond_the_radius() {
+        for radius in &[None, Some(0.5), Some(1.0), Some(1.7)] {
+            for f in all(*radius) {
+                let r = f.radius;
+                assert_eq!(f.eval1(-r), 0.0, "{:?}", f);
+                for &x in &[r + 1e-9, r + 0.5, r + 10.0] {
+                    assert_eq!(f.eval1(x), 0.0, "{:?} at {}", f, x);
+                    assert_eq!(f.eval1(-x), 0.0, "{:?} at {}", f, -x);
+                }
+                if f.filter != Filter::Box {
+                    assert!(f.eval1(r).abs() < 1e-12, "{:?}", f);
+                }
+                assert!(f.eval1(0.0) > 0.0, "{:?}", f);
+            }
+        }
+    }
+
+    #[test]
+    fn margin_covers_the_radius() {
+        for &(radius, margin) in &[(0.5, 0), (1.0, 1), (1.5, 1), (1.6, 2)] {
+            for f in all(Some(radius)) {
+                assert_eq!(f.margin(), margin, "{:?}", f);
+            }
+        }
+    }
+
+    #[test]
+    fn half_pixel_box_is_the_pixel_average() {
+        // render_tile weights the pixel t columns right of a sample's own by eval1(t - dx)
+        // and the pixel t rows down by eval1(dy - t), for offsets dx = jx - 0.5 and
+        // dy = 0.5 - jy of the sample from its pixel center; with no margin only the own
+        // pixel is reached, and every jitter in [0, 1) gets weight one, as the plain average
+        let f = PixelFilter::new(Filter::Box, None);
+        assert_eq!(f.margin(), 0);
+        for k in 0..=100 {
+            let jitter = (k as f64 / 100.0).min(0.999_999);
+            let (dx, dy) = (jitter - 0.5, 0.5 - jitter);
+            for &(own, before, after) in &[(-dx, -1.0 - dx, 1.0 - dx), (dy, dy + 1.0, dy - 1.0)] {
+                assert_eq!(f.eval1(own), 1.0, "jitter {}", jitter);
+                assert_eq!(f.eval1(before), 0.0, "jitter {}", jitter);
+                assert_eq!(f.eval1(after), 0.0, "jitter {}", jitter);
+            }
+        }
+    }
+}
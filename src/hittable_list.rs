@@ -1,10 +1,13 @@
+use crate::sampler::Sampler;
 use crate::Box;
 use crate::BvhNode;
 use crate::CheckerTexture;
+use crate::ConstantMedium;
 use crate::Dielectric;
 use crate::DiffuseLight;
 use crate::FlipFace;
 use crate::HitRecord;
+use crate::Isotropic;
 use crate::Lambertian;
 use crate::Metal;
 use crate::Object;
@@ -12,6 +15,7 @@ use crate::Ray;
 use crate::RotateY;
 use crate::SolidColor;
 use crate::Sphere;
+use crate::Tagged;
 use crate::Translate;
 use crate::Vec3;
 use crate::XYRect;
@@ -20,10 +24,19 @@ use crate::YZRrect;
 use crate::AABB;
 use std::sync::Arc;
 extern crate rand;
+use rand::rngs::StdRng;
 use rand::Rng;
 
 const INFINITY: f64 = 1e15;
 
+// what stands in the cornell box
+#[derive(Clone, Copy, PartialEq)]
+enum Contents {
+    Boxes,
+    Glass,
+    Smoke,
+}
+
 #[derive(Clone)]
 pub struct HittableList {
     pub objects: Vec<Arc<dyn Object>>,
@@ -43,14 +56,25 @@ impl Object for HittableList {
         temp_rec
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        let mut tr = 1.0;
+        for object in self.objects.iter() {
+            tr *= object.transmittance(r, t_min, t_max);
+            if tr == 0.0 {
+                break;
+            }
+        }
+        tr
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
         if self.objects.is_empty() {
             return Option::None;
         }
         let mut first_box = true;
         let mut output_box = AABB::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0));
         for object in self.objects.iter() {
-            let tmp = object.bounding_box();
+            let tmp = object.bounding_box(t0, t1);
             match tmp {
                 None => {
                     return Option::None;
@@ -77,9 +101,29 @@ impl Object for HittableList {
         sum
     }
 
-    fn random(&self, o: Vec3) -> Vec3 {
-        let mut rng = rand::thread_rng();
-        self.objects[rng.gen_range(0, self.objects.len())].random(o)
+    fn random(&self, o: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        self.objects[self.pick(s)].random(o, s)
+    }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (rec, pdf) = self.objects[self.pick(s)].sample_surface(s);
+        (rec, pdf / self.objects.len() as f64)
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        let r = Ray::new(o, d, 0.0);
+        let mut closest = None;
+        let mut closest_so_far = INFINITY;
+        for object in self.objects.iter() {
+            if let Some(rec) = object.hit(r, 0.001, closest_so_far) {
+                closest_so_far = rec.t;
+                closest = Some(object);
+            }
+        }
+        match closest {
+            Some(object) => object.surface_pdf(o, d) / self.objects.len() as f64,
+            None => 0.0,
+        }
     }
 }
 
@@ -96,7 +140,21 @@ impl HittableList {
         self.num += 1;
     }
 
-    pub fn random_scene() -> BvhNode {
+    // wraps every object so its hits carry its position in the list as the object id
+    pub fn tagged(self) -> Self {
+        let mut ret = HittableList::new();
+        for (k, obj) in self.objects.into_iter().enumerate() {
+            ret.add(Arc::new(Tagged::new(k as u32 + 1, obj)));
+        }
+        ret
+    }
+
+    // an object picked uniformly, for sampling the list as a light
+    fn pick(&self, s: &mut dyn Sampler) -> usize {
+        ((s.get_1d() * self.objects.len() as f64) as usize).min(self.objects.len() - 1)
+    }
+
+    pub fn random_scene(rng: &mut StdRng) -> BvhNode {
         let mut world = HittableList::new();
 
         let checker = CheckerTexture::new(
@@ -111,22 +169,21 @@ impl HittableList {
 
         for a in -11..11 {
             for b in -11..11 {
-                let choose_mat = rand::random::<f64>();
+                let choose_mat = rng.gen::<f64>();
                 let center = Vec3::new(
-                    a as f64 + 0.9 * rand::random::<f64>(),
+                    a as f64 + 0.9 * rng.gen::<f64>(),
                     0.2,
-                    b as f64 + 0.9 * rand::random::<f64>(),
+                    b as f64 + 0.9 * rng.gen::<f64>(),
                 );
                 if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                     if choose_mat < 0.8 {
                         //difuse
-                        let albedo = Vec3::elemul(Vec3::random1(), Vec3::random1());
+                        let albedo = Vec3::elemul(Vec3::random1(rng), Vec3::random1(rng));
                         let sphere_mat = Lambertian::new(SolidColor::new(albedo));
                         world.add(Arc::new(Sphere::new(center, 0.2, sphere_mat)));
                     } else if choose_mat < 0.95 {
                         //metal
-                        let albedo = Vec3::random2(0.5, 1.0);
-                        let mut rng = rand::thread_rng();
+                        let albedo = Vec3::random2(0.5, 1.0, rng);
                         let fuzz = rng.gen_range(0.0, 0.5);
                         let sphere_mat = Metal::new(albedo, fuzz);
                         world.add(Arc::new(Sphere::new(center, 0.2, sphere_mat)));
@@ -149,10 +206,10 @@ impl HittableList {
         world.add(Arc::new(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, mat3)));
 
         //world
-        BvhNode::new(world, 0.001, INFINITY)
+        BvhNode::new(world.tagged(), 0.0, 0.0, rng)
     }
 
-    pub fn night() -> BvhNode {
+    pub fn night(rng: &mut StdRng) -> BvhNode {
         let mut world = HittableList::new();
 
         let checker = CheckerTexture::new(
@@ -166,33 +223,31 @@ impl HittableList {
         )));
         for a in -13..13 {
             for b in -13..13 {
-                let choose_mat = rand::random::<f64>();
-                let mut rng = rand::thread_rng();
+                let choose_mat = rng.gen::<f64>();
                 let r = rng.gen_range(0.09, 0.29);
                 let center = Vec3::new(
-                    a as f64 + 0.9 * rand::random::<f64>(),
+                    a as f64 + 0.9 * rng.gen::<f64>(),
                     r,
-                    b as f64 + 0.9 * rand::random::<f64>(),
+                    b as f64 + 0.9 * rng.gen::<f64>(),
                 );
 
                 if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                     if choose_mat < 0.6 {
                         let dl = DiffuseLight::new(SolidColor::new(Vec3::elemul(
-                            Vec3::random1(),
-                            Vec3::random1(),
+                            Vec3::random1(rng),
+                            Vec3::random1(rng),
                         )));
                         world.add(Arc::new(Sphere::new(center, r * 0.99999, dl)));
                         let ke = Dielectric::new(4.0);
                         world.add(Arc::new(Sphere::new(center, r, ke)));
                     } else if choose_mat < 0.65 {
                         //difuse
-                        let albedo = Vec3::elemul(Vec3::random1(), Vec3::random1());
+                        let albedo = Vec3::elemul(Vec3::random1(rng), Vec3::random1(rng));
                         let sphere_mat = Lambertian::new(SolidColor::new(albedo));
                         world.add(Arc::new(Sphere::new(center, r, sphere_mat)));
                     } else if choose_mat < 0.8 {
                         //metal
-                        let albedo = Vec3::random2(0.5, 1.0);
-                        let mut rng = rand::thread_rng();
+                        let albedo = Vec3::random2(0.5, 1.0, rng);
                         let fuzz = rng.gen_range(0.0, 0.5);
                         let sphere_mat = Metal::new(albedo, fuzz);
                         world.add(Arc::new(Sphere::new(center, r, sphere_mat)));
@@ -222,21 +277,39 @@ impl HittableList {
         world.add(Arc::new(Sphere::new(Vec3::new(3.0, 0.45, 0.0), 0.45, ke)));
 
         //world
-        BvhNode::new(world, 0.001, INFINITY)
+        BvhNode::new(world.tagged(), 0.0, 0.0, rng)
+    }
+
+    // returns the world and the list of its emitters
+    pub fn cornell_box() -> (HittableList, HittableList) {
+        HittableList::cornell(Contents::Boxes)
+    }
+
+    // the short box replaced by a glass sphere, whose caustic path tracing struggles with
+    pub fn cornell_glass() -> (HittableList, HittableList) {
+        HittableList::cornell(Contents::Glass)
+    }
+
+    // the boxes turned into dark and light smoke
+    pub fn cornell_smoke() -> (HittableList, HittableList) {
+        HittableList::cornell(Contents::Smoke)
     }
 
-    pub fn cornell_box() -> HittableList {
+    fn cornell(contents: Contents) -> (HittableList, HittableList) {
         let red = Lambertian::new(SolidColor::new(Vec3::new(0.65, 0.05, 0.05)));
         let white = Lambertian::new(SolidColor::new(Vec3::new(0.73, 0.73, 0.73)));
         let green = Lambertian::new(SolidColor::new(Vec3::new(0.12, 0.45, 0.15)));
         let light = DiffuseLight::new(SolidColor::new(Vec3::new(15.0, 15.0, 15.0)));
 
         let mut world = HittableList::new();
+        let mut lights = HittableList::new();
         world.add(Arc::new(YZRrect::new(0.0, 555.0, 0.0, 555.0, 555.0, green)));
         world.add(Arc::new(YZRrect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-        world.add(Arc::new(FlipFace::new(XZRect::new(
+        let light_obj = Arc::new(FlipFace::new(XZRect::new(
             213.0, 343.0, 227.0, 332.0, 554.0, light,
-        ))));
+        )));
+        world.add(light_obj.clone());
+        lights.add(light_obj);
         world.add(Arc::new(XZRect::new(
             0.0,
             555.0,
@@ -270,26 +343,38 @@ impl HittableList {
             //white,
         );
         let rot1 = RotateY::new(box1, 15.0);
-        let ww1 = Arc::new(Translate::new(rot1, Vec3::new(265.0, 0.0, 295.0)));
-        world.add(ww1);
-
-        /*let glass_sphere = Arc::new(Sphere::new(
-            Vec3::new(190.0, 90.0, 190.0),
-            90.0,
-            Dielectric::new(1.5),
-        ));
-        world.add(glass_sphere);*/
+        let ww1 = Translate::new(rot1, Vec3::new(265.0, 0.0, 295.0));
+        if contents == Contents::Smoke {
+            let smoke = Isotropic::new(SolidColor::new(Vec3::new(0.0, 0.0, 0.0)));
+            world.add(Arc::new(ConstantMedium::new(ww1, 0.01, smoke)));
+        } else {
+            world.add(Arc::new(ww1));
+        }
 
-        let box2 = Box::new(
-            Vec3::new(0.0, 0.0, 0.0),
-            Vec3::new(165.0, 165.0, 165.0),
-            white,
-        );
-        let rot2 = RotateY::new(box2, -18.0);
-        let ww2 = Arc::new(Translate::new(rot2, Vec3::new(130.0, 0.0, 65.0)));
-        world.add(ww2);
+        if contents == Contents::Glass {
+            let glass_sphere = Arc::new(Sphere::new(
+                Vec3::new(190.0, 90.0, 190.0),
+                90.0,
+                Dielectric::new(1.5),
+            ));
+            world.add(glass_sphere);
+        } else {
+            let box2 = Box::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(165.0, 165.0, 165.0),
+                white,
+            );
+            let rot2 = RotateY::new(box2, -18.0);
+            let ww2 = Translate::new(rot2, Vec3::new(130.0, 0.0, 65.0));
+            if contents == Contents::Smoke {
+                let smoke = Isotropic::new(SolidColor::new(Vec3::new(1.0, 1.0, 1.0)));
+                world.add(Arc::new(ConstantMedium::new(ww2, 0.01, smoke)));
+            } else {
+                world.add(Arc::new(ww2));
+            }
+        }
 
-        world
+        (world.tagged(), lights)
         //BvhNode::new(world, 0.001, INFINITY)
     }
 }
@@ -0,0 +1,540 @@
+use crate::diagnostics;
+use crate::environment::Background;
+use crate::material::ScaRet;
+use crate::sampler::Sampler;
+use crate::spectrum::Wavelengths;
+use crate::CosPdf;
+use crate::HitRecord;
+use crate::HittablePdf;
+use crate::Object;
+use crate::Pdf;
+use crate::Ray;
+use crate::RenderSettings;
+use crate::Vec3;
+use crate::INFINITY;
+
+// survival probability of a path with the given throughput, None once it is terminated
+pub fn russian_roulette(
+    settings: &RenderSettings,
+    bounce: i32,
+    throughput: Vec3,
+    sampler: &mut dyn Sampler,
+) -> Option<f64> {
+    if bounce < settings.rr_depth {
+        return Some(1.0);
+    }
+    let p = throughput.max_component().min(0.95);
+    if p > 0.0 && sampler.get_1d() < p {
+        Some(p)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Heuristic {
+    Balance,
+    Power,
+}
+
+impl Heuristic {
+    pub const NAMES: [&'static str; 2] = ["balance", "power"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "balance" => Some(Heuristic::Balance),
+            "power" => Some(Heuristic::Power),
+            _ => None,
+        }
+    }
+
+    // weight of a sample drawn from f when g could also have produced it
+    pub fn weight(self, pdf_f: f64, pdf_g: f64) -> f64 {
+        let (f, g) = match self {
+            Heuristic::Balance => (pdf_f, pdf_g),
+            Heuristic::Power => (pdf_f * pdf_f, pdf_g * pdf_g),
+        };
+        if f + g > 0.0 {
+            f / (f + g)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    Path,
+    Bdpt,
+    Photon,
+    Sppm,
+}
+
+impl Integrator {
+    pub const NAMES: [&'static str; 4] = ["path", "bdpt", "photon", "sppm"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "path" => Some(Integrator::Path),
+            "bdpt" => Some(Integrator::Bdpt),
+            "photon" => Some(Integrator::Photon),
+            "sppm" => Some(Integrator::Sppm),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_black(c: Vec3) -> bool {
+    c.x == 0.0 && c.y == 0.0 && c.z == 0.0
+}
+
+// the start of a light path: a point on one of the lights and a cosine-distributed direction
+// on either side of it, diffuse lights emit from both faces
+pub struct Emission<'a> {
+    pub rec: HitRecord<'a>,
+    pub le: Vec3,
+    pub ray: Ray,
+    // of the point per unit area and of the direction per solid angle
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+impl<'a> Emission<'a> {
+    // radiance carried along the ray divided by the pdfs that chose it
+    pub fn beta(&self) -> Vec3 {
+        let cos = (self.ray.dir.unit() * self.rec.normal).abs();
+        self.le * (cos / (self.pdf_pos * self.pdf_dir))
+    }
+}
+
+pub fn sample_emission<'a>(
+    lights: &'a dyn Object,
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> Option<Emission<'a>> {
+    let (rec, pdf_pos) = lights.sample_surface(sampler);
+    let le = rec.mat.unwrap().emitted(&rec, rec.u, rec.v, rec.p);
+    if pdf_pos <= 0.0 || is_black(le) {
+        return None;
+    }
+    let side = if sampler.get_1d() < 0.5 {
+        rec.normal
+    } else {
+        -rec.normal
+    };
+    let emit = CosPdf::new(side);
+    let dir = emit.generate(sampler);
+    let pdf_dir = 0.5 * emit.value(dir);
+    if pdf_dir <= 0.0 {
+        return None;
+    }
+    Some(Emission {
+        rec,
+        le,
+        ray: Ray::new(rec.p, dir, time),
+        pdf_pos,
+        pdf_dir,
+    })
+}
+
+// whether the surface found at distance t along r belongs to the light list, only those
+// could also have been reached by next-event estimation
+pub fn is_light(r: Ray, t: f64, lights: &dyn Object) -> bool {
+    match lights.hit(r, 0.001, INFINITY) {
+        Some(lrec) => (lrec.t - t).abs() <= 1e-6 * t.max(1.0),
+        None => false,
+    }
+}
+
+// next-event estimation: picks a point on one of the lights, casts a shadow ray towards it
+// and returns the light's emitted radiance and the factor that makes it the direct lighting
+// at rec, not yet multiplied by the throughput; kept apart so spectral renders can upsample
+// the emission, and weighted against sampling the material's scatter pdf unless mis is None
+pub fn sample_light(
+    r_in: Ray,
+    rec: &HitRecord,
+    scatter: &ScaRet,
+    lights: &dyn Object,
+    world: &dyn Object,
+    mis: Option<Heuristic>,
+    sampler: &mut dyn Sampler,
+) -> (Vec3, Vec3) {
+    let none = (Vec3::zero(), Vec3::zero());
+    let light = HittablePdf::new(rec.p, lights);
+    let dir = light.generate(sampler);
+    let light_pdf = light.value(dir);
+    if light_pdf <= 0.0 {
+        return none;
+    }
+    let shadow_ray = Ray::new(rec.p, dir, r_in.time);
+    let lrec = match lights.hit(shadow_ray, 0.001, INFINITY) {
+        Some(lrec) => lrec,
+        None => return none,
+    };
+    let le = lrec.mat.unwrap().emitted(&lrec, lrec.u, lrec.v, lrec.p);
+    let mat = rec.mat.unwrap();
+    let bsdf = scatter.attenustion * mat.scattering_pdf(r_in, rec, shadow_ray);
+    if is_black(le) || is_black(bsdf) {
+        return none;
+    }
+    // stop just short of the light so it does not occlude itself
+    let tr = world.transmittance(shadow_ray, 0.001, lrec.t * (1.0 - 1e-6));
+    if tr == 0.0 {
+        return none;
+    }
+    let w = match mis {
+        Some(h) => h.weight(
+            light_pdf,
+            scatter.pdf.as_ref().map_or(0.0, |p| p.value(dir)),
+        ),
+        None => 1.0,
+    };
+    (le, bsdf * (tr * w / light_pdf))
+}
+
+// unidirectional path tracer; at every diffuse vertex it adds direct lighting through an
+// explicit shadow ray and continues with one material sample, combining the two with
+// multiple importance sampling; spectral renders follow three wavelengths instead of rgb and
+// return CIE XYZ
+pub fn ray_color(
+    r: Ray,
+    background: &Background,
+    lights: Option<&dyn Object>,
+    world: &dyn Object,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let mut ray = r;
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    let mut radiance = Vec3::zero();
+    let mut bounce = 0;
+    // pdf of the material sample that produced `ray`, None after the camera or a specular bounce
+    let mut prev_pdf: Option<(Vec3, f64)> = None;
+    let mut wavelengths = if settings.spectral {
+        Some(Wavelengths::sample(sampler.get_1d()))
+    } else {
+        None
+    };
+    let upsample = |wl: Option<Wavelengths>, c: Vec3| wl.map_or(c, |wl| wl.upsample(c));
+    diagnostics::log(|| format!("camera ray from {} along {}", ray.beg, ray.dir));
+    while bounce < settings.max_depth {
+        let rec = match world.hit(ray, 0.001, INFINITY) {
+            Some(rec) => rec,
+            None => {
+                let bg = background.radiance(ray.dir);
+                // an environment map or sky is among the lights and may have been sampled already
+                let w = match (lights, prev_pdf) {
+                    (Some(lights), Some((prev_p, mat_pdf))) if background.is_light() => settings
+                        .mis
+                        .weight(mat_pdf, lights.pdf_value(prev_p, ray.dir)),
+                    _ => 1.0,
+                };
+                radiance += Vec3::elemul(throughput, upsample(wavelengths, bg)) * w;
+                diagnostics::log(|| {
+                    format!(
+                        "bounce {}: miss, background {}, mis weight {}",
+                        bounce, bg, w
+                    )
+                });
+                break;
+            }
+        };
+        let mat = rec.mat.unwrap();
+        diagnostics::log(|| {
+            format!(
+                "bounce {}: {} (object {}) at {}, normal {}, t {}, front face {}",
+                bounce,
+                mat.name(),
+                rec.id,
+                rec.p,
+                rec.normal,
+                rec.t,
+                rec.front_face
+            )
+        });
+        let emitted = mat.emitted(&rec, rec.u, rec.v, rec.p) + rec.emission;
+        if !is_black(emitted) {
+            let w = match (lights, prev_pdf) {
+                (Some(lights), Some((prev_p, mat_pdf))) if is_light(ray, rec.t, lights) => settings
+                    .mis
+                    .weight(mat_pdf, lights.pdf_value(prev_p, ray.dir)),
+                _ => 1.0,
+            };
+            radiance += Vec3::elemul(throughput, upsample(wavelengths, emitted)) * w;
+            diagnostics::log(|| format!("    emitted {}, mis weight {}", emitted, w));
+        }
+
+        let mut s = match wavelengths {
+            Some(wl) => mat.scatter_at(ray, &rec, wl.hero(), sampler),
+            None => mat.scatter(ray, &rec, sampler),
+        };
+        if !s.jud {
+            break;
+        }
+        if let Some(wl) = wavelengths.as_mut() {
+            s.attenustion = wl.upsample(s.attenustion);
+            if mat.dispersive() {
+                throughput = wl.drop_secondary(throughput);
+            }
+        }
+        let (scattered, f) = if s.is_specular {
+            prev_pdf = None;
+            diagnostics::log(|| format!("    specular towards {}", s.scattered.dir));
+            (s.scattered, s.attenustion)
+        } else {
+            if let Some(lights) = lights {
+                let (le, f) =
+                    sample_light(ray, &rec, &s, lights, world, Some(settings.mis), sampler);
+                let ld = Vec3::elemul(upsample(wavelengths, le), f);
+                radiance += Vec3::elemul(throughput, ld);
+                diagnostics::log(|| format!("    light sample {}", ld));
+            }
+
+            let mat_pdf = s.pdf.unwrap();
+            let dir = mat_pdf.generate(sampler);
+            let pdf_val = mat_pdf.value(dir);
+            let scattered = Ray::new(rec.p, dir, ray.time);
+            prev_pdf = Some((rec.p, pdf_val));
+            let scattering_pdf = mat.scattering_pdf(ray, &rec, scattered);
+            diagnostics::log(|| {
+                format!(
+                    "    scattered towards {}, pdf {}, scattering pdf {}",
+                    dir, pdf_val, scattering_pdf
+                )
+            });
+            (scattered, s.attenustion * (scattering_pdf / pdf_val))
+        };
+
+        throughput = Vec3::elemul(throughput, f);
+        match russian_roulette(settings, bounce, throughput, sampler) {
+            Some(p) => throughput = throughput / p,
+            None => {
+                diagnostics::log(|| String::from("    stopped by russian roulette"));
+                break;
+            }
+        }
+        diagnostics::log(|| format!("    throughput {}", throughput));
+        ray = scattered;
+        bounce += 1;
+    }
+    match wavelengths {
+        Some(wl) => wl.to_xyz(radiance),
+        None => radiance,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::sampler::{Fixed, SamplerKind, Sampling};
+    use crate::{DiffuseLight, HittableList, Lambertian, SolidColor, Sphere, XYRect};
+    use std::f64::consts::PI;
+    use std::sync::Arc;
+
+    fn settings() -> RenderSettings {
+        RenderSettings {
+            max_depth: 50,
+            ..RenderSettings::test(1, 1, Integrator::Path)
+        }
+    }
+
+    #[test]
+    fn russian_roulette_waits_for_rr_depth() {
+        let settings = settings();
+        let dim = Vec3::new(0.01, 0.0, 0.0);
+        // every path survives the first bounces, however dim, and keeps its weight
+        for bounce in 0..settings.rr_depth {
+            for &u in &[0.0, 0.5, 0.999] {
+                let p = russian_roulette(&settings, bounce, dim, &mut Fixed(u, u));
+                assert_eq!(p, Some(1.0));
+            }
+            let p = russian_roulette(&settings, bounce, Vec3::zero(), &mut Fixed(0.5, 0.5));
+            assert_eq!(p, Some(1.0));
+        }
+        // then it survives with the brightest channel of its throughput, at most 0.95
+        let half = Vec3::new(0.2, 0.5, 0.1);
+        let rr = settings.rr_depth;
+        assert_eq!(
+            russian_roulette(&settings, rr, half, &mut Fixed(0.4, 0.4)),
+            Some(0.5)
+        );
+        assert_eq!(
+            russian_roulette(&settings, rr, half, &mut Fixed(0.6, 0.6)),
+            None
+        );
+        let bright = Vec3::new(3.0, 3.0, 3.0);
+        assert_eq!(
+            russian_roulette(&settings, rr, bright, &mut Fixed(0.9, 0.9)),
+            Some(0.95)
+        );
+        assert_eq!(
+            russian_roulette(&settings, rr, bright, &mut Fixed(0.96, 0.96)),
+            None
+        );
+        assert_eq!(
+            russian_roulette(&settings, rr, Vec3::zero(), &mut Fixed(0.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn a_ball_in_a_white_furnace_reflects_its_albedo() {
+        // nothing the ball sends out can come back to it, so every path returns its albedo
+        let ball = Sphere::new(
+            Vec3::new(0.0, 0.0, -3.0),
+            1.0,
+            Lambertian::new(SolidColor::new(Vec3::new(0.4, 0.6, 0.8))),
+        );
+        let white = Background::Color(Vec3::new(1.0, 1.0, 1.0));
+        let mut settings = settings();
+        settings.rr_depth = settings.max_depth;
+        let mut sampler = Sampling::new(SamplerKind::Independent, 3, 64).sampler();
+        let at = Ray::new(Vec3::zero(), Vec3::new(0.1, -0.2, -1.0), 0.0);
+        for index in 0..64 {
+            sampler.start((0, 0), index);
+            let c = ray_color(at, &white, None, &ball, &settings, sampler.as_mut());
+            assert!((c - Vec3::new(0.4, 0.6, 0.8)).length() < 1e-9, "{}", c);
+        }
+
+        // a miss sees the background, and a single bounce has nowhere to go
+        let away = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let c = ray_color(away, &white, None, &ball, &settings, sampler.as_mut());
+        assert_eq!(c, Vec3::new(1.0, 1.0, 1.0));
+        settings.max_depth = 1;
+        let c = ray_color(at, &white, None, &ball, &settings, sampler.as_mut());
+        assert_eq!(c, Vec3::zero());
+    }
+
+    #[test]
+    fn mis_weights_sum_to_one() {
+        let pdfs = [(1.0, 1.0), (0.3, 2.5), (4.0, 0.01), (0.0, 0.7), (1e-8, 1e8)];
+        for &h in &[Heuristic::Balance, Heuristic::Power] {
+            for &(f, g) in &pdfs {
+                let sum = h.weight(f, g) + h.weight(g, f);
+                assert!((sum - 1.0).abs() < 1e-12, "{:?} {} {}", h, f, g);
+            }
+            // neither strategy could have drawn the sample
+            assert_eq!(h.weight(0.0, 0.0), 0.0);
+            assert_eq!(h.weight(0.0, 3.0), 0.0);
+        }
+        // the power heuristic leans further towards the likelier strategy
+        assert!(Heuristic::Power.weight(2.0, 1.0) > Heuristic::Balance.weight(2.0, 1.0));
+        assert_eq!(Heuristic::Balance.weight(2.0, 1.0), 2.0 / 3.0);
+        assert_eq!(Heuristic::Power.weight(2.0, 1.0), 0.8);
+    }
+
+    #[test]
+    fn shadow_rays_find_the_light_unless_blocked() {
+        let gray = Lambertian::new(SolidColor::new(Vec3::new(0.5, 0.5, 0.5)));
+        let floor = XYRect::new(-4.0, 4.0, -4.0, 4.0, 0.0, gray.clone());
+        let glow = DiffuseLight::new(SolidColor::new(Vec3::new(4.0, 4.0, 4.0)));
+        let light = XYRect::new(-0.5, 0.5, -0.5, 0.5, 1.0, glow);
+        let mut world = HittableList::new();
+        world.add(Arc::new(floor.clone()));
+        world.add(Arc::new(light.clone()));
+
+        // the floor at the origin, right below the middle of the light, which is where the
+        // sample at (0.5, 0.5) lands: one unit away, straight up, so its pdf is one
+        let r_in = Ray::new(Vec3::new(1.0, 0.0, 0.5), Vec3::new(-2.0, 0.0, -1.0), 0.0);
+        let rec = floor.hit(r_in, 0.001, INFINITY).unwrap();
+        let scatter = gray.scatter(r_in, &rec, &mut Fixed(0.5, 0.5));
+        let (le, f) = sample_light(
+            r_in,
+            &rec,
+            &scatter,
+            &light,
+            &world,
+            None,
+            &mut Fixed(0.5, 0.5),
+        );
+        assert_eq!(le, Vec3::new(4.0, 4.0, 4.0));
+        assert!((f.x - 0.5 / PI).abs() < 1e-12, "{}", f);
+
+        // weighted against the cosine lobe that could also have found the light
+        let mis = Some(Heuristic::Power);
+        let (_, f) = sample_light(
+            r_in,
+            &rec,
+            &scatter,
+            &light,
+            &world,
+            mis,
+            &mut Fixed(0.5, 0.5),
+        );
+        let w = Heuristic::Power.weight(1.0, 1.0 / PI);
+        assert!((f.x - 0.5 / PI * w).abs() < 1e-12, "{}", f);
+
+        // a tile halfway up casts its shadow over the point
+        world.add(Arc::new(XYRect::new(-0.1, 0.1, -0.1, 0.1, 0.5, gray)));
+        let (le, f) = sample_light(
+            r_in,
+            &rec,
+            &scatter,
+            &light,
+            &world,
+            mis,
+            &mut Fixed(0.5, 0.5),
+        );
+        assert!(is_black(le) && is_black(f));
+    }
+
+    #[test]
+    fn mis_counts_the_light_once() {
+        // a gray plane lit by a small square light above it, seen at the point right below it;
+        // the plane cannot see itself, so all its light is direct
+        let gray = Lambertian::new(SolidColor::new(Vec3::new(0.5, 0.5, 0.5)));
+        let glow = DiffuseLight::new(SolidColor::new(Vec3::new(4.0, 4.0, 4.0)));
+        let light = XYRect::new(-0.5, 0.5, -0.5, 0.5, 1.0, glow);
+        let mut world = HittableList::new();
+        world.add(Arc::new(XYRect::new(-50.0, 50.0, -50.0, 50.0, 0.0, gray)));
+        world.add(Arc::new(light.clone()));
+        let black = Background::Color(Vec3::zero());
+        let r = Ray::new(Vec3::new(0.3, 0.0, 0.5), Vec3::new(-0.3, 0.0, -0.5), 0.0);
+
+        let n = 200_000;
+        let mean = |lights: Option<&dyn Object>, mis: Heuristic| {
+            let mut settings = settings();
+            settings.rr_depth = settings.max_depth;
+            settings.mis = mis;
+            let mut sampler = Sampling::new(SamplerKind::Independent, 9, 1).sampler();
+            let mut sum = 0.0;
+            for index in 0..n {
+                sampler.start((0, 0), index);
+                sum += ray_color(r, &black, lights, &world, &settings, sampler.as_mut()).x;
+            }
+            sum / n as f64
+        };
+        // material samples alone find the light now and then, which is unbiased but noisy
+        let reference = mean(None, Heuristic::Power);
+        // the light samples alone, the term mis would count twice if it did not weight it
+        let mut nee = 0.0;
+        let mut sampler = Sampling::new(SamplerKind::Independent, 10, 1).sampler();
+        let rec = world.hit(r, 0.001, INFINITY).unwrap();
+        let scatter = rec.mat.unwrap().scatter(r, &rec, sampler.as_mut());
+        for index in 0..n {
+            sampler.start((0, 0), index);
+            let (le, f) = sample_light(r, &rec, &scatter, &light, &world, None, sampler.as_mut());
+            nee += Vec3::elemul(le, f).x / n as f64;
+        }
+        assert!(
+            (nee - reference).abs() < 0.02 * reference,
+            "{} against {}",
+            nee,
+            reference
+        );
+
+        for &h in &[Heuristic::Balance, Heuristic::Power] {
+            let mis = mean(Some(&light), h);
+            assert!(
+                (mis - reference).abs() < 0.02 * reference,
+                "{:?} gives {} against {}",
+                h,
+                mis,
+                reference
+            );
+            assert!(mis < reference + 0.5 * nee);
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::fmt;
+
+// how deep arrays and objects may nest before the parser gives up rather than the stack
+const MAX_DEPTH: usize = 128;
+
+#[derive(Clone, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+// every value remembers the line it starts on so that errors can point at it
+#[derive(Clone, Debug)]
+pub struct Value {
+    pub line: usize,
+    pub json: Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, msg: String) -> Self {
+        Self { line, msg }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Value {
+    pub fn kind(&self) -> &'static str {
+        match self.json {
+            Json::Null => "null",
+            Json::Bool(_) => "a boolean",
+            Json::Number(_) => "a number",
+            Json::Str(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+
+    fn expected(&self, what: &str) -> ParseError {
+        ParseError::new(
+            self.line,
+            format!("expected {}, found {}", what, self.kind()),
+        )
+    }
+
+    pub fn as_f64(&self) -> Result<f64, ParseError> {
+        match self.json {
+            Json::Number(x) => Ok(x),
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, ParseError> {
+        match self.json {
+            Json::Bool(b) => Ok(b),
+            _ => Err(self.expected("a boolean")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, ParseError> {
+        match &self.json {
+            Json::Str(s) => Ok(s),
+            _ => Err(self.expected("a string")),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Value], ParseError> {
+        match &self.json {
+            Json::Array(a) => Ok(a),
+            _ => Err(self.expected("an array")),
+        }
+    }
+
+    pub fn as_object(&self) -> Result<&[(String, Value)], ParseError> {
+        match &self.json {
+            Json::Object(o) => Ok(o),
+            _ => Err(self.expected("an object")),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match &self.json {
+            Json::Object(o) => o.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, ParseError> {
+    let mut p = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+        depth: 0,
+    };
+    let v = p.value()?;
+    p.skip_ws();
+    if p.pos < p.chars.len() {
+        return Err(p.error("trailing characters after the top-level value"));
+    }
+    Ok(v)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    // arrays and objects entered and not yet left
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> ParseError {
+        ParseError::new(self.line, String::from(msg))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ' ' || c == '\t' || c == '\n' || c == '\r' {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        self.skip_ws();
+        match self.bump() {
+            Some(x) if x == c => Ok(()),
+            Some(x) => Err(self.error(&format!("expected `{}`, found `{}`", c, x))),
+            None => Err(self.error(&format!("expected `{}`, found end of file", c))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_ws();
+        let line = self.line;
+        let json = match self.peek() {
+            None => return Err(self.error("unexpected end of file")),
+            Some('{') => self.nested(Self::object)?,
+            Some('[') => self.nested(Self::array)?,
+            Some('"') => Json::Str(self.string()?),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number()?,
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.peek() {
+                    if !c.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    word.push(c);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    "null" => Json::Null,
+                    _ => return Err(self.error(&format!("unexpected word `{}`", word))),
+                }
+            }
+            Some(c) => return Err(self.error(&format!("unexpected character `{}`", c))),
+        };
+        Ok(Value { line, json })
+    }
+
+    fn nested(&mut self, f: fn(&mut Self) -> Result<Json, ParseError>) -> Result<Json, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("arrays and objects are nested too deeply"));
+        }
+        self.depth += 1;
+        let json = f(self);
+        self.depth -= 1;
+        json
+    }
+
+    fn object(&mut self) -> Result<Json, ParseError> {
+        self.expect('{')?;
+        let mut fields: Vec<(String, Value)> = Vec::new();
+        self.skip_ws();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a quoted field name"));
+            }
+            let line = self.line;
+            let key = self.string()?;
+            if fields.iter().any(|(k, _)| *k == key) {
+                return Err(ParseError::new(line, format!("duplicate field `{}`", key)));
+            }
+            self.expect(':')?;
+            let v = self.value()?;
+            fields.push((key, v));
+            self.skip_ws();
+            match self.bump() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected `,` or `}` in object")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_ws();
+        if self.peek() == Some(']') {
+            self.bump();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_ws();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,` or `]` in array")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = 0;
+                            for _ in 0..4 {
+                                let d = self.bump().and_then(|c| c.to_digit(16));
+                                match d {
+                                    Some(d) => code = code * 16 + d,
+                                    None => return Err(self.error("invalid \\u escape")),
+                                }
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, ParseError> {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+                s.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match s.parse::<f64>() {
+            Ok(x) => Ok(Json::Number(x)),
+            Err(_) => Err(self.error(&format!("invalid number `{}`", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> (usize, String) {
+        let e = parse(text).unwrap_err();
+        (e.line, e.msg)
+    }
+
+    #[test]
+    fn parses_a_document() {
+        let v = parse(
+            "{\n  \"a\": [1, -2.5e1, true, false, null],\n  \"b\": { \"c\": \"x\\n\\u0041\\\"\" }\n}",
+        )
+        .unwrap();
+        let a = v.get("a").unwrap();
+        assert_eq!(a.line, 2);
+        let items = a.as_array().unwrap();
+        assert_eq!(items[0].as_f64().unwrap(), 1.0);
+        assert_eq!(items[1].as_f64().unwrap(), -25.0);
+        assert!(items[2].as_bool().unwrap());
+        assert!(!items[3].as_bool().unwrap());
+        assert_eq!(items[4].kind(), "null");
+        let b = v.get("b").unwrap();
+        assert_eq!(b.line, 3);
+        assert_eq!(b.get("c").unwrap().as_str().unwrap(), "x\nA\"");
+        assert!(v.get("d").is_none());
+        assert_eq!(parse(" [ ] ").unwrap().as_array().unwrap().len(), 0);
+        assert_eq!(parse("{}").unwrap().as_object().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        assert_eq!(
+            error("{\n\"a\": 1,\n\"b\": tru\n}"),
+            (3, String::from("unexpected word `tru`"))
+        );
+        assert_eq!(
+            error("{\n\"a\": 1,\n\n\"a\": 2}"),
+            (4, String::from("duplicate field `a`"))
+        );
+        let v = parse("{\n\"a\":\n\"x\"}").unwrap();
+        let e = v.get("a").unwrap().as_f64().unwrap_err();
+        assert_eq!(
+            (e.line, e.msg),
+            (3, String::from("expected a number, found a string"))
+        );
+        assert_eq!(
+            error("[1]\n2"),
+            (
+                2,
+                String::from("trailing characters after the top-level value")
+            )
+        );
+    }
+
+    #[test]
+    fn bad_escapes_and_numbers() {
+        assert_eq!(
+            error("\"\\q\""),
+            (1, String::from("invalid escape in string"))
+        );
+        assert_eq!(
+            error("\"\\u12g4\""),
+            (1, String::from("invalid \\u escape"))
+        );
+        for n in ["1.2.3", "-", "1e", "--1", "1e+-2"].iter() {
+            assert_eq!(error(n), (1, format!("invalid number `{}`", n)));
+        }
+        assert_eq!(
+            error("[1 2]"),
+            (1, String::from("expected `,` or `]` in array"))
+        );
+        assert_eq!(
+            error("{1: 2}"),
+            (1, String::from("expected a quoted field name"))
+        );
+    }
+
+    #[test]
+    fn truncated_input() {
+        assert_eq!(error(""), (1, String::from("unexpected end of file")));
+        assert_eq!(error("[1,"), (1, String::from("unexpected end of file")));
+        assert_eq!(
+            error("[1"),
+            (1, String::from("expected `,` or `]` in array"))
+        );
+        assert_eq!(
+            error("{\"a\""),
+            (1, String::from("expected `:`, found end of file"))
+        );
+        assert_eq!(
+            error("{\"a\": 1"),
+            (1, String::from("expected `,` or `}` in object"))
+        );
+        assert_eq!(error("\"abc"), (1, String::from("unterminated string")));
+        assert_eq!(
+            error("\"ab\ncd\""),
+            (2, String::from("unterminated string"))
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let ok = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&ok).is_ok());
+        let deep = "[{\"a\":".repeat(100_000);
+        assert_eq!(
+            error(&deep),
+            (1, String::from("arrays and objects are nested too deeply"))
+        );
+    }
+}
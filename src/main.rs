@@ -5,7 +5,8 @@ mod camera;
 use camera::Camera;
 mod object;
 use object::{
-    Box, FlipFace, HitRecord, Object, RotateY, Sphere, Translate, XYRect, XZRect, YZRrect,
+    Box, ConstantMedium, FlipFace, HitRecord, Motion, MovingSphere, Object, RotateY, Sphere,
+    Tagged, Translate, XYRect, XZRect, YZRrect,
 };
 mod ray;
 use ray::Ray;
@@ -18,83 +19,54 @@ use vec3::Vec3;
 mod hittable_list;
 use hittable_list::HittableList;
 mod material;
-use material::{Dielectric, DiffuseLight, Lambertian, Material, Metal, NoMaterial};
+use material::{
+    Dielectric, DiffuseLight, HenyeyGreenstein, Isotropic, Lambertian, Material, Metal,
+};
 mod onb;
 use onb::Onb;
 mod pdf;
-use pdf::{CosPdf, HittablePdf, MixturePdf, Pdf};
-//use std::sync::Arc;
+use pdf::{CosPdf, HittablePdf, Pdf, ScatterPdf};
+mod adaptive;
+use adaptive::Adaptive;
+mod bdpt;
+mod checkpoint;
+use checkpoint::Checkpoint;
+mod denoise;
+mod environment;
+use environment::{Background, Environment};
+mod sky;
+use sky::Sky;
+mod diagnostics;
+use diagnostics::Faults;
+mod features;
+mod filter;
+use filter::{Filter, PixelFilter};
+mod integrator;
+mod photon;
+use integrator::Integrator;
+mod render;
+use render::{Pass, RenderSettings};
+mod sampler;
+use sampler::Sampling;
+mod scene;
+use scene::Scene;
+mod cli;
+mod spectrum;
+use cli::Options;
+mod json;
+mod output;
+mod tonemap;
+mod volume;
+use indicatif::ProgressBar;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::Arc;
+use std::time::Instant;
+use tonemap::{Operator, ToneMapper};
 //extern crate rand;
 //use rand::Rng;
 
-use image::{ImageBuffer, RgbImage};
-use indicatif::ProgressBar;
-
-const INFINITY: f64 = 1e15;
-
-fn _ray_color_(r: Ray, back_ground: Vec3, world: &impl Object, depth: i32) -> Vec3 {
-    let rec = world.hit(r, 0.001, INFINITY);
-    if depth <= 0 {
-        return Vec3::zero();
-    }
-    match rec {
-        Option::Some(rec) => {
-            let s = rec.mat.as_ref().unwrap().scatter_(r, &rec);
-            let emitted = rec.mat.unwrap().emitted(&rec, rec.u, rec.v, rec.p);
-            if s.jud {
-                return emitted
-                    + Vec3::elemul(
-                        _ray_color_(s.scattered, back_ground, world, depth - 1),
-                        s.attenustion,
-                    );
-            }
-            emitted
-        }
-        Option::None => back_ground,
-    }
-}
-
-fn ray_color<T: Object + Copy>(
-    r: Ray,
-    back_ground: Vec3,
-    lights: &T,
-    world: &impl Object,
-    depth: i32,
-) -> Vec3 {
-    let rec = world.hit(r, 0.001, INFINITY);
-    if depth <= 0 {
-        return Vec3::zero();
-    }
-    match rec {
-        Option::Some(rec) => {
-            let s = rec.mat.as_ref().unwrap().scatter(r, &rec);
-            let emitted = rec.mat.as_ref().unwrap().emitted(&rec, rec.u, rec.v, rec.p);
-            if s.jud {
-                if s.is_specular {
-                    return Vec3::elemul(
-                        s.attenustion,
-                        ray_color(s.scattered, back_ground, lights, world, depth - 1),
-                    );
-                }
-                let light_ptr = HittablePdf::new(rec.p, *lights);
-                let km = s.pdf_ptr.unwrap();
-                let p = MixturePdf::new(&light_ptr, &*km);
-
-                let scattered = Ray::new(rec.p, p.generate());
-                let pdf_val = p.value(scattered.dir);
-
-                return emitted
-                    + Vec3::elemul(
-                        s.attenustion
-                            * rec.mat.as_ref().unwrap().scattering_pdf(r, &rec, scattered),
-                        ray_color(scattered, back_ground, lights, world, depth - 1) / pdf_val,
-                    );
-            }
-            emitted
-        }
-        Option::None => back_ground,
-    }
-}
+pub const INFINITY: f64 = 1e15;
 
 fn clamp(x: f64, min: f64, max: f64) -> f64 {
     if x < min {
@@ -106,135 +78,343 @@ fn clamp(x: f64, min: f64, max: f64) -> f64 {
     x
 }
 
-fn main() {
-    let samples_per_pixel = 1000;
-    let max_depth = 50;
-
-    let choose = 2;
-    let mut world = HittableList::new();
-    let _spw: BvhNode;
-    let lights = XZRect::new(213.0, 343.0, 227.0, 332.0, 554.0, NoMaterial);
-    let _glass_sphere = Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, NoMaterial);
-    let aspect_ratio: f64;
-    let image_width: u32;
-    let image_height: u32;
-    let lookfrom: Vec3;
-    let lookat: Vec3;
-    let vup: Vec3;
-    let dist_to_focus: f64;
-    let aperture: f64;
-    let background: Vec3;
-    let cam: Camera;
-    match choose {
-        1 => {
-            //night
-            _spw = HittableList::night();
-            aspect_ratio = 3.0 / 2.0;
-            image_width = 1600;
-            image_height = ((image_width as f64) / aspect_ratio) as u32;
-
-            lookfrom = Vec3::new(13.0, 2.0, 3.0);
-            lookat = Vec3::new(0.0, 0.0, 0.0);
-            vup = Vec3::new(0.0, 1.0, 0.0);
-            dist_to_focus = 10.0;
-            aperture = 0.1;
-            background = Vec3::new(0.0, 0.0, 0.0);
+fn fail(e: &str) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(1);
+}
 
-            cam = Camera::new(
-                lookfrom,
-                lookat,
-                vup,
-                20.0,
-                aspect_ratio,
-                aperture,
-                dist_to_focus,
-            );
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut opts = match Options::parse(args.iter().cloned()) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
         }
-        2 => {
-            world = HittableList::cornell_box();
-            //_spw = BvhNode::new(HittableList::new(), 0.001, INFINITY);
-            aspect_ratio = 1.0;
-            image_width = 200;
-            image_height = ((image_width as f64) / aspect_ratio) as u32;
-
-            lookfrom = Vec3::new(278.0, 278.0, -800.0);
-            lookat = Vec3::new(278.0, 278.0, 0.0);
-            vup = Vec3::new(0.0, 1.0, 0.0);
-            dist_to_focus = 10.0;
-            aperture = 0.0;
-            background = Vec3::new(0.0, 0.0, 0.0);
+    };
+    if opts.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
 
-            cam = Camera::new(
-                lookfrom,
-                lookat,
-                vup,
-                40.0,
-                aspect_ratio,
-                aperture,
-                dist_to_focus,
+    // a resumed render takes the image shaping options from the checkpoint and only the
+    // resumable ones from the command line
+    let mut resumed = None;
+    if let Some(path) = opts.resume.clone() {
+        let ck = Checkpoint::load(&path).unwrap_or_else(|e| fail(&e));
+        let (fixed, _) = cli::split_resumable(&args);
+        if !fixed.is_empty() {
+            eprintln!(
+                "error: {} cannot be changed when resuming, only: {}",
+                fixed[0],
+                cli::RESUMABLE.join(", ")
             );
+            std::process::exit(2);
         }
-        _ => {
-            //day
-            _spw = HittableList::random_scene();
-            aspect_ratio = 3.0 / 2.0;
-            image_width = 200;
-            image_height = ((image_width as f64) / aspect_ratio) as u32;
+        opts = match Options::parse(ck.args.iter().chain(&args).cloned()) {
+            Ok(opts) => opts,
+            Err(e) => fail(&format!("{}: {}", path, e)),
+        };
+        if opts.checkpoint.is_none() {
+            opts.checkpoint = Some(path);
+        }
+        resumed = Some(ck);
+    }
 
-            lookfrom = Vec3::new(13.0, 2.0, 3.0);
-            lookat = Vec3::new(0.0, 0.0, 0.0);
-            vup = Vec3::new(0.0, 1.0, 0.0);
-            dist_to_focus = 10.0;
-            aperture = 0.1;
-            background = Vec3::new(0.7, 0.8, 1.0);
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+    let mut scene = match &opts.scene_file {
+        Some(path) => Scene::load(path, &mut rng).unwrap_or_else(|e| fail(&e)),
+        None => Scene::builtin(&opts.scene, &mut rng).unwrap(),
+    };
+    opts.apply(&mut scene);
+    if let Some(path) = &opts.environment {
+        let env = Environment::load(
+            std::path::Path::new(path),
+            opts.env_intensity.unwrap_or(1.0),
+            opts.env_rotation.unwrap_or(0.0),
+        )
+        .unwrap_or_else(|e| fail(&e));
+        scene.background = Background::Map(Arc::new(env));
+    }
+    if let Some((elevation, azimuth)) = opts.sky {
+        let sky = Sky::new(
+            elevation,
+            azimuth,
+            opts.turbidity.unwrap_or(3.0),
+            opts.ground_albedo.unwrap_or(0.3),
+            opts.env_intensity.unwrap_or(1.0),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("error: --sky: {}", e);
+            std::process::exit(2);
+        });
+        scene.background = Background::Sky(Arc::new(sky));
+    }
+    let image_width = scene.image_width;
+    let image_height = scene.image_height();
+    if image_width < 2 || image_height < 2 {
+        eprintln!(
+            "error: image size {}x{} is too small",
+            image_width, image_height
+        );
+        std::process::exit(2);
+    }
+    let samples_per_pixel = opts.samples_per_pixel;
+    let photon_mapping = match opts.integrator {
+        Integrator::Photon | Integrator::Sppm => true,
+        Integrator::Path | Integrator::Bdpt => false,
+    };
+    if photon_mapping && scene.lights.is_none() {
+        eprintln!("error: photon mapping needs a scene with lights to emit photons from");
+        std::process::exit(2);
+    }
+    // sppm pixels carry a shrinking radius rather than a plain sum of samples
+    if opts.integrator == Integrator::Sppm && (opts.checkpoint.is_some() || opts.adaptive.is_some())
+    {
+        eprintln!("error: sppm renders cannot be checkpointed or sampled adaptively");
+        std::process::exit(2);
+    }
+    // sppm averages whole passes per pixel, there are no camera samples to spread
+    if opts.integrator == Integrator::Sppm
+        && (opts.filter != Filter::Box || opts.filter_radius.is_some())
+    {
+        eprintln!("error: sppm renders are box filtered, --filter does not apply");
+        std::process::exit(2);
+    }
+    // media points have no surface, the other integrators would treat them as one
+    if scene.media && opts.integrator != Integrator::Path {
+        eprintln!("error: participating media only work with the path integrator");
+        std::process::exit(2);
+    }
+    if opts.spectral && opts.integrator != Integrator::Path {
+        eprintln!("error: --spectral only works with the path integrator");
+        std::process::exit(2);
+    }
+    // only the path tracer samples the map as a light, the others would find its bright texels
+    // by chance alone
+    if let Background::Map(_) = scene.background {
+        if opts.integrator != Integrator::Path {
+            eprintln!("error: environment maps only work with the path integrator");
+            std::process::exit(2);
+        }
+    }
+    // the same goes for the sun, a tiny disk far brighter than anything else
+    if let Background::Sky(_) = scene.background {
+        if opts.integrator != Integrator::Path {
+            eprintln!("error: --sky only works with the path integrator");
+            std::process::exit(2);
+        }
+    }
+    // the path tracer draws directions from an environment map or the sky along with the lights
+    if opts.integrator == Integrator::Path && scene.background.is_light() {
+        let mut lights = HittableList::new();
+        if let Some(l) = scene.lights.take() {
+            lights.add(l);
+        }
+        lights.add(Arc::new(scene.background.clone()));
+        scene.lights = Some(Arc::new(lights));
+    }
+    let photon_radius = match (
+        opts.photon_radius,
+        scene.world.bounding_box(scene.time0, scene.time1),
+    ) {
+        (Some(r), _) => r,
+        (None, Some(b)) => (b.max - b.min).length() / 100.0,
+        (None, None) => 1.0,
+    };
 
-            cam = Camera::new(
-                lookfrom,
-                lookat,
-                vup,
-                20.0,
-                aspect_ratio,
-                aperture,
-                dist_to_focus,
+    let mut settings = RenderSettings {
+        image_width,
+        image_height,
+        samples_per_pixel,
+        max_depth: opts.max_depth,
+        rr_depth: opts.rr_depth,
+        mis: opts.mis,
+        integrator: opts.integrator,
+        photons: opts.photons,
+        photon_radius,
+        threads: opts.threads,
+        tile_size: opts.tile_size,
+        filter: PixelFilter::new(opts.filter, opts.filter_radius),
+        spectral: opts.spectral,
+    };
+    let mut acc = match resumed {
+        Some(ck) => {
+            if ck.width != image_width || ck.height != image_height {
+                fail(&format!(
+                    "the checkpoint is {}x{} but the scene renders at {}x{}",
+                    ck.width, ck.height, image_width, image_height
+                ));
+            }
+            ck
+        }
+        None => Checkpoint::new(cli::split_resumable(&args).0, image_width, image_height),
+    };
+    // the spp target goes with the checkpoint, a resume without --spp renders on towards it
+    acc.args = cli::split_resumable(&acc.args).0;
+    acc.args.push(String::from("--spp"));
+    acc.args.push(samples_per_pixel.to_string());
+    let sampling = Sampling::new(opts.sampler, opts.seed, samples_per_pixel);
+    let features = if opts.denoise || !opts.aovs.is_empty() {
+        Some(features::render(
+            &settings,
+            &sampling,
+            scene.cam(),
+            scene.background.clone(),
+            scene.world.clone(),
+            features::SAMPLES,
+        ))
+    } else {
+        None
+    };
+    let tm = ToneMapper::new(opts.tonemap, opts.exposure, opts.white);
+    let save = |acc: &Checkpoint, faults: &[Faults]| {
+        if let Some(path) = &opts.checkpoint {
+            if let Err(e) = acc.save(path) {
+                fail(&format!("cannot write {}: {}", path, e));
+            }
+        }
+        let mut img = acc.image();
+        if let Some(f) = &features {
+            let variance: Vec<f64> = (0..img.len()).map(|k| acc.variance(k)).collect();
+            img = denoise::denoise(
+                &img,
+                &variance,
+                f,
+                image_width as usize,
+                image_height as usize,
             );
         }
-    }
-    let mut img: RgbImage = ImageBuffer::new(image_width, image_height);
-    let bar = ProgressBar::new(image_height as u64);
-    for j in 0..image_height {
-        for i in 0..image_width {
-            let mut col = Vec3::new(0.0, 0.0, 0.0);
-            for _s in 0..samples_per_pixel {
-                let u = (i as f64 + rand::random::<f64>()) / (image_width as f64 - 1.0);
-                let v = (image_height as f64 - j as f64 + rand::random::<f64>())
-                    / (image_height as f64 - 1.0);
-                let r = cam.get_ray(u, v);
-                if choose == 2 {
-                    col += ray_color(r, background, &lights, &world, max_depth);
-                } else {
-                    //col += _ray_color_(r, background, &_spw, max_depth);
-                }
-                //col += ray_color(r, background, &lights, &world, max_depth);
+        let ret = match &features {
+            Some(f) if !opts.aovs.is_empty() && output::is_exr(&opts.output) => {
+                let layers: Vec<_> = opts
+                    .aovs
+                    .iter()
+                    .map(|&aov| (aov.name(), aov.channels(), f.pass(aov)))
+                    .collect();
+                output::save_exr_layers(&opts.output, &img, image_width, image_height, &layers)
             }
-            let pixel = img.get_pixel_mut(i, j);
-            if col.x.is_nan() {
-                col.x = 0.0;
+            Some(f) => {
+                output::save(&opts.output, &img, image_width, image_height, &tm).and_then(|_| {
+                    opts.aovs.iter().try_for_each(|&aov| {
+                        let path = output::pass_path(&opts.output, aov.name());
+                        output::save_pass(
+                            &path,
+                            &f.pass(aov),
+                            &f.display(aov),
+                            image_width,
+                            image_height,
+                        )
+                    })
+                })
             }
-            if col.y.is_nan() {
-                col.y = 0.0;
+            None => output::save(&opts.output, &img, image_width, image_height, &tm),
+        };
+        if let Err(e) = ret {
+            fail(&e);
+        }
+        if let Some(path) = &opts.bad_pixels {
+            let img = diagnostics::marked(&img, faults);
+            let tm = ToneMapper::new(Operator::Linear, 0.0, None);
+            if let Err(e) = output::save(path, &img, image_width, image_height, &tm) {
+                fail(&e);
             }
-            if col.z.is_nan() {
-                col.z = 0.0;
+        }
+        if let Some(path) = &opts.heatmap {
+            let img = adaptive::heatmap(&acc.count);
+            let tm = ToneMapper::new(Operator::Linear, 0.0, None);
+            if let Err(e) = output::save(path, &img, image_width, image_height, &tm) {
+                fail(&e);
             }
-            *pixel = image::Rgb([
-                (clamp((col.x / (samples_per_pixel as f64)).sqrt(), 0.0, 0.999) * 255.999) as u8,
-                (clamp((col.y / (samples_per_pixel as f64)).sqrt(), 0.0, 255.999) * 255.999) as u8,
-                (clamp((col.z / (samples_per_pixel as f64)).sqrt(), 0.0, 255.999) * 255.999) as u8,
-            ]);
         }
-        bar.inc(1);
-    }
+    };
+
+    // with --adaptive, --spp is the average over the image and pixels draw from the total
+    let adaptive = opts.adaptive.map(|threshold| Adaptive {
+        threshold,
+        min_spp: opts.min_spp,
+        max_spp: opts
+            .max_spp
+            .unwrap_or_else(|| samples_per_pixel.saturating_mul(4)),
+    });
+    let budget = samples_per_pixel as u64 * acc.count.len() as u64;
+    // without a checkpoint or adaptive sampling everything is a single pass
+    let pass_spp = if opts.checkpoint.is_some() || adaptive.is_some() {
+        opts.pass_spp
+    } else {
+        samples_per_pixel
+    };
+    let next_pass = |acc: &Checkpoint| match &adaptive {
+        Some(adaptive) => adaptive.plan(acc, pass_spp, budget),
+        None => {
+            let counts: Vec<u32> = acc
+                .count
+                .iter()
+                .map(|&c| samples_per_pixel.saturating_sub(c).min(pass_spp))
+                .collect();
+            if counts.iter().any(|&c| c > 0) {
+                Some(counts)
+            } else {
+                None
+            }
+        }
+    };
 
-    img.save("output/test.png").unwrap();
+    let todo = match adaptive {
+        Some(_) => budget.saturating_sub(acc.total()),
+        None => acc
+            .count
+            .iter()
+            .map(|&c| samples_per_pixel.saturating_sub(c) as u64)
+            .sum(),
+    };
+    let bar = ProgressBar::new(todo);
+    let mut last_save = Instant::now();
+    if opts.trace_bad {
+        diagnostics::enable_trace();
+    }
+    // only counts the samples of this run, a resumed render starts over
+    let mut faults = vec![Faults::default(); acc.count.len()];
+    while let Some(counts) = next_pass(&acc) {
+        settings.samples_per_pixel = counts.iter().copied().max().unwrap_or(0);
+        let pass = Arc::new(Pass {
+            counts,
+            first: acc.count.clone(),
+            sampling: sampling.clone(),
+            round: acc.passes,
+        });
+        let samples = render::render(
+            &settings,
+            scene.cam(),
+            scene.background.clone(),
+            scene.world.clone(),
+            scene.lights.clone(),
+            pass.clone(),
+            &bar,
+        );
+        acc.add(&samples, &pass.counts);
+        for (f, s) in faults.iter_mut().zip(&samples.faults) {
+            f.merge(*s);
+        }
+        if opts.checkpoint.is_some() && last_save.elapsed().as_secs_f64() >= opts.checkpoint_every {
+            save(&acc, &faults);
+            last_save = Instant::now();
+        }
+    }
     bar.finish();
+    save(&acc, &faults);
+
+    let mut total = Faults::default();
+    for f in &faults {
+        total.merge(*f);
+    }
+    if total.total() > 0 {
+        eprintln!(
+            "warning: left out {} NaN, {} infinite and {} negative samples in {} pixels",
+            total.nan,
+            total.inf,
+            total.negative,
+            faults.iter().filter(|f| f.total() > 0).count()
+        );
+    }
 }
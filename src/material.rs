@@ -1,26 +1,71 @@
+use crate::pdf::{henyey_greenstein, PhasePdf};
+use crate::sampler::Sampler;
 use crate::CosPdf;
 use crate::HitRecord;
-use crate::Pdf;
 use crate::Ray;
+use crate::ScatterPdf;
 use crate::Texture;
 use crate::Vec3;
-extern crate rand;
+use std::sync::Arc;
 
-pub trait Material {
-    fn scatter(&self, r_in: Ray, rec: &HitRecord) -> ScaRet;
-    fn scatter_(&self, r_in: Ray, rec: &HitRecord) -> ScaRet_;
+pub trait Material: Send + Sync {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, s: &mut dyn Sampler) -> ScaRet;
+    // scatter at one wavelength in nm, for spectral renders
+    fn scatter_at(&self, r_in: Ray, rec: &HitRecord, _lambda: f64, s: &mut dyn Sampler) -> ScaRet {
+        self.scatter(r_in, rec, s)
+    }
+    // whether scatter_at sends different wavelengths different ways
+    fn dispersive(&self) -> bool {
+        false
+    }
     fn emitted(&self, _rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
         Vec3::zero()
     }
     fn scattering_pdf(&self, _r_in: Ray, _rec: &HitRecord, _scattered: Ray) -> f64 {
         0.0
     }
+    // surface color for the feature buffers, black for materials that only emit
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+    // the type name in scene files, for diagnostics
+    fn name(&self) -> &'static str;
+}
+
+impl Material for Arc<dyn Material> {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, s: &mut dyn Sampler) -> ScaRet {
+        (**self).scatter(r_in, rec, s)
+    }
+
+    fn scatter_at(&self, r_in: Ray, rec: &HitRecord, lambda: f64, s: &mut dyn Sampler) -> ScaRet {
+        (**self).scatter_at(r_in, rec, lambda, s)
+    }
+
+    fn dispersive(&self) -> bool {
+        (**self).dispersive()
+    }
+
+    fn emitted(&self, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        (**self).emitted(rec, u, v, p)
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
+        (**self).scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        (**self).albedo(rec)
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
 }
 
 pub struct ScaRet {
     pub scattered: Ray,
     pub attenustion: Vec3,
-    pub pdf_ptr: Option<Box<dyn Pdf>>,
+    pub pdf: Option<ScatterPdf>,
     pub is_specular: bool,
     pub jud: bool,
 }
@@ -30,7 +75,7 @@ impl ScaRet {
         Self {
             scattered: r,
             attenustion: v,
-            pdf_ptr: Option::None,
+            pdf: Option::None,
             is_specular: ip,
             jud: j,
         }
@@ -40,46 +85,13 @@ impl ScaRet {
         Self {
             scattered: r,
             attenustion: v,
-            pdf_ptr: Option::None,
+            pdf: Option::None,
             is_specular: ip,
             jud: j,
         }
     }
 }
 
-pub struct ScaRet_ {
-    pub scattered: Ray,
-    pub attenustion: Vec3,
-    pub jud: bool,
-}
-
-impl ScaRet_ {
-    pub fn new(r: Ray, v: Vec3, j: bool) -> Self {
-        Self {
-            scattered: r,
-            attenustion: v,
-            jud: j,
-        }
-    }
-}
-
-#[derive(Clone, Debug, Copy)]
-pub struct NoMaterial;
-
-impl Material for NoMaterial {
-    fn scatter(&self, _r_in: Ray, _rec: &HitRecord) -> ScaRet {
-        panic!("no material");
-    }
-
-    fn emitted(&self, _rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
-        panic!("no material");
-    }
-
-    fn scatter_(&self, _r_in: Ray, _rec: &HitRecord) -> ScaRet_ {
-        panic!("no material!");
-    }
-}
-
 #[derive(Clone, Debug, Copy)]
 pub struct Lambertian<T: Texture> {
     pub albedo: T,
@@ -92,11 +104,11 @@ impl<T: Texture> Lambertian<T> {
 }
 
 impl<T: Texture> Material for Lambertian<T> {
-    fn scatter(&self, _r_in: Ray, rec: &HitRecord) -> ScaRet {
+    fn scatter(&self, _r_in: Ray, rec: &HitRecord, _s: &mut dyn Sampler) -> ScaRet {
         ScaRet {
-            scattered: Ray::new(Vec3::zero(), Vec3::zero()),
+            scattered: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
             attenustion: self.albedo.value(rec.u, rec.v, rec.p),
-            pdf_ptr: Option::Some(Box::new(CosPdf::new(rec.normal))),
+            pdf: Option::Some(ScatterPdf::Cos(CosPdf::new(rec.normal))),
             is_specular: false,
             jud: true,
         }
@@ -111,13 +123,12 @@ impl<T: Texture> Material for Lambertian<T> {
         }
     }
 
-    fn scatter_(&self, _r_in: Ray, rec: &HitRecord) -> ScaRet_ {
-        let sca_dir = rec.normal + Vec3::random_unit_vec();
-        ScaRet_::new(
-            Ray::new(rec.p, sca_dir),
-            self.albedo.value(rec.u, rec.v, rec.p),
-            true,
-        )
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.p)
+    }
+
+    fn name(&self) -> &'static str {
+        "lambertian"
     }
 }
 
@@ -143,33 +154,77 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: Ray, rec: &HitRecord) -> ScaRet {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, s: &mut dyn Sampler) -> ScaRet {
         let reflected = Vec3::reflect(r_in.dir.unit(), rec.normal);
+        let scattered = Ray::new(
+            rec.p,
+            reflected + Vec3::random_in_unit_sphere(s) * self.fuzz,
+            r_in.time,
+        );
+        // fuzz can push the reflection into the surface, which absorbs it
         ScaRet {
-            scattered: Ray::new(rec.p, reflected + Vec3::random_in_unit_sphere() * self.fuzz),
+            scattered,
             attenustion: self.albedo,
-            pdf_ptr: Option::None,
+            pdf: Option::None,
             is_specular: true,
-            jud: true,
+            jud: scattered.dir * rec.normal > 0.0,
         }
     }
 
-    fn scatter_(&self, r_in: Ray, rec: &HitRecord) -> ScaRet_ {
-        let reflected = Vec3::reflect(r_in.dir.unit(), rec.normal);
-        let sca = Ray::new(rec.p, reflected + Vec3::random_in_unit_sphere() * self.fuzz);
-        let at = self.albedo;
-        ScaRet_::new(sca, at, (sca.dir * rec.normal) > 0.0)
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
     }
+
+    fn name(&self) -> &'static str {
+        "metal"
+    }
+}
+
+// how the index of refraction of a dielectric varies with the wavelength, taken in micrometres
+#[derive(Clone, Debug)]
+pub enum Dispersion {
+    None,
+    // n = a + b / l^2
+    Cauchy(f64, f64),
+    // n^2 = 1 + sum of b[i] l^2 / (l^2 - c[i])
+    Sellmeier([f64; 3], [f64; 3]),
 }
 
 #[derive(Clone, Debug)]
 pub struct Dielectric {
     pub ref_idx: f64,
+    pub dispersion: Dispersion,
 }
 
 impl Dielectric {
     pub fn new(x: f64) -> Self {
-        Self { ref_idx: x }
+        Self {
+            ref_idx: x,
+            dispersion: Dispersion::None,
+        }
+    }
+
+    // ref_idx is the index at the sodium d line, 587.6 nm, which renders in rgb use
+    pub fn with_dispersion(dispersion: Dispersion) -> Self {
+        let mut ret = Self {
+            ref_idx: 1.0,
+            dispersion,
+        };
+        ret.ref_idx = ret.ior(587.6);
+        ret
+    }
+
+    // index of refraction at lambda in nm
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match &self.dispersion {
+            Dispersion::None => self.ref_idx,
+            Dispersion::Cauchy(a, b) => a + b / l2,
+            Dispersion::Sellmeier(b, c) => {
+                let sum: f64 = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
     }
 
     pub fn schlick(cos: f64, ri: f64) -> f64 {
@@ -180,12 +235,17 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: Ray, rec: &HitRecord) -> ScaRet {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, s: &mut dyn Sampler) -> ScaRet {
+        self.scatter_at(r_in, rec, 587.6, s)
+    }
+
+    fn scatter_at(&self, r_in: Ray, rec: &HitRecord, lambda: f64, s: &mut dyn Sampler) -> ScaRet {
+        let ref_idx = self.ior(lambda);
         let eta: f64 = {
             if rec.front_face {
-                1.0 / self.ref_idx
+                1.0 / ref_idx
             } else {
-                self.ref_idx
+                ref_idx
             }
         };
         let cos_theta = {
@@ -198,44 +258,45 @@ impl Material for Dielectric {
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         if eta * sin_theta > 1.0 {
             let refl = Vec3::reflect(r_in.dir.unit(), rec.normal);
-            return ScaRet::new(Ray::new(rec.p, refl), Vec3::new(1.0, 1.0, 1.0), true, true);
+            return ScaRet::new(
+                Ray::new(rec.p, refl, r_in.time),
+                Vec3::new(1.0, 1.0, 1.0),
+                true,
+                true,
+            );
         }
         let rp = Dielectric::schlick(cos_theta, eta);
-        if rand::random::<f64>() < rp {
+        if s.get_1d() < rp {
             let refl = Vec3::reflect(r_in.dir.unit(), rec.normal);
-            return ScaRet::new(Ray::new(rec.p, refl), Vec3::new(1.0, 1.0, 1.0), true, true);
+            return ScaRet::new(
+                Ray::new(rec.p, refl, r_in.time),
+                Vec3::new(1.0, 1.0, 1.0),
+                true,
+                true,
+            );
         }
         let refr = Vec3::refract(r_in.dir.unit(), rec.normal, eta);
-        ScaRet::new(Ray::new(rec.p, refr), Vec3::new(1.0, 1.0, 1.0), true, true)
+        ScaRet::new(
+            Ray::new(rec.p, refr, r_in.time),
+            Vec3::new(1.0, 1.0, 1.0),
+            true,
+            true,
+        )
     }
 
-    fn scatter_(&self, r_in: Ray, rec: &HitRecord) -> ScaRet_ {
-        let eta: f64 = {
-            if rec.front_face {
-                1.0 / self.ref_idx
-            } else {
-                self.ref_idx
-            }
-        };
-        let cos_theta = {
-            if -r_in.dir.unit() * rec.normal > 1.0 {
-                1.0
-            } else {
-                -r_in.dir.unit() * rec.normal
-            }
-        };
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-        if eta * sin_theta > 1.0 {
-            let refl = Vec3::reflect(r_in.dir.unit(), rec.normal);
-            return ScaRet_::new(Ray::new(rec.p, refl), Vec3::new(1.0, 1.0, 1.0), true);
+    fn dispersive(&self) -> bool {
+        match self.dispersion {
+            Dispersion::None => false,
+            Dispersion::Cauchy(..) | Dispersion::Sellmeier(..) => true,
         }
-        let rp = Dielectric::schlick(cos_theta, eta);
-        if rand::random::<f64>() < rp {
-            let refl = Vec3::reflect(r_in.dir.unit(), rec.normal);
-            return ScaRet_::new(Ray::new(rec.p, refl), Vec3::new(1.0, 1.0, 1.0), true);
-        }
-        let refr = Vec3::refract(r_in.dir.unit(), rec.normal, eta);
-        ScaRet_::new(Ray::new(rec.p, refr), Vec3::new(1.0, 1.0, 1.0), true)
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+
+    fn name(&self) -> &'static str {
+        "dielectric"
     }
 }
 
@@ -251,11 +312,11 @@ impl<T: Texture> DiffuseLight<T> {
 }
 
 impl<T: Texture> Material for DiffuseLight<T> {
-    fn scatter(&self, _r_in: Ray, _rec: &HitRecord) -> ScaRet {
+    fn scatter(&self, _r_in: Ray, _rec: &HitRecord, _s: &mut dyn Sampler) -> ScaRet {
         ScaRet {
-            scattered: Ray::new(Vec3::zero(), Vec3::zero()),
+            scattered: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
             attenustion: Vec3::zero(),
-            pdf_ptr: Option::None,
+            pdf: Option::None,
             is_specular: false,
             jud: false,
         }
@@ -265,11 +326,144 @@ impl<T: Texture> Material for DiffuseLight<T> {
         self.emit.value(u, v, p)
     }
 
-    fn scatter_(&self, _r_in: Ray, _rec: &HitRecord) -> ScaRet_ {
-        ScaRet_ {
-            scattered: Ray::new(Vec3::zero(), Vec3::zero()),
-            attenustion: Vec3::zero(),
-            jud: false,
+    fn name(&self) -> &'static str {
+        "diffuse_light"
+    }
+}
+
+// the phase function of a participating medium that scatters equally in every direction,
+// albedo is the fraction of the light it scatters rather than absorbs
+#[derive(Clone, Debug)]
+pub struct Isotropic<T: Texture> {
+    pub albedo: T,
+}
+
+impl<T: Texture> Isotropic<T> {
+    pub fn new(albedo: T) -> Self {
+        Self { albedo }
+    }
+}
+
+impl<T: Texture> Material for Isotropic<T> {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, _s: &mut dyn Sampler) -> ScaRet {
+        phase_scatter(r_in, self.albedo.value(rec.u, rec.v, rec.p), 0.0)
+    }
+
+    fn scattering_pdf(&self, _r_in: Ray, _rec: &HitRecord, _scattered: Ray) -> f64 {
+        henyey_greenstein(0.0, 0.0)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.p)
+    }
+
+    fn name(&self) -> &'static str {
+        "isotropic"
+    }
+}
+
+// a medium that scatters mostly forward for g > 0 or backward for g < 0, with g the mean
+// cosine of the scattering angle
+#[derive(Clone, Debug)]
+pub struct HenyeyGreenstein<T: Texture> {
+    pub albedo: T,
+    pub g: f64,
+}
+
+impl<T: Texture> HenyeyGreenstein<T> {
+    pub fn new(albedo: T, g: f64) -> Self {
+        Self { albedo, g }
+    }
+}
+
+impl<T: Texture> Material for HenyeyGreenstein<T> {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, _s: &mut dyn Sampler) -> ScaRet {
+        phase_scatter(r_in, self.albedo.value(rec.u, rec.v, rec.p), self.g)
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, _rec: &HitRecord, scattered: Ray) -> f64 {
+        henyey_greenstein(r_in.dir.unit() * scattered.dir.unit(), self.g)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.p)
+    }
+
+    fn name(&self) -> &'static str {
+        "henyey_greenstein"
+    }
+}
+
+fn phase_scatter(r_in: Ray, albedo: Vec3, g: f64) -> ScaRet {
+    ScaRet {
+        scattered: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+        attenustion: albedo,
+        pdf: Option::Some(ScatterPdf::Phase(PhasePdf::new(r_in.dir, g))),
+        is_specular: false,
+        jud: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerKind, Sampling};
+    use crate::{Object, XZRect};
+
+    #[test]
+    fn dispersion_matches_bk7() {
+        // Schott N-BK7 has n = 1.5168 at the sodium d line, in both fits
+        let sellmeier = Dielectric::with_dispersion(Dispersion::Sellmeier(
+            [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        ));
+        let cauchy = Dielectric::with_dispersion(Dispersion::Cauchy(1.5046, 0.004_20));
+        for glass in &[sellmeier, cauchy] {
+            assert!(
+                (glass.ior(587.6) - 1.5168).abs() < 1e-3,
+                "{}",
+                glass.ior(587.6)
+            );
+            assert!((glass.ref_idx - glass.ior(587.6)).abs() < 1e-12);
+            // blue bends more than red
+            assert!(glass.ior(450.0) > glass.ref_idx && glass.ref_idx > glass.ior(650.0));
+        }
+        let plain = Dielectric::new(1.5);
+        assert!((plain.ior(450.0) - 1.5).abs() < 1e-12 && (plain.ior(650.0) - 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rough_metal_absorbs_reflections_into_the_surface() {
+        // a ray skimming a floor of fully fuzzed metal
+        let floor = XZRect::new(
+            -10.0,
+            10.0,
+            -10.0,
+            10.0,
+            0.0,
+            Metal::new(Vec3::new(0.8, 0.8, 0.8), 1.0),
+        );
+        let r_in = Ray::new(Vec3::new(-10.0, 0.1, 0.0), Vec3::new(100.0, -1.0, 0.0), 0.0);
+        let rec = floor.hit(r_in, 0.001, crate::INFINITY).unwrap();
+        let mut sampler = Sampling::new(SamplerKind::Independent, 5, 1).sampler();
+        let (mut kept, mut absorbed) = (0, 0);
+        for index in 0..200 {
+            sampler.start((0, 0), index);
+            let s = rec.mat.unwrap().scatter(r_in, &rec, sampler.as_mut());
+            let above = s.scattered.dir * rec.normal > 0.0;
+            assert_eq!(s.jud, above);
+            if above {
+                kept += 1;
+            } else {
+                absorbed += 1;
+            }
         }
+        // at grazing incidence about half of the fuzzed reflections point below the floor
+        assert!(
+            kept > 50 && absorbed > 50,
+            "{} kept, {} absorbed",
+            kept,
+            absorbed
+        );
     }
 }
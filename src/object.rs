@@ -1,3 +1,4 @@
+use crate::sampler::{self, Sampler};
 use crate::HittableList;
 use crate::Material;
 use crate::Onb;
@@ -5,23 +6,74 @@ use crate::Ray;
 use crate::Vec3;
 use crate::AABB;
 use std::sync::Arc;
-extern crate rand;
-use rand::Rng;
 
 const INFINITY: f64 = 1e15;
 
-pub trait Object {
+pub trait Object: Send + Sync {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
-    fn bounding_box(&self) -> Option<AABB>;
+    // encloses the object at every time from t0 to t1
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB>;
 
     fn pdf_value(&self, _o: Vec3, _d: Vec3) -> f64 {
         panic!("unimplemented!")
     }
 
-    fn random(&self, _v: Vec3) -> Vec3 {
+    fn random(&self, _v: Vec3, _s: &mut dyn Sampler) -> Vec3 {
         panic!("unimplemented!")
     }
+
+    // picks a point on the surface, independent of any receiver, to start a light path from;
+    // returns it with its outward normal and the pdf per unit area
+    fn sample_surface(&self, _s: &mut dyn Sampler) -> (HitRecord, f64) {
+        panic!("unimplemented!")
+    }
+
+    // pdf per unit area with which sample_surface picks the point where o + t*d first hits
+    fn surface_pdf(&self, _o: Vec3, _d: Vec3) -> f64 {
+        panic!("unimplemented!")
+    }
+
+    // fraction of the light that gets along r from t_min to t_max: zero when a surface is in
+    // the way, otherwise an unbiased estimate of what the media on the way let through
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        if self.hit(r, t_min, t_max).is_some() {
+            0.0
+        } else {
+            1.0
+        }
+    }
 }
+impl Object for Arc<dyn Object> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        (**self).hit(r, t_min, t_max)
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        (**self).bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
+        (**self).pdf_value(o, d)
+    }
+
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        (**self).random(v, s)
+    }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        (**self).sample_surface(s)
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        (**self).surface_pdf(o, d)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        (**self).transmittance(r, t_min, t_max)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct HitRecord<'a> {
     pub p: Vec3,
     pub normal: Vec3,
@@ -30,6 +82,12 @@ pub struct HitRecord<'a> {
     pub mat: Option<&'a dyn Material>,
     pub u: f64,
     pub v: f64,
+    // position of the hit top-level object in its scene, counted from 1; 0 when untagged
+    pub id: u32,
+    // radiance a volume emits at the collision divided by its density there, so that
+    // collisions, which happen in proportion to density, add up to the emission along the ray;
+    // zero on surfaces
+    pub emission: Vec3,
 }
 
 impl<'a> HitRecord<'a> {
@@ -90,6 +148,8 @@ impl<T: Material> Object for Sphere<T> {
                 }
                 let uv_ = HitRecord::get_sphere_uv((r.at(temp) - self.center) / self.radius);
                 return Option::Some(HitRecord {
+                    id: 0,
+                    emission: Vec3::zero(),
                     p: r.at(temp),
                     normal: tmpp,
                     t: temp,
@@ -110,6 +170,8 @@ impl<T: Material> Object for Sphere<T> {
                 }
                 let uv_ = HitRecord::get_sphere_uv((r.at(temp) - self.center) / self.radius);
                 return Option::Some(HitRecord {
+                    id: 0,
+                    emission: Vec3::zero(),
                     p: r.at(temp),
                     normal: tmpp,
                     t: temp,
@@ -123,7 +185,7 @@ impl<T: Material> Object for Sphere<T> {
         Option::None
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(AABB::new(
             self.center - Vec3::new(self.radius, self.radius, self.radius),
             self.center + Vec3::new(self.radius, self.radius, self.radius),
@@ -131,7 +193,7 @@ impl<T: Material> Object for Sphere<T> {
     }
 
     fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
-        let rec = self.hit(Ray::new(o, d), 0.001, INFINITY);
+        let rec = self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY);
         match rec {
             None => 0.0,
             Some(_r) => {
@@ -143,11 +205,82 @@ impl<T: Material> Object for Sphere<T> {
         }
     }
 
-    fn random(&self, v: Vec3) -> Vec3 {
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
         let di = self.center - v;
         let dis_sq = di.length_squared();
         let uvw = Onb::build_from_w(di);
-        uvw.local(Vec3::random_to_sphere(self.radius, dis_sq))
+        uvw.local(Vec3::random_to_sphere(self.radius, dis_sq, s))
+    }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let n = Vec3::random_unit_vec(s);
+        let uv_ = HitRecord::get_sphere_uv(n);
+        let rec = HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
+            p: self.center + n * self.radius,
+            normal: n,
+            t: 0.0,
+            front_face: true,
+            mat: Option::Some(&self.mat),
+            u: uv_.u,
+            v: uv_.v,
+        };
+        (rec, self.surface_pdf(Vec3::zero(), Vec3::zero()))
+    }
+
+    fn surface_pdf(&self, _o: Vec3, _d: Vec3) -> f64 {
+        1.0 / (4.0 * std::f64::consts::PI * self.radius * self.radius)
+    }
+}
+
+// fraction of the way from time0 to time1 at time, held at 0 before and 1 after
+fn progress(time: f64, time0: f64, time1: f64) -> f64 {
+    crate::clamp((time - time0) / (time1 - time0), 0.0, 1.0)
+}
+
+// a sphere moving in a straight line from center0 at time0 to center1 at time1
+pub struct MovingSphere<T: Material> {
+    sphere: Sphere<T>,
+    center1: Vec3,
+    time0: f64,
+    time1: f64,
+}
+
+impl<T: Material> MovingSphere<T> {
+    pub fn new(center0: Vec3, center1: Vec3, time0: f64, time1: f64, r: f64, m: T) -> Self {
+        Self {
+            sphere: Sphere::new(center0, r, m),
+            center1,
+            time0,
+            time1,
+        }
+    }
+
+    // how far the sphere has moved from center0 at time
+    fn offset(&self, time: f64) -> Vec3 {
+        (self.center1 - self.sphere.center) * progress(time, self.time0, self.time1)
+    }
+}
+
+impl<T: Material> Object for MovingSphere<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let offset = self.offset(r.time);
+        let mut rec = self
+            .sphere
+            .hit(Ray::new(r.beg - offset, r.dir, r.time), t_min, t_max)?;
+        rec.p += offset;
+        Some(rec)
+    }
+
+    // the path is straight, so the boxes at either end cover everything in between
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        let b = self.sphere.bounding_box(t0, t1)?;
+        let (o0, o1) = (self.offset(t0), self.offset(t1));
+        Some(AABB::surrounding_box(
+            AABB::new(b.min + o0, b.max + o0),
+            AABB::new(b.min + o1, b.max + o1),
+        ))
     }
 }
 
@@ -187,6 +320,8 @@ impl<T: Material> Object for XYRect<T> {
         }
         let outward_normal = Vec3::new(0.0, 0.0, 1.0);
         Option::Some(HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
             p: r.at(t_),
             normal: {
                 if (r.dir * outward_normal) < 0.0 {
@@ -203,7 +338,7 @@ impl<T: Material> Object for XYRect<T> {
         })
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(AABB::new(
             Vec3::new(self.x0, self.y0, self.k - 0.0001),
             Vec3::new(self.x1, self.y1, self.k + 0.0001),
@@ -211,7 +346,7 @@ impl<T: Material> Object for XYRect<T> {
     }
 
     fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
-        let rec = self.hit(Ray::new(o, d), 0.001, INFINITY);
+        let rec = self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY);
         match rec {
             None => 0.0,
             Some(rec) => {
@@ -223,15 +358,40 @@ impl<T: Material> Object for XYRect<T> {
         }
     }
 
-    fn random(&self, v: Vec3) -> Vec3 {
-        let mut rng = rand::thread_rng();
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
         let ran = Vec3::new(
-            rng.gen_range(self.x0, self.x1),
+            self.x0 + r1 * (self.x1 - self.x0),
+            self.y0 + r2 * (self.y1 - self.y0),
             self.k,
-            rng.gen_range(self.y0, self.y1),
         );
         ran - v
     }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (r1, r2) = s.get_2d();
+        let x = self.x0 + r1 * (self.x1 - self.x0);
+        let y = self.y0 + r2 * (self.y1 - self.y0);
+        let rec = HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
+            p: Vec3::new(x, y, self.k),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            t: 0.0,
+            front_face: true,
+            mat: Option::Some(&self.mp),
+            u: (x - self.x0) / (self.x1 - self.x0),
+            v: (y - self.y0) / (self.y1 - self.y0),
+        };
+        (rec, 1.0 / ((self.x1 - self.x0) * (self.y1 - self.y0)))
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        match self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY) {
+            None => 0.0,
+            Some(_) => 1.0 / ((self.x1 - self.x0) * (self.y1 - self.y0)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Copy)]
@@ -270,6 +430,8 @@ impl<T: Material> Object for XZRect<T> {
         }
         let outward_normal = Vec3::new(0.0, 1.0, 0.0);
         Option::Some(HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
             p: r.at(t_),
             normal: {
                 if (r.dir * outward_normal) < 0.0 {
@@ -286,7 +448,7 @@ impl<T: Material> Object for XZRect<T> {
         })
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(AABB::new(
             Vec3::new(self.x0, self.k - 0.0001, self.z0),
             Vec3::new(self.x1, self.k + 0.0001, self.z1),
@@ -294,7 +456,7 @@ impl<T: Material> Object for XZRect<T> {
     }
 
     fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
-        let rec = self.hit(Ray::new(o, d), 0.001, INFINITY);
+        let rec = self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY);
         match rec {
             None => 0.0,
             Some(rec) => {
@@ -306,15 +468,40 @@ impl<T: Material> Object for XZRect<T> {
         }
     }
 
-    fn random(&self, v: Vec3) -> Vec3 {
-        let mut rng = rand::thread_rng();
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
         let ran = Vec3::new(
-            rng.gen_range(self.x0, self.x1),
+            self.x0 + r1 * (self.x1 - self.x0),
             self.k,
-            rng.gen_range(self.z0, self.z1),
+            self.z0 + r2 * (self.z1 - self.z0),
         );
         ran - v
     }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (r1, r2) = s.get_2d();
+        let x = self.x0 + r1 * (self.x1 - self.x0);
+        let z = self.z0 + r2 * (self.z1 - self.z0);
+        let rec = HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
+            p: Vec3::new(x, self.k, z),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            t: 0.0,
+            front_face: true,
+            mat: Option::Some(&self.mp),
+            u: (x - self.x0) / (self.x1 - self.x0),
+            v: (z - self.z0) / (self.z1 - self.z0),
+        };
+        (rec, 1.0 / ((self.x1 - self.x0) * (self.z1 - self.z0)))
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        match self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY) {
+            None => 0.0,
+            Some(_) => 1.0 / ((self.x1 - self.x0) * (self.z1 - self.z0)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Copy)]
@@ -353,6 +540,8 @@ impl<T: Material> Object for YZRrect<T> {
         }
         let outward_normal = Vec3::new(1.0, 0.0, 0.0);
         Option::Some(HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
             p: r.at(t_),
             normal: {
                 if (r.dir * outward_normal) < 0.0 {
@@ -369,7 +558,7 @@ impl<T: Material> Object for YZRrect<T> {
         })
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(AABB::new(
             Vec3::new(self.k - 0.0001, self.y0, self.z0),
             Vec3::new(self.k + 0.0001, self.y1, self.z1),
@@ -377,7 +566,7 @@ impl<T: Material> Object for YZRrect<T> {
     }
 
     fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
-        let rec = self.hit(Ray::new(o, d), 0.001, INFINITY);
+        let rec = self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY);
         match rec {
             None => 0.0,
             Some(rec) => {
@@ -389,15 +578,40 @@ impl<T: Material> Object for YZRrect<T> {
         }
     }
 
-    fn random(&self, v: Vec3) -> Vec3 {
-        let mut rng = rand::thread_rng();
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
         let ran = Vec3::new(
-            rng.gen_range(self.y0, self.y1),
             self.k,
-            rng.gen_range(self.z0, self.z1),
+            self.y0 + r1 * (self.y1 - self.y0),
+            self.z0 + r2 * (self.z1 - self.z0),
         );
         ran - v
     }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (r1, r2) = s.get_2d();
+        let y = self.y0 + r1 * (self.y1 - self.y0);
+        let z = self.z0 + r2 * (self.z1 - self.z0);
+        let rec = HitRecord {
+            id: 0,
+            emission: Vec3::zero(),
+            p: Vec3::new(self.k, y, z),
+            normal: Vec3::new(1.0, 0.0, 0.0),
+            t: 0.0,
+            front_face: true,
+            mat: Option::Some(&self.mp),
+            u: (y - self.y0) / (self.y1 - self.y0),
+            v: (z - self.z0) / (self.z1 - self.z0),
+        };
+        (rec, 1.0 / ((self.y1 - self.y0) * (self.z1 - self.z0)))
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        match self.hit(Ray::new(o, d, 0.0), 0.001, INFINITY) {
+            None => 0.0,
+            Some(_) => 1.0 / ((self.y1 - self.y0) * (self.z1 - self.z0)),
+        }
+    }
 }
 
 pub struct Box {
@@ -463,7 +677,7 @@ impl Object for Box {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
         self.sides.hit(r, t_min, t_max)
     }
-    fn bounding_box(&self) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Option::Some(AABB::new(self.box_min, self.box_max))
     }
 }
@@ -481,7 +695,7 @@ impl<T: Object> Translate<T> {
 
 impl<T: Object> Object for Translate<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mor = Ray::new(r.beg - self.offset, r.dir);
+        let mor = Ray::new(r.beg - self.offset, r.dir, r.time);
         let wmm = self.ptr.hit(mor, t_min, t_max);
         match wmm {
             None => None,
@@ -500,74 +714,68 @@ impl<T: Object> Object for Translate<T> {
         }
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
-        let tmp = self.ptr.bounding_box();
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        let tmp = self.ptr.bounding_box(t0, t1);
         match tmp {
             None => None,
             Some(k) => Some(AABB::new(k.min + self.offset, k.max + self.offset)),
         }
     }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        let mor = Ray::new(r.beg - self.offset, r.dir, r.time);
+        self.ptr.transmittance(mor, t_min, t_max)
+    }
 }
 
 pub struct RotateY<T: Object> {
     ptr: T,
     sin_theta: f64,
     cos_theta: f64,
-    hasbox: bool,
-    bbox: AABB,
 }
 
 impl<T: Object> RotateY<T> {
     pub fn new(p: T, angle: f64) -> Self {
         let radians = angle * std::f64::consts::PI / 180.0;
+        Self {
+            ptr: p,
+            sin_theta: radians.sin(),
+            cos_theta: radians.cos(),
+        }
+    }
+}
 
-        let co = radians.cos();
-        let si = radians.sin();
-
-        let get = p.bounding_box();
-        let mut tt = get.unwrap();
-
-        let mi = Vec3::new(INFINITY, INFINITY, INFINITY);
-        let ma = Vec3::new(-INFINITY, -INFINITY, -INFINITY);
+// the box around tt after turning it by the angle with the given sine and cosine about the y axis
+fn rotate_box(tt: AABB, si: f64, co: f64) -> AABB {
+    let mut mi = Vec3::new(INFINITY, INFINITY, INFINITY);
+    let mut ma = Vec3::new(-INFINITY, -INFINITY, -INFINITY);
 
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let x = tt.max.x * i as f64 + (1.0 - i as f64) * tt.min.x;
-                    let y = tt.max.y * j as f64 + (1.0 - j as f64) * tt.min.y;
-                    let z = tt.max.z * k as f64 + (1.0 - k as f64) * tt.min.z;
+    for i in 0..2 {
+        for j in 0..2 {
+            for k in 0..2 {
+                let x = tt.max.x * i as f64 + (1.0 - i as f64) * tt.min.x;
+                let y = tt.max.y * j as f64 + (1.0 - j as f64) * tt.min.y;
+                let z = tt.max.z * k as f64 + (1.0 - k as f64) * tt.min.z;
 
-                    let newx = x * co + z * si;
-                    let newz = -si * x + co * z;
-                    let tes = Vec3::new(newx, y, newz);
+                let newx = x * co + z * si;
+                let newz = -si * x + co * z;
+                let tes = Vec3::new(newx, y, newz);
 
-                    mi.x.min(tes.x);
-                    ma.x.max(tes.x);
-                    mi.y.min(tes.y);
-                    ma.y.max(tes.y);
-                    mi.z.min(tes.z);
-                    ma.z.max(tes.z);
-                }
+                mi.x = mi.x.min(tes.x);
+                ma.x = ma.x.max(tes.x);
+                mi.y = mi.y.min(tes.y);
+                ma.y = ma.y.max(tes.y);
+                mi.z = mi.z.min(tes.z);
+                ma.z = ma.z.max(tes.z);
             }
         }
-        tt = AABB::new(mi, ma);
-        Self {
-            ptr: p,
-            sin_theta: si,
-            cos_theta: co,
-            hasbox: {
-                match get {
-                    None => false,
-                    Some(_w) => true,
-                }
-            },
-            bbox: tt,
-        }
     }
+    AABB::new(mi, ma)
 }
 
-impl<T: Object> Object for RotateY<T> {
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+impl<T: Object> RotateY<T> {
+    // the ray in the frame of the unrotated object
+    fn to_local(&self, r: Ray) -> Ray {
         let mut ori = r.beg;
         let mut di = r.dir;
 
@@ -577,7 +785,13 @@ impl<T: Object> Object for RotateY<T> {
         di.x = self.cos_theta * r.dir.x - self.sin_theta * r.dir.z;
         di.z = self.sin_theta * r.dir.x + self.cos_theta * r.dir.z;
 
-        let ror = Ray::new(ori, di);
+        Ray::new(ori, di, r.time)
+    }
+}
+
+impl<T: Object> Object for RotateY<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let ror = self.to_local(r);
 
         let ww = self.ptr.hit(ror, t_min, t_max);
 
@@ -605,13 +819,116 @@ impl<T: Object> Object for RotateY<T> {
         }
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
-        if self.hasbox {
-            Some(self.bbox)
-        } else {
-            None
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        let tt = self.ptr.bounding_box(t0, t1)?;
+        Some(rotate_box(tt, self.sin_theta, self.cos_theta))
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        self.ptr.transmittance(self.to_local(r), t_min, t_max)
+    }
+}
+
+// the vector v turned about the y axis by the angle with the given sine and cosine
+fn turn_y(v: Vec3, si: f64, co: f64) -> Vec3 {
+    Vec3::new(co * v.x + si * v.z, v.y, -si * v.x + co * v.z)
+}
+
+// moves the wrapped object between two poses, each a turn about the y axis followed by a
+// shift, going linearly from the first at time0 to the second at time1
+pub struct Motion<T: Object> {
+    ptr: T,
+    time0: f64,
+    time1: f64,
+    offset0: Vec3,
+    offset1: Vec3,
+    // in radians
+    angle0: f64,
+    angle1: f64,
+}
+
+impl<T: Object> Motion<T> {
+    // angles in degrees
+    pub fn new(
+        p: T,
+        time0: f64,
+        time1: f64,
+        offset0: Vec3,
+        offset1: Vec3,
+        angle0: f64,
+        angle1: f64,
+    ) -> Self {
+        Self {
+            ptr: p,
+            time0,
+            time1,
+            offset0,
+            offset1,
+            angle0: angle0.to_radians(),
+            angle1: angle1.to_radians(),
         }
     }
+
+    // the shift and the turn at time
+    fn pose(&self, time: f64) -> (Vec3, f64) {
+        let f = progress(time, self.time0, self.time1);
+        (
+            self.offset0 + (self.offset1 - self.offset0) * f,
+            self.angle0 + (self.angle1 - self.angle0) * f,
+        )
+    }
+
+    // the ray in the frame of the object at rest
+    fn to_local(&self, r: Ray) -> (Ray, Vec3, f64) {
+        let (offset, angle) = self.pose(r.time);
+        let (si, co) = angle.sin_cos();
+        let local = Ray::new(
+            turn_y(r.beg - offset, -si, co),
+            turn_y(r.dir, -si, co),
+            r.time,
+        );
+        (local, offset, angle)
+    }
+}
+
+impl<T: Object> Object for Motion<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (local, offset, angle) = self.to_local(r);
+        let (si, co) = angle.sin_cos();
+        let mut rec = self.ptr.hit(local, t_min, t_max)?;
+        rec.p = turn_y(rec.p, si, co) + offset;
+        let nor = turn_y(rec.normal, si, co);
+        rec.normal = if r.dir * nor < 0.0 { nor } else { -nor };
+        Some(rec)
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        let b = self.ptr.bounding_box(t0, t1)?;
+        let (offset0, angle0) = self.pose(t0);
+        let (offset1, angle1) = self.pose(t1);
+        let turned = if (angle1 - angle0).abs() < 1e-12 {
+            let (si, co) = angle0.sin_cos();
+            rotate_box(b, si, co)
+        } else {
+            // turning sweeps the box around the axis, so take the whole circle it may reach
+            let x = b.min.x.abs().max(b.max.x.abs());
+            let z = b.min.z.abs().max(b.max.z.abs());
+            let radius = (x * x + z * z).sqrt();
+            AABB::new(
+                Vec3::new(-radius, b.min.y, -radius),
+                Vec3::new(radius, b.max.y, radius),
+            )
+        };
+        // the shift is linear in time, so its two ends bound it
+        Some(AABB::surrounding_box(
+            AABB::new(turned.min + offset0, turned.max + offset0),
+            AABB::new(turned.min + offset1, turned.max + offset1),
+        ))
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        self.ptr.transmittance(self.to_local(r).0, t_min, t_max)
+    }
 }
 
 pub struct FlipFace<T: Object> {
@@ -636,7 +953,268 @@ impl<T: Object> Object for FlipFace<T> {
             }
         }
     }
-    fn bounding_box(&self) -> Option<AABB> {
-        self.ptr.bounding_box()
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.ptr.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
+        self.ptr.pdf_value(o, d)
+    }
+
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(v, s)
+    }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (mut rec, pdf) = self.ptr.sample_surface(s);
+        rec.front_face = !rec.front_face;
+        (rec, pdf)
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        self.ptr.surface_pdf(o, d)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        self.ptr.transmittance(r, t_min, t_max)
+    }
+}
+
+// smoke, fog or any other medium of constant density filling a convex boundary; a ray
+// scatters off the phase function material at an exponentially distributed distance inside,
+// or passes through if that lies beyond where it leaves
+pub struct ConstantMedium<T: Object, M: Material> {
+    boundary: T,
+    neg_inv_density: f64,
+    phase: M,
+    id: u64,
+}
+
+impl<T: Object, M: Material> ConstantMedium<T, M> {
+    pub fn new(boundary: T, density: f64, phase: M) -> Self {
+        let id = medium_id(boundary.bounding_box(0.0, 0.0), density);
+        Self {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase,
+            id,
+        }
+    }
+
+    // where the ray line enters and leaves the boundary, clipped to [t_min, t_max]
+    fn segment(&self, r: Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let t_in = self.boundary.hit(r, -INFINITY, INFINITY)?.t;
+        let t_out = self.boundary.hit(r, t_in + 0.0001, INFINITY)?.t;
+        let t_in = t_in.max(t_min).max(0.0);
+        let t_out = t_out.min(t_max);
+        if t_in >= t_out {
+            None
+        } else {
+            Some((t_in, t_out))
+        }
+    }
+}
+
+// hit has no sampler, so the random numbers a medium needs come from hashing the ray: the
+// same ray always meets the medium at the same point, which keeps shadow rays consistent with
+// the rays they check and renders reproducible, and any other ray draws independently
+pub fn ray_seed(r: Ray) -> u64 {
+    [r.beg.x, r.beg.y, r.beg.z, r.dir.x, r.dir.y, r.dir.z]
+        .iter()
+        .fold(0, |h, x| sampler::hash(h, x.to_bits()))
+}
+
+// names a medium by its bounds and density, so its draws depend on the medium alone and not
+// on what else was built before it; only media that coincide and match in density share one
+pub fn medium_id(bounds: Option<AABB>, density: f64) -> u64 {
+    let corners = bounds.map_or([0.0; 6], |b| {
+        [b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z]
+    });
+    corners
+        .iter()
+        .fold(density.to_bits(), |h, x| sampler::hash(h, x.to_bits()))
+}
+
+// the seed of the medium `id` for the stretch of r that enters it at t_in; the ray alone is
+// not enough, every medium a ray crosses has to draw its own numbers or their collisions
+// would all happen at the same fraction of the way
+pub fn medium_seed(r: Ray, id: u64, t_in: f64) -> u64 {
+    sampler::hash(sampler::hash(ray_seed(r), id), t_in.to_bits())
+}
+
+// the k-th uniform number in [0, 1) of the sequence seeded by medium_seed
+pub fn ray_random(seed: u64, k: u64) -> f64 {
+    (sampler::hash(seed, k) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl<T: Object, M: Material> Object for ConstantMedium<T, M> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (t_in, t_out) = self.segment(r, t_min, t_max)?;
+        let length = r.dir.length();
+        let seed = medium_seed(r, self.id, t_in);
+        let distance = self.neg_inv_density * (1.0 - ray_random(seed, 0)).ln();
+        if distance > (t_out - t_in) * length {
+            return None;
+        }
+        let t = t_in + distance / length;
+        Some(HitRecord {
+            p: r.at(t),
+            // arbitrary, phase functions do not look at it
+            normal: Vec3::new(1.0, 0.0, 0.0),
+            t,
+            front_face: true,
+            mat: Some(&self.phase),
+            u: 0.0,
+            v: 0.0,
+            id: 0,
+            emission: Vec3::zero(),
+        })
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.boundary.bounding_box(t0, t1)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        match self.segment(r, t_min, t_max) {
+            Some((t_in, t_out)) => ((t_out - t_in) * r.dir.length() / self.neg_inv_density).exp(),
+            None => 1.0,
+        }
+    }
+}
+
+// gives every hit of the wrapped object a stable id, see HittableList::tagged
+pub struct Tagged<T: Object> {
+    id: u32,
+    ptr: T,
+}
+
+impl<T: Object> Tagged<T> {
+    pub fn new(id: u32, a: T) -> Self {
+        Self { id, ptr: a }
+    }
+}
+
+impl<T: Object> Object for Tagged<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut rec = self.ptr.hit(r, t_min, t_max)?;
+        rec.id = self.id;
+        Some(rec)
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.ptr.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, o: Vec3, d: Vec3) -> f64 {
+        self.ptr.pdf_value(o, d)
+    }
+
+    fn random(&self, v: Vec3, s: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(v, s)
+    }
+
+    fn sample_surface(&self, s: &mut dyn Sampler) -> (HitRecord, f64) {
+        let (mut rec, pdf) = self.ptr.sample_surface(s);
+        rec.id = self.id;
+        (rec, pdf)
+    }
+
+    fn surface_pdf(&self, o: Vec3, d: Vec3) -> f64 {
+        self.ptr.surface_pdf(o, d)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        self.ptr.transmittance(r, t_min, t_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Isotropic, Lambertian, SolidColor};
+
+    fn slab(z0: f64, z1: f64) -> Arc<dyn Object> {
+        let white = Isotropic::new(SolidColor::new(Vec3::new(1.0, 1.0, 1.0)));
+        Arc::new(ConstantMedium::new(
+            Box::new(
+                Vec3::new(-1.0, -1.0, z0),
+                Vec3::new(1.0, 1.0, z1),
+                white.clone(),
+            ),
+            1.0,
+            white,
+        ))
+    }
+
+    // fraction of rays along +z that cross the media without a collision
+    fn passed(world: &dyn Object) -> f64 {
+        let n = 40_000;
+        let mut rng = sampler::Pcg32::new(7, 0);
+        let through = (0..n)
+            .filter(|_| {
+                let beg = Vec3::new(rng.next_f64() - 0.5, rng.next_f64() - 0.5, -1.0);
+                let r = Ray::new(beg, Vec3::new(0.0, 0.0, 1.0), 0.0);
+                world.hit(r, 0.001, crate::INFINITY).is_none()
+            })
+            .count();
+        through as f64 / n as f64
+    }
+
+    #[test]
+    fn stacked_media_draw_independently() {
+        let mut one = HittableList::new();
+        one.add(slab(0.0, 1.0));
+        assert!((passed(&one) - (-1.0f64).exp()).abs() < 0.01);
+
+        let mut two = HittableList::new();
+        two.add(slab(0.0, 1.0));
+        two.add(slab(1.5, 2.5));
+        assert!((passed(&two) - (-2.0f64).exp()).abs() < 0.01);
+    }
+
+    #[test]
+    fn moving_sphere_boxes_cover_the_shutter() {
+        let ball = MovingSphere::new(
+            Vec3::zero(),
+            Vec3::new(2.0, 0.0, 0.0),
+            0.0,
+            1.0,
+            0.5,
+            Lambertian::new(SolidColor::new(Vec3::new(0.5, 0.5, 0.5))),
+        );
+        let whole = AABB::new(Vec3::new(-0.5, -0.5, -0.5), Vec3::new(2.5, 0.5, 0.5));
+        assert_eq!(ball.bounding_box(0.0, 1.0), Some(whole));
+        let late = AABB::new(Vec3::new(0.5, -0.5, -0.5), Vec3::new(2.5, 0.5, 0.5));
+        assert_eq!(ball.bounding_box(0.5, 1.0), Some(late));
+
+        // a ray down through x = 2 finds the ball only once it has got there
+        let down = |time| Ray::new(Vec3::new(2.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), time);
+        assert!(ball.hit(down(0.0), 0.001, crate::INFINITY).is_none());
+        let rec = ball.hit(down(1.0), 0.001, crate::INFINITY).unwrap();
+        assert!((rec.p - Vec3::new(2.0, 0.5, 0.0)).length() < 1e-9);
+    }
+
+    #[test]
+    fn media_draw_the_same_whatever_was_built_first() {
+        let rays: Vec<Ray> = (0..50)
+            .map(|k| {
+                let x = k as f64 / 50.0 - 0.5;
+                Ray::new(Vec3::new(x, -x, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0)
+            })
+            .collect();
+        let hits = |slab: &dyn Object| -> Vec<Option<f64>> {
+            rays.iter()
+                .map(|&r| slab.hit(r, 0.001, crate::INFINITY).map(|rec| rec.t))
+                .collect()
+        };
+        let first = hits(&*slab(0.0, 1.0));
+        for k in 0..5 {
+            slab(k as f64, k as f64 + 1.0);
+        }
+        assert_eq!(hits(&*slab(0.0, 1.0)), first);
+        // a medium elsewhere draws its own numbers
+        let moved = hits(&*slab(0.0, 1.5));
+        assert_ne!(moved, first);
     }
 }
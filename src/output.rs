@@ -0,0 +1,289 @@
+use crate::tonemap::{self, ToneMapper};
+use crate::Vec3;
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageBuffer, Rgb, RgbImage};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// writes the linear radiance buffer, picking the format from the file extension;
+// only 8-bit formats go through the tone mapper
+pub fn save(
+    path: &str,
+    buf: &[Vec3],
+    width: u32,
+    height: u32,
+    tm: &ToneMapper,
+) -> Result<(), String> {
+    let ret = match extension(path).as_str() {
+        "hdr" => write_hdr(path, buf, width, height),
+        "pfm" => write_pfm(path, buf, width, height),
+        "exr" => write_exr(
+            path,
+            width,
+            height,
+            &[
+                ("B", buf.iter().map(|c| c.z as f32).collect()),
+                ("G", buf.iter().map(|c| c.y as f32).collect()),
+                ("R", buf.iter().map(|c| c.x as f32).collect()),
+            ],
+        ),
+        _ => to_rgb_image(&tm.apply(buf), width, height)
+            .save(path)
+            .map_err(|e| e.to_string()),
+    };
+    ret.map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn is_exr(path: &str) -> bool {
+    extension(path) == "exr"
+}
+
+// where an auxiliary pass goes next to the beauty image, output/test.png gives
+// output/test.depth.png
+pub fn pass_path(path: &str, name: &str) -> String {
+    let p = Path::new(path);
+    match p.extension().and_then(|e| e.to_str()) {
+        Some(ext) => p
+            .with_extension(format!("{}.{}", name, ext))
+            .to_string_lossy()
+            .into_owned(),
+        None => format!("{}.{}", path, name),
+    }
+}
+
+// writes an auxiliary pass, float formats keep the raw values and 8-bit formats get
+// `display`, which is already in [0, 1]
+pub fn save_pass(
+    path: &str,
+    raw: &[Vec3],
+    display: &[Vec3],
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    let ret = match extension(path).as_str() {
+        "hdr" => write_hdr(path, raw, width, height),
+        "pfm" => write_pfm(path, raw, width, height),
+        "exr" => write_exr(
+            path,
+            width,
+            height,
+            &[
+                ("B", raw.iter().map(|c| c.z as f32).collect()),
+                ("G", raw.iter().map(|c| c.y as f32).collect()),
+                ("R", raw.iter().map(|c| c.x as f32).collect()),
+            ],
+        ),
+        _ => to_rgb_image(display, width, height)
+            .save(path)
+            .map_err(|e| e.to_string()),
+    };
+    ret.map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+// the beauty image in R, G and B plus one layer per pass, named <layer>.<channel>, with
+// channel names taken for x, y and z in order
+pub fn save_exr_layers(
+    path: &str,
+    buf: &[Vec3],
+    width: u32,
+    height: u32,
+    layers: &[(&str, &[&str], Vec<Vec3>)],
+) -> Result<(), String> {
+    let mut channels: Vec<(String, Vec<f32>)> = vec![
+        (String::from("R"), buf.iter().map(|c| c.x as f32).collect()),
+        (String::from("G"), buf.iter().map(|c| c.y as f32).collect()),
+        (String::from("B"), buf.iter().map(|c| c.z as f32).collect()),
+    ];
+    for (layer, names, data) in layers {
+        for (k, name) in names.iter().enumerate() {
+            let v = data.iter().map(|c| [c.x, c.y, c.z][k] as f32).collect();
+            channels.push((format!("{}.{}", layer, name), v));
+        }
+    }
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+    write_exr(path, width, height, &channels).map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+// expects display-referred linear values, as returned by ToneMapper::apply
+pub fn to_rgb_image(buf: &[Vec3], width: u32, height: u32) -> RgbImage {
+    let mut img: RgbImage = ImageBuffer::new(width, height);
+    for j in 0..height {
+        for i in 0..width {
+            let col = buf[(j * width + i) as usize];
+            *img.get_pixel_mut(i, j) = image::Rgb(tonemap::to_srgb8(col));
+        }
+    }
+    img
+}
+
+fn create(path: &str) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| e.to_string())
+}
+
+// Radiance RGBE, run-length encoded by the image crate
+pub fn write_hdr(path: &str, buf: &[Vec3], width: u32, height: u32) -> Result<(), String> {
+    let data: Vec<Rgb<f32>> = buf
+        .iter()
+        .map(|c| Rgb([c.x as f32, c.y as f32, c.z as f32]))
+        .collect();
+    let w = create(path)?;
+    HdrEncoder::new(w)
+        .encode(&data, width as usize, height as usize)
+        .map_err(|e| e.to_string())
+}
+
+// portable float map: little-endian f32 RGB, rows stored from the bottom up
+pub fn write_pfm(path: &str, buf: &[Vec3], width: u32, height: u32) -> Result<(), String> {
+    let mut w = create(path)?;
+    let mut data = Vec::with_capacity(buf.len() * 12 + 32);
+    data.extend_from_slice(format!("PF\n{} {}\n-1.0\n", width, height).as_bytes());
+    for j in (0..height).rev() {
+        for i in 0..width {
+            let c = buf[(j * width + i) as usize];
+            for x in [c.x, c.y, c.z].iter() {
+                data.extend_from_slice(&(*x as f32).to_le_bytes());
+            }
+        }
+    }
+    w.write_all(&data)
+        .and_then(|_| w.flush())
+        .map_err(|e| e.to_string())
+}
+
+fn exr_attr(out: &mut Vec<u8>, name: &str, ty: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(ty.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+// single-part scanline OpenEXR with uncompressed 32-bit float channels;
+// channels must be sorted by name, e.g. B, G, R
+pub fn write_exr<S: AsRef<str>>(
+    path: &str,
+    width: u32,
+    height: u32,
+    channels: &[(S, Vec<f32>)],
+) -> Result<(), String> {
+    let mut out: Vec<u8> = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    let mut chlist = Vec::new();
+    for (name, _) in channels.iter() {
+        chlist.extend_from_slice(name.as_ref().as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&2i32.to_le_bytes()); // FLOAT
+        chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    exr_attr(&mut out, "channels", "chlist", &chlist);
+    exr_attr(&mut out, "compression", "compression", &[0]);
+    let mut window = Vec::new();
+    for x in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&x.to_le_bytes());
+    }
+    exr_attr(&mut out, "dataWindow", "box2i", &window);
+    exr_attr(&mut out, "displayWindow", "box2i", &window);
+    exr_attr(&mut out, "lineOrder", "lineOrder", &[0]);
+    exr_attr(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    exr_attr(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attr(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0);
+
+    // one scanline per chunk, each chunk is its y, its byte size and the channel rows
+    let line_size = (width as usize) * 4 * channels.len();
+    let table_start = out.len();
+    let chunks_start = table_start + 8 * height as usize;
+    for j in 0..height as usize {
+        let offset = (chunks_start + j * (8 + line_size)) as u64;
+        out.extend_from_slice(&offset.to_le_bytes());
+    }
+    for j in 0..height as usize {
+        out.extend_from_slice(&(j as i32).to_le_bytes());
+        out.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for (_, data) in channels.iter() {
+            for x in data[j * width as usize..(j + 1) * width as usize].iter() {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+    }
+
+    let mut w = create(path)?;
+    w.write_all(&out)
+        .and_then(|_| w.flush())
+        .map_err(|e| e.to_string())
+}
+
+// a file in the system temp directory that no other test process uses
+#[cfg(test)]
+pub fn temp(name: &str) -> String {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("raytracer-{}-{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::read_image;
+    use crate::tonemap::Operator;
+    use std::fs;
+
+    const WIDTH: u32 = 3;
+    const HEIGHT: u32 = 2;
+
+    // every pixel different, with values that f32 holds exactly
+    fn image() -> Vec<Vec3> {
+        (0..WIDTH * HEIGHT)
+            .map(|k| {
+                let x = k as f64;
+                Vec3::new(0.25 + x, 0.5 * x + 0.125, 8.0 - x)
+            })
+            .collect()
+    }
+
+    // writes the image and reads it back, rows from the top
+    fn round_trip(name: &str) -> Vec<Vec3> {
+        let path = temp(name);
+        let tm = ToneMapper::new(Operator::Linear, 0.0, None);
+        save(&path, &image(), WIDTH, HEIGHT, &tm).unwrap();
+        let back = read_image(Path::new(&path));
+        fs::remove_file(&path).unwrap();
+        let (width, height, pixels) = back.unwrap();
+        assert_eq!(
+            (width, height),
+            (WIDTH as usize, HEIGHT as usize),
+            "{}",
+            name
+        );
+        pixels
+    }
+
+    #[test]
+    fn float_formats_round_trip() {
+        for name in ["image.pfm", "image.exr"].iter() {
+            for (k, (a, b)) in round_trip(name).iter().zip(image()).enumerate() {
+                assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z), "{} pixel {}", name, k);
+            }
+        }
+        // rgbe keeps 8 bits of mantissa per channel under a shared exponent
+        for (k, (a, b)) in round_trip("image.hdr").iter().zip(image()).enumerate() {
+            let err = (*a - b).length() / b.length();
+            assert!(err < 0.02, "hdr pixel {}: {} rather than {}", k, a, b);
+        }
+    }
+}
@@ -1,10 +1,11 @@
+use crate::sampler::Sampler;
 use crate::Object;
 use crate::Onb;
 use crate::Vec3;
 
 pub trait Pdf {
     fn value(&self, dir: Vec3) -> f64;
-    fn generate(&self) -> Vec3;
+    fn generate(&self, s: &mut dyn Sampler) -> Vec3;
 }
 
 pub struct CosPdf {
@@ -29,53 +30,165 @@ impl Pdf for CosPdf {
         }
     }
 
-    fn generate(&self) -> Vec3 {
-        self.uvw.local(Vec3::random_cosine_direction())
+    fn generate(&self, s: &mut dyn Sampler) -> Vec3 {
+        self.uvw.local(Vec3::random_cosine_direction(s))
     }
 }
 
-pub struct HittablePdf<T: Object> {
+pub struct HittablePdf<'a> {
     o: Vec3,
-    ptr: T,
+    ptr: &'a dyn Object,
 }
 
-impl<'a, T: Object> HittablePdf<T> {
-    pub fn new(oo: Vec3, p: T) -> Self {
+impl<'a> HittablePdf<'a> {
+    pub fn new(oo: Vec3, p: &'a dyn Object) -> Self {
         Self { o: oo, ptr: p }
     }
 }
 
-impl<T: Object> Pdf for HittablePdf<T> {
+impl<'a> Pdf for HittablePdf<'a> {
     fn value(&self, dir: Vec3) -> f64 {
         self.ptr.pdf_value(self.o, dir)
     }
 
-    fn generate(&self) -> Vec3 {
-        self.ptr.random(self.o)
+    fn generate(&self, s: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(self.o, s)
     }
 }
 
-pub struct MixturePdf<'a> {
-    p0: &'a dyn Pdf,
-    p1: &'a dyn Pdf,
+// Henyey-Greenstein phase function of the angle between the incoming direction and the
+// scattered one; g > 0 scatters forward, g < 0 back and g = 0 uniformly over the sphere
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * std::f64::consts::PI * denom * denom.sqrt())
 }
 
-impl<'a> MixturePdf<'a> {
-    pub fn new(pp0: &'a dyn Pdf, pp1: &'a dyn Pdf) -> Self {
-        Self { p0: pp0, p1: pp1 }
+// samples the phase function exactly, so its value is also the pdf
+pub struct PhasePdf {
+    uvw: Onb,
+    g: f64,
+}
+
+impl PhasePdf {
+    pub fn new(dir: Vec3, g: f64) -> Self {
+        Self {
+            uvw: Onb::build_from_w(dir),
+            g,
+        }
     }
 }
 
-impl<'a> Pdf for MixturePdf<'a> {
+impl Pdf for PhasePdf {
     fn value(&self, dir: Vec3) -> f64 {
-        0.5 * self.p0.value(dir) + 0.5 * self.p1.value(dir)
+        henyey_greenstein(dir.unit() * self.uvw.w(), self.g)
     }
-    fn generate(&self) -> Vec3 {
-        let tmp = rand::random::<f64>();
-        if tmp < 0.5 {
-            self.p0.generate()
+
+    fn generate(&self, s: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = s.get_2d();
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
         } else {
-            self.p1.generate()
+            let t = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            (1.0 + g * g - t * t) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * u2;
+        self.uvw.local(Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+}
+
+// pdfs a material can hand back from scatter, kept on the stack rather than boxed per bounce
+pub enum ScatterPdf {
+    Cos(CosPdf),
+    Phase(PhasePdf),
+}
+
+impl Pdf for ScatterPdf {
+    fn value(&self, dir: Vec3) -> f64 {
+        match self {
+            ScatterPdf::Cos(p) => p.value(dir),
+            ScatterPdf::Phase(p) => p.value(dir),
+        }
+    }
+
+    fn generate(&self, s: &mut dyn Sampler) -> Vec3 {
+        match self {
+            ScatterPdf::Cos(p) => p.generate(s),
+            ScatterPdf::Phase(p) => p.generate(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerKind, Sampling};
+    use std::f64::consts::PI;
+
+    const GS: [f64; 5] = [-0.7, 0.0, 0.0005, 0.3, 0.9];
+
+    // integral of the phase function over the directions whose cosine with the axis lies in
+    // [a, b], by the midpoint rule
+    fn mass(g: f64, a: f64, b: f64) -> f64 {
+        let n = 20_000;
+        let step = (b - a) / n as f64;
+        (0..n)
+            .map(|k| 2.0 * PI * henyey_greenstein(a + (k as f64 + 0.5) * step, g) * step)
+            .sum()
+    }
+
+    #[test]
+    fn henyey_greenstein_integrates_to_one() {
+        for &g in GS.iter() {
+            assert!((mass(g, -1.0, 1.0) - 1.0).abs() < 1e-4, "g = {}", g);
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_samples_follow_its_pdf() {
+        let axis = Vec3::new(1.0, 2.0, -0.5).unit();
+        let n = 100_000;
+        let bins = 10;
+        for &g in GS.iter() {
+            let pdf = PhasePdf::new(axis, g);
+            let mut sampler = Sampling::new(SamplerKind::Independent, 11, 1).sampler();
+            let mut counts = vec![0; bins];
+            let mut mean_cos = 0.0;
+            for k in 0..n {
+                sampler.start((0, 0), k);
+                let dir = pdf.generate(sampler.as_mut());
+                assert!((dir.length() - 1.0).abs() < 1e-9);
+                let cos = dir * axis;
+                mean_cos += cos / n as f64;
+                counts[(((cos + 1.0) / 2.0 * bins as f64) as usize).min(bins - 1)] += 1;
+                // the value is the density the direction was drawn with
+                assert!((pdf.value(dir) - henyey_greenstein(cos, g)).abs() < 1e-9);
+            }
+            // the mean cosine of henyey-greenstein is g
+            assert!(
+                (mean_cos - g).abs() < 0.01,
+                "g = {}, mean cosine {}",
+                g,
+                mean_cos
+            );
+            for (k, &count) in counts.iter().enumerate() {
+                let a = -1.0 + 2.0 * k as f64 / bins as f64;
+                let expected = mass(g, a, a + 2.0 / bins as f64);
+                let found = count as f64 / n as f64;
+                assert!(
+                    (found - expected).abs() < 0.005,
+                    "g = {}, bin {}: {} of the samples, {} expected",
+                    g,
+                    k,
+                    found,
+                    expected
+                );
+            }
         }
     }
 }
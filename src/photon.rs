@@ -0,0 +1,498 @@
+use crate::diagnostics::{self, Faults};
+use crate::environment::Background;
+use crate::integrator::{russian_roulette, sample_emission, sample_light};
+use crate::render::{pixel_uv, run_tiles, Samples};
+use crate::sampler::{Sampler, Sampling};
+use crate::tonemap::luminance;
+use crate::Camera;
+use crate::HitRecord;
+use crate::Object;
+use crate::Pdf;
+use crate::Ray;
+use crate::RenderSettings;
+use crate::Vec3;
+use crate::INFINITY;
+use indicatif::ProgressBar;
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::thread;
+
+// photon mapping after Jensen, with the stochastic progressive variant of Hachisuka and
+// Jensen: photons leave the lights on the light list and are stored where they hit a diffuse
+// surface after at least one bounce, the camera follows specular bounces to the first diffuse
+// surface and adds next-event estimation for direct light to a density estimate of the rest
+
+// sppm keeps this fraction of the new photons when shrinking the radius
+const ALPHA: f64 = 2.0 / 3.0;
+
+struct Photon {
+    p: Vec3,
+    // direction of travel
+    dir: Vec3,
+    normal: Vec3,
+    power: Vec3,
+}
+
+pub struct PhotonMap {
+    // kd-tree kept in place, each range holds its splitting photon in the middle
+    photons: Vec<Photon>,
+    axes: Vec<u32>,
+    // light paths traced for the map, including the ones that stored nothing
+    emitted: usize,
+}
+
+fn build(photons: &mut [Photon], axes: &mut [u32]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut lo = photons[0].p;
+    let mut hi = lo;
+    for ph in photons.iter() {
+        lo = Vec3::new(lo.x.min(ph.p.x), lo.y.min(ph.p.y), lo.z.min(ph.p.z));
+        hi = Vec3::new(hi.x.max(ph.p.x), hi.y.max(ph.p.y), hi.z.max(ph.p.z));
+    }
+    let ext = hi - lo;
+    let axis = if ext.x >= ext.y && ext.x >= ext.z {
+        0
+    } else if ext.y >= ext.z {
+        1
+    } else {
+        2
+    };
+    photons.sort_by(|a, b| {
+        a.p.get(axis)
+            .partial_cmp(&b.p.get(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    let mid = photons.len() / 2;
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (axes_left, axes_right) = axes.split_at_mut(mid);
+    build(left, axes_left);
+    build(&mut right[1..], &mut axes_right[1..]);
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>, emitted: usize) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self {
+            photons,
+            axes,
+            emitted,
+        }
+    }
+
+    fn query<F: FnMut(&Photon)>(&self, lo: usize, hi: usize, p: Vec3, r2: f64, f: &mut F) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let ph = &self.photons[mid];
+        if (ph.p - p).length_squared() <= r2 {
+            f(ph);
+        }
+        let d = p.get(self.axes[mid]) - ph.p.get(self.axes[mid]);
+        let (near, far) = if d <= 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.query(near.0, near.1, p, r2, f);
+        if d * d <= r2 {
+            self.query(far.0, far.1, p, r2, f);
+        }
+    }
+
+    // throughput * bsdf * power summed over the photons within sqrt(r2) of vp, and their count
+    fn gather(&self, vp: &VisiblePoint, r2: f64) -> (Vec3, usize) {
+        let mat = vp.rec.mat.unwrap();
+        let mut phi = Vec3::zero();
+        let mut m = 0;
+        self.query(0, self.photons.len(), vp.rec.p, r2, &mut |ph| {
+            // photons around a corner lie on a differently facing surface
+            if ph.normal * vp.rec.normal < 0.9 {
+                return;
+            }
+            let wi = -ph.dir;
+            let cos = wi * vp.rec.normal;
+            if cos <= 0.0 {
+                return;
+            }
+            let scattered = Ray::new(vp.rec.p, wi, vp.r_in.time);
+            let f = vp.attenuation * (mat.scattering_pdf(vp.r_in, &vp.rec, scattered) / cos);
+            phi += Vec3::elemul(f, ph.power);
+            m += 1;
+        });
+        (Vec3::elemul(vp.throughput, phi), m)
+    }
+}
+
+// follows one light path, storing a photon at every diffuse surface after the first bounce;
+// direct light is left to next-event estimation on the camera side
+fn trace_photon(
+    settings: &RenderSettings,
+    cam: &Camera,
+    world: &dyn Object,
+    lights: &dyn Object,
+    photons: &mut Vec<Photon>,
+    sampler: &mut dyn Sampler,
+) {
+    let time = cam.sample_time(sampler);
+    let e = match sample_emission(lights, time, sampler) {
+        Some(e) => e,
+        None => return,
+    };
+    let mut ray = e.ray;
+    let mut beta = e.beta();
+    let scale = beta.max_component();
+    for depth in 0..settings.max_depth {
+        let rec = match world.hit(ray, 0.001, INFINITY) {
+            Some(rec) => rec,
+            None => return,
+        };
+        let mat = rec.mat.unwrap();
+        let s = mat.scatter(ray, &rec, sampler);
+        if !s.jud {
+            return;
+        }
+        if s.is_specular {
+            beta = Vec3::elemul(beta, s.attenustion);
+            ray = s.scattered;
+        } else {
+            if depth > 0 {
+                photons.push(Photon {
+                    p: rec.p,
+                    dir: ray.dir.unit(),
+                    normal: rec.normal,
+                    power: beta,
+                });
+            }
+            let sp = s.pdf.unwrap();
+            let dir = sp.generate(sampler);
+            let pdf = sp.value(dir);
+            if pdf <= 0.0 {
+                return;
+            }
+            let scattered = Ray::new(rec.p, dir, ray.time);
+            beta = Vec3::elemul(
+                beta,
+                s.attenustion * (mat.scattering_pdf(ray, &rec, scattered) / pdf),
+            );
+            ray = scattered;
+        }
+        // roulette on the power relative to the emitted one keeps photon powers even
+        match russian_roulette(settings, depth, beta / scale, sampler) {
+            Some(p) => beta = beta / p,
+            None => return,
+        }
+    }
+}
+
+// traces `count` light paths over settings.threads threads and builds a map of their photons;
+// the paths of map number `round` are the samples of a pixel (u32::MAX, round) off the image
+pub fn shoot(
+    settings: &RenderSettings,
+    sampling: &Sampling,
+    cam: Camera,
+    world: &Arc<dyn Object>,
+    lights: &Arc<dyn Object>,
+    count: usize,
+    round: u32,
+) -> PhotonMap {
+    let threads = settings.threads.max(1);
+    let mut workers = Vec::new();
+    let mut first = 0;
+    for k in 0..threads {
+        let n = count / threads + if k < count % threads { 1 } else { 0 };
+        let settings = *settings;
+        let sampling = sampling.clone();
+        let world = world.clone();
+        let lights = lights.clone();
+        workers.push(thread::spawn(move || {
+            let mut sampler = sampling.sampler();
+            let mut photons = Vec::new();
+            for index in first..first + n {
+                sampler.start((u32::MAX, round), index as u32);
+                trace_photon(
+                    &settings,
+                    &cam,
+                    &*world,
+                    &*lights,
+                    &mut photons,
+                    &mut *sampler,
+                );
+            }
+            photons
+        }));
+        first += n;
+    }
+    let mut photons = Vec::new();
+    for worker in workers {
+        photons.extend(worker.join().unwrap());
+    }
+    PhotonMap::new(photons, count)
+}
+
+// first diffuse surface seen from the camera
+struct VisiblePoint<'a> {
+    rec: HitRecord<'a>,
+    r_in: Ray,
+    attenuation: Vec3,
+    throughput: Vec3,
+}
+
+pub struct Context<'a> {
+    settings: &'a RenderSettings,
+    cam: &'a Camera,
+    background: &'a Background,
+    world: &'a dyn Object,
+    lights: &'a dyn Object,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        settings: &'a RenderSettings,
+        cam: &'a Camera,
+        background: &'a Background,
+        world: &'a dyn Object,
+        lights: &'a dyn Object,
+    ) -> Self {
+        Self {
+            settings,
+            cam,
+            background,
+            world,
+            lights,
+        }
+    }
+
+    // follows the camera ray through specular bounces; returns the radiance found on the way,
+    // direct light at the diffuse surface it stops on included, and that surface
+    fn visible_point(
+        &self,
+        u: f64,
+        v: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (Vec3, Option<VisiblePoint<'a>>) {
+        let mut ray = self.cam.get_ray(u, v, sampler);
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut radiance = Vec3::zero();
+        for depth in 0..self.settings.max_depth {
+            let rec = match self.world.hit(ray, 0.001, INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    radiance += Vec3::elemul(throughput, self.background.radiance(ray.dir));
+                    break;
+                }
+            };
+            let mat = rec.mat.unwrap();
+            radiance += Vec3::elemul(throughput, mat.emitted(&rec, rec.u, rec.v, rec.p));
+            let s = mat.scatter(ray, &rec, sampler);
+            if !s.jud {
+                break;
+            }
+            if !s.is_specular {
+                let (le, f) = sample_light(ray, &rec, &s, self.lights, self.world, None, sampler);
+                radiance += Vec3::elemul(throughput, Vec3::elemul(le, f));
+                let vp = VisiblePoint {
+                    rec,
+                    r_in: ray,
+                    attenuation: s.attenustion,
+                    throughput,
+                };
+                return (radiance, Some(vp));
+            }
+            throughput = Vec3::elemul(throughput, s.attenustion);
+            match russian_roulette(self.settings, depth, throughput, sampler) {
+                Some(p) => throughput = throughput / p,
+                None => break,
+            }
+            ray = s.scattered;
+        }
+        (radiance, None)
+    }
+
+    // one sample of the camera ray through (u, v) against a fixed photon map
+    pub fn radiance(&self, u: f64, v: f64, map: &PhotonMap, sampler: &mut dyn Sampler) -> Vec3 {
+        let (mut radiance, vp) = self.visible_point(u, v, sampler);
+        if let Some(vp) = vp {
+            let r2 = self.settings.photon_radius * self.settings.photon_radius;
+            let (phi, _) = map.gather(&vp, r2);
+            radiance += phi / (map.emitted as f64 * PI * r2);
+        }
+        radiance
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SppmPixel {
+    r2: f64,
+    // photons accounted for so far, a fraction ALPHA of every pass
+    n: f64,
+    tau: Vec3,
+    direct: Vec3,
+}
+
+// stochastic progressive photon mapping; every sample per pixel is one pass of a fresh
+// photon map and a new camera ray per pixel, after which each pixel shrinks its radius
+pub fn render_sppm(
+    settings: &RenderSettings,
+    sampling: &Sampling,
+    cam: Camera,
+    background: Background,
+    world: Arc<dyn Object>,
+    lights: Arc<dyn Object>,
+    bar: &ProgressBar,
+) -> Samples {
+    let width = settings.image_width as usize;
+    let pixel_count = width * settings.image_height as usize;
+    let mut faults = vec![Faults::default(); pixel_count];
+    let mut pixels = vec![
+        SppmPixel {
+            r2: settings.photon_radius * settings.photon_radius,
+            n: 0.0,
+            tau: Vec3::zero(),
+            direct: Vec3::zero(),
+        };
+        pixel_count
+    ];
+    let passes = settings.samples_per_pixel;
+    for pass in 0..passes {
+        let map = Arc::new(shoot(
+            settings,
+            sampling,
+            cam,
+            &world,
+            &lights,
+            settings.photons,
+            pass,
+        ));
+        let radii: Arc<Vec<f64>> = Arc::new(pixels.iter().map(|p| p.r2).collect());
+        let settings = *settings;
+        let world = world.clone();
+        let lights = lights.clone();
+        let background = background.clone();
+        let sampling = sampling.clone();
+        run_tiles(
+            &settings,
+            move || sampling.sampler(),
+            move |sampler: &mut Box<dyn Sampler>, tile| {
+                let ctx = Context::new(&settings, &cam, &background, &*world, &*lights);
+                let mut res = Vec::new();
+                for j in tile.y0..tile.y1 {
+                    for i in tile.x0..tile.x1 {
+                        diagnostics::begin();
+                        sampler.start((i, j), pass);
+                        let (u, v) = pixel_uv(&settings, i, j, &mut **sampler);
+                        let (direct, vp) = ctx.visible_point(u, v, &mut **sampler);
+                        let (phi, m) = match vp {
+                            Some(vp) => map.gather(&vp, radii[j as usize * width + i as usize]),
+                            None => (Vec3::zero(), 0),
+                        };
+                        let bad = [direct, phi]
+                            .iter()
+                            .find_map(|&c| diagnostics::check(c).map(|fault| (fault, c)));
+                        if let Some((fault, c)) = bad {
+                            diagnostics::report((i as usize, j as usize), fault, c);
+                        }
+                        res.push((direct, phi, m, bad.map(|b| b.0)));
+                    }
+                }
+                res
+            },
+            |tile, res| {
+                let tile_width = (tile.x1 - tile.x0) as usize;
+                for (k, (direct, phi, m, fault)) in res.into_iter().enumerate() {
+                    let i = tile.x0 as usize + k % tile_width;
+                    let j = tile.y0 as usize + k / tile_width;
+                    // a bad pass leaves the pixel as it was
+                    if let Some(fault) = fault {
+                        faults[j * width + i].add(fault);
+                        continue;
+                    }
+                    let px = &mut pixels[j * width + i];
+                    px.direct += direct;
+                    if m > 0 {
+                        let m = m as f64;
+                        let n = px.n + ALPHA * m;
+                        let r2 = px.r2 * n / (px.n + m);
+                        px.tau = (px.tau + phi) * (r2 / px.r2);
+                        px.n = n;
+                        px.r2 = r2;
+                    }
+                }
+                bar.inc(((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as u64);
+            },
+        );
+    }
+
+    // render returns sums over samples, a pass counts as one sample of weight one
+    let emitted = passes as f64 * settings.photons as f64;
+    let sum: Vec<Vec3> = pixels
+        .iter()
+        .map(|px| px.direct + px.tau * (passes as f64 / (emitted * PI * px.r2)))
+        .collect();
+    Samples {
+        weight: vec![passes as f64; pixel_count],
+        sum_y: sum.iter().map(|&c| luminance(c)).collect(),
+        sum,
+        sum_sq: vec![0.0; pixel_count],
+        splat: vec![Vec3::zero(); pixel_count],
+        faults,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Pcg32;
+
+    #[test]
+    fn query_matches_brute_force() {
+        let mut rng = Pcg32::new(3, 0);
+        let mut points = Vec::new();
+        for k in 0..2000 {
+            // flattened on y and snapped to a grid on x, so the tree also splits on ties
+            let p = Vec3::new(
+                (rng.next_f64() * 20.0).floor() / 20.0,
+                rng.next_f64() * 0.1,
+                rng.next_f64(),
+            );
+            points.push(p);
+            // some photons land on the same spot
+            if k % 10 == 0 {
+                points.push(p);
+            }
+        }
+        let photons = points
+            .iter()
+            .enumerate()
+            .map(|(k, &p)| Photon {
+                p,
+                dir: Vec3::new(0.0, -1.0, 0.0),
+                normal: Vec3::new(0.0, 1.0, 0.0),
+                power: Vec3::new(k as f64, 0.0, 0.0),
+            })
+            .collect();
+        let map = PhotonMap::new(photons, points.len());
+        for _ in 0..200 {
+            let p = Vec3::new(
+                rng.next_f64() * 1.2 - 0.1,
+                rng.next_f64() * 0.2 - 0.05,
+                rng.next_f64() * 1.2 - 0.1,
+            );
+            let r = rng.next_f64() * 0.2;
+            let mut found = Vec::new();
+            map.query(0, map.photons.len(), p, r * r, &mut |ph: &Photon| {
+                found.push(ph.power.x as usize)
+            });
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&k| (points[k] - p).length_squared() <= r * r)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -4,11 +4,18 @@ use crate::Vec3;
 pub struct Ray {
     pub beg: Vec3,
     pub dir: Vec3,
+    // the moment within the camera's shutter the ray travels at, moving objects are hit where
+    // they are then
+    pub time: f64,
 }
 
 impl Ray {
-    pub fn new(p: Vec3, d: Vec3) -> Self {
-        Self { beg: p, dir: d }
+    pub fn new(p: Vec3, d: Vec3, time: f64) -> Self {
+        Self {
+            beg: p,
+            dir: d,
+            time,
+        }
     }
 
     pub fn at(self, t: f64) -> Vec3 {
@@ -0,0 +1,572 @@
+use crate::bdpt;
+use crate::diagnostics::{self, Faults};
+use crate::environment::Background;
+use crate::filter::PixelFilter;
+use crate::integrator::{ray_color, Heuristic, Integrator};
+use crate::photon;
+use crate::sampler::{Sampler, Sampling};
+use crate::spectrum;
+use crate::tonemap::luminance;
+use crate::Camera;
+use crate::Object;
+use crate::Vec3;
+use indicatif::ProgressBar;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: i32,
+    // bounces before russian roulette may terminate a path
+    pub rr_depth: i32,
+    pub mis: Heuristic,
+    pub integrator: Integrator,
+    // photons per photon map, one map per pass for sppm
+    pub photons: usize,
+    // gather radius of the photon map, the starting radius for sppm
+    pub photon_radius: f64,
+    pub threads: usize,
+    pub tile_size: u32,
+    // spreads every camera sample over the pixels around it
+    pub filter: PixelFilter,
+    // the path integrator traces wavelengths and samples come back as CIE XYZ
+    pub spectral: bool,
+}
+
+impl RenderSettings {
+    // a small path traced render with one sample per pixel and the box filter, for tests to
+    // change field by field
+    #[cfg(test)]
+    pub fn test(image_width: u32, image_height: u32, integrator: Integrator) -> Self {
+        Self {
+            image_width,
+            image_height,
+            samples_per_pixel: 1,
+            max_depth: 5,
+            rr_depth: 3,
+            mis: Heuristic::Power,
+            integrator,
+            photons: 0,
+            photon_radius: 0.0,
+            threads: 2,
+            tile_size: 4,
+            filter: PixelFilter::new(crate::filter::Filter::Box, None),
+            spectral: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+fn make_tiles(settings: &RenderSettings) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    let mut y0 = 0;
+    while y0 < settings.image_height {
+        let y1 = (y0 + settings.tile_size).min(settings.image_height);
+        let mut x0 = 0;
+        while x0 < settings.image_width {
+            let x1 = (x0 + settings.tile_size).min(settings.image_width);
+            tiles.push(Tile { x0, y0, x1, y1 });
+            x0 = x1;
+        }
+        y0 = y1;
+    }
+    tiles
+}
+
+// per-pixel sums of a render, row by row from the top
+pub struct Samples {
+    // filter weighted radiance of the samples near each pixel, and the sum of their weights
+    pub sum: Vec<Vec3>,
+    pub weight: Vec<f64>,
+    // luminance and squared luminance of the pixel's own samples, for variance estimates
+    pub sum_y: Vec<f64>,
+    pub sum_sq: Vec<f64>,
+    // light paths bdpt connected to the camera; they land on arbitrary pixels, so they are
+    // averaged over the samples of the whole image rather than those of their pixel
+    pub splat: Vec<Vec3>,
+    // samples left out for being NaN, infinite or negative
+    pub faults: Vec<Faults>,
+}
+
+// splats are summed in fixed point, units of 2^-32, as integer sums come out the same
+// whichever order the workers add in while floating point ones do not
+const SPLAT_SCALE: f64 = 4_294_967_296.0;
+
+// contributions of light paths that bdpt connects to the camera, one buffer per worker
+pub struct Splats {
+    width: usize,
+    sum: Vec<[i64; 3]>,
+    pub faults: Vec<Faults>,
+}
+
+impl Splats {
+    pub fn new(width: usize, pixel_count: usize) -> Self {
+        Self {
+            width,
+            sum: vec![[0; 3]; pixel_count],
+            faults: vec![Faults::default(); pixel_count],
+        }
+    }
+
+    // adds to pixel k, bad contributions are counted instead
+    pub fn add(&mut self, k: usize, c: Vec3) {
+        match diagnostics::check(c) {
+            None => {
+                for (s, x) in self.sum[k].iter_mut().zip(&[c.x, c.y, c.z]) {
+                    *s = s.saturating_add((x * SPLAT_SCALE).round() as i64);
+                }
+            }
+            Some(fault) => {
+                self.faults[k].add(fault);
+                diagnostics::report((k % self.width, k / self.width), fault, c);
+            }
+        }
+    }
+}
+
+// (u, v) for a camera ray through pixel (i, j), counted from the top left, at (jx, jy) in
+// [0, 1) from the bottom left corner of the pixel
+pub fn film_uv(settings: &RenderSettings, i: u32, j: u32, jx: f64, jy: f64) -> (f64, f64) {
+    let image_width = settings.image_width as f64;
+    let image_height = settings.image_height as f64;
+    let u = (i as f64 + jx) / (image_width - 1.0);
+    let v = (image_height - j as f64 + jy) / (image_height - 1.0);
+    (u, v)
+}
+
+// jittered (u, v) for a camera ray through pixel (i, j), the first two sample dimensions
+pub fn pixel_uv(
+    settings: &RenderSettings,
+    i: u32,
+    j: u32,
+    sampler: &mut dyn Sampler,
+) -> (f64, f64) {
+    let (jx, jy) = sampler.get_2d();
+    film_uv(settings, i, j, jx, jy)
+}
+
+// the samples one render takes: counts[k] of pixel k, numbered on from first[k] so the
+// sequences of the sampler carry on over passes
+pub struct Pass {
+    pub counts: Vec<u32>,
+    pub first: Vec<u32>,
+    pub sampling: Sampling,
+    // passes rendered before this one, resumed renders included; each shoots its own photons
+    pub round: u32,
+}
+
+// hands the tiles out to settings.threads workers, each keeping a state made by init;
+// results reach `done` on the calling thread in tile order, whichever worker finishes
+// first, and the states of all workers are returned at the end
+pub fn run_tiles<S, R, I, W, D>(settings: &RenderSettings, init: I, work: W, mut done: D) -> Vec<S>
+where
+    S: Send + 'static,
+    R: Send + 'static,
+    I: Fn() -> S + Send + Sync + 'static,
+    W: Fn(&mut S, Tile) -> R + Send + Sync + 'static,
+    D: FnMut(Tile, R),
+{
+    let tiles = Arc::new(make_tiles(settings));
+    let next = Arc::new(AtomicUsize::new(0));
+    let init = Arc::new(init);
+    let work = Arc::new(work);
+    let (tx, rx) = mpsc::channel();
+
+    let mut workers = Vec::new();
+    for _ in 0..settings.threads.max(1) {
+        let tiles = tiles.clone();
+        let next = next.clone();
+        let init = init.clone();
+        let work = work.clone();
+        let tx = tx.clone();
+        workers.push(thread::spawn(move || {
+            let mut state = init();
+            loop {
+                let k = next.fetch_add(1, Ordering::SeqCst);
+                if k >= tiles.len() {
+                    break;
+                }
+                let res = work(&mut state, tiles[k]);
+                tx.send((k, res)).unwrap();
+            }
+            state
+        }));
+    }
+    drop(tx);
+
+    // results that arrived ahead of an earlier tile
+    let mut early = Vec::new();
+    early.resize_with(tiles.len(), || None);
+    let mut first = 0;
+    for (k, res) in rx {
+        early[k] = Some(res);
+        while first < tiles.len() {
+            match early[first].take() {
+                Some(res) => done(tiles[first], res),
+                None => break,
+            }
+            first += 1;
+        }
+    }
+    workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap())
+        .collect()
+}
+
+// what a tile adds to the image: filtered sums over the tile grown by the filter's reach,
+// which overlap the neighbouring tiles, and the statistics of each pixel's own samples
+struct TileFilm {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    sum: Vec<Vec3>,
+    weight: Vec<f64>,
+    // luminance, squared luminance and faults, over the tile itself
+    pixels: Vec<(f64, f64, Faults)>,
+}
+
+// takes the samples `pass` asks of each pixel of the tile from `sample` at jittered (u, v) and
+// splats them through the filter; bad samples are counted and left out, so they add nothing
+fn render_tile<F>(
+    settings: &RenderSettings,
+    tile: Tile,
+    splat: &mut Splats,
+    sampler: &mut dyn Sampler,
+    pass: &Pass,
+    mut sample: F,
+) -> TileFilm
+where
+    F: FnMut(f64, f64, &mut Splats, &mut dyn Sampler) -> Vec3,
+{
+    let filter = settings.filter;
+    let margin = filter.margin();
+    let x0 = tile.x0.saturating_sub(margin);
+    let y0 = tile.y0.saturating_sub(margin);
+    let x1 = (tile.x1 + margin).min(settings.image_width);
+    let y1 = (tile.y1 + margin).min(settings.image_height);
+    let film_width = (x1 - x0) as usize;
+    let film_len = film_width * (y1 - y0) as usize;
+    let mut film = TileFilm {
+        x0,
+        y0,
+        x1,
+        sum: vec![Vec3::zero(); film_len],
+        weight: vec![0.0; film_len],
+        pixels: Vec::with_capacity(((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as usize),
+    };
+    // filter weights along each axis for the pixels a sample reaches
+    let mut wx = vec![0.0; 2 * margin as usize + 1];
+    let mut wy = wx.clone();
+    for j in tile.y0..tile.y1 {
+        for i in tile.x0..tile.x1 {
+            let mut sum_y = 0.0;
+            let mut sq = 0.0;
+            let mut faults = Faults::default();
+            let k = j as usize * settings.image_width as usize + i as usize;
+            for s in 0..pass.counts[k] {
+                diagnostics::begin();
+                sampler.start((i, j), pass.first[k] + s);
+                let (jx, jy) = sampler.get_2d();
+                let (film_u, film_v) = film_uv(settings, i, j, jx, jy);
+                let color = sample(film_u, film_v, splat, sampler);
+                match diagnostics::check(color) {
+                    None => {
+                        let lum = if settings.spectral {
+                            color.y
+                        } else {
+                            luminance(color)
+                        };
+                        sum_y += lum;
+                        sq += lum * lum;
+                        // offsets of the sample from the pixel center, y pointing down; the
+                        // filter's support is half open, and jitter 0 lies on the left and
+                        // the bottom border, so x is taken from the sample to the pixel and y
+                        // the other way round to keep every sample of a box in its own pixel
+                        let (dx, dy) = (jx - 0.5, 0.5 - jy);
+                        for (tap, weight) in wx.iter_mut().enumerate() {
+                            *weight = filter.eval1(tap as f64 - margin as f64 - dx);
+                        }
+                        for (tap, weight) in wy.iter_mut().enumerate() {
+                            *weight = filter.eval1(dy - (tap as f64 - margin as f64));
+                        }
+                        for (row, &fy) in wy.iter().enumerate() {
+                            let py = (j + row as u32).wrapping_sub(margin);
+                            if py < y0 || py >= y1 || fy == 0.0 {
+                                continue;
+                            }
+                            for (col, &fx) in wx.iter().enumerate() {
+                                let px = (i + col as u32).wrapping_sub(margin);
+                                if px < x0 || px >= x1 || fx == 0.0 {
+                                    continue;
+                                }
+                                let at = (py - y0) as usize * film_width + (px - x0) as usize;
+                                film.sum[at] += color * (fx * fy);
+                                film.weight[at] += fx * fy;
+                            }
+                        }
+                    }
+                    Some(fault) => {
+                        faults.add(fault);
+                        diagnostics::report((i as usize, j as usize), fault, color);
+                    }
+                }
+            }
+            film.pixels.push((sum_y, sq, faults));
+        }
+    }
+    film
+}
+
+// renders the samples of `pass`, except for sppm which takes samples_per_pixel passes over
+// the whole image; `bar` advances by one per sample
+pub fn render(
+    settings: &RenderSettings,
+    cam: Camera,
+    background: Background,
+    world: Arc<dyn Object>,
+    lights: Option<Arc<dyn Object>>,
+    pass: Arc<Pass>,
+    bar: &ProgressBar,
+) -> Samples {
+    let width = settings.image_width as usize;
+    let pixel_count = width * settings.image_height as usize;
+    let photon_map = match settings.integrator {
+        Integrator::Sppm => {
+            return photon::render_sppm(
+                settings,
+                &pass.sampling,
+                cam,
+                background,
+                world,
+                lights.unwrap(),
+                bar,
+            )
+        }
+        Integrator::Photon => Some(Arc::new(photon::shoot(
+            settings,
+            &pass.sampling,
+            cam,
+            &world,
+            lights.as_ref().unwrap(),
+            settings.photons,
+            pass.round,
+        ))),
+        _ => None,
+    };
+
+    let mut sum = vec![Vec3::zero(); pixel_count];
+    let mut weight = vec![0.0; pixel_count];
+    let mut sum_y = vec![0.0; pixel_count];
+    let mut sum_sq = vec![0.0; pixel_count];
+    let mut faults = vec![Faults::default(); pixel_count];
+    let settings = *settings;
+    let counts = pass.clone();
+    let states = run_tiles(
+        &settings,
+        {
+            let pass = pass.clone();
+            move || {
+                let splat = match settings.integrator {
+                    Integrator::Bdpt => Splats::new(width, pixel_count),
+                    _ => Splats::new(width, 0),
+                };
+                (splat, pass.sampling.sampler())
+            }
+        },
+        move |(splat, sampler): &mut (Splats, Box<dyn Sampler>), tile| {
+            let world = &*world;
+            let lights = lights.as_deref();
+            let sampler = &mut **sampler;
+            match settings.integrator {
+                Integrator::Path => {
+                    render_tile(&settings, tile, splat, sampler, &pass, |u, v, _, s| {
+                        ray_color(
+                            cam.get_ray(u, v, s),
+                            &background,
+                            lights,
+                            world,
+                            &settings,
+                            s,
+                        )
+                    })
+                }
+                Integrator::Bdpt => {
+                    let ctx = bdpt::Context::new(&settings, &cam, &background, world, lights);
+                    render_tile(&settings, tile, splat, sampler, &pass, |u, v, splat, s| {
+                        ctx.sample(u, v, splat, s)
+                    })
+                }
+                Integrator::Photon | Integrator::Sppm => {
+                    let map = photon_map.as_deref().unwrap();
+                    let ctx =
+                        photon::Context::new(&settings, &cam, &background, world, lights.unwrap());
+                    render_tile(&settings, tile, splat, sampler, &pass, |u, v, _, s| {
+                        ctx.radiance(u, v, map, s)
+                    })
+                }
+            }
+        },
+        |tile, film| {
+            let film_width = (film.x1 - film.x0) as usize;
+            for (k, (col, w)) in film.sum.into_iter().zip(film.weight).enumerate() {
+                let p =
+                    (film.y0 as usize + k / film_width) * width + film.x0 as usize + k % film_width;
+                sum[p] += col;
+                weight[p] += w;
+            }
+            let tile_width = (tile.x1 - tile.x0) as usize;
+            let mut samples = 0;
+            for (k, (y, sq, f)) in film.pixels.into_iter().enumerate() {
+                let i = tile.x0 as usize + k % tile_width;
+                let j = tile.y0 as usize + k / tile_width;
+                sum_y[j * width + i] = y;
+                sum_sq[j * width + i] = sq;
+                faults[j * width + i] = f;
+                samples += counts.counts[j * width + i] as u64;
+            }
+            bar.inc(samples);
+        },
+    );
+    if settings.spectral {
+        for s in sum.iter_mut() {
+            *s = spectrum::xyz_to_rgb(*s);
+        }
+    }
+    let mut fixed = vec![[0i64; 3]; pixel_count];
+    for (s, _) in states {
+        for (col, c) in fixed.iter_mut().zip(s.sum) {
+            for (a, b) in col.iter_mut().zip(&c) {
+                *a = a.saturating_add(*b);
+            }
+        }
+        for (f, c) in faults.iter_mut().zip(s.faults) {
+            f.merge(c);
+        }
+    }
+    let splat = fixed
+        .iter()
+        .map(|c| Vec3::new(c[0] as f64, c[1] as f64, c[2] as f64) / SPLAT_SCALE)
+        .collect();
+    Samples {
+        sum,
+        weight,
+        sum_y,
+        sum_sq,
+        splat,
+        faults,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::sampler::SamplerKind;
+    use crate::{HittableList, Lambertian, SolidColor, Sphere};
+
+    fn settings(threads: usize) -> RenderSettings {
+        RenderSettings {
+            samples_per_pixel: 8,
+            rr_depth: 2,
+            threads,
+            tile_size: 3,
+            filter: PixelFilter::new(Filter::Gaussian, None),
+            ..RenderSettings::test(10, 7, Integrator::Path)
+        }
+    }
+
+    // a gray ball under a white sky, rendered with the given seed
+    fn image(settings: &RenderSettings, seed: u64) -> Vec<Vec3> {
+        let mut world = HittableList::new();
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, 0.0, -3.0),
+            1.0,
+            Lambertian::new(SolidColor::new(Vec3::new(0.5, 0.5, 0.5))),
+        )));
+        let cam = Camera::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            10.0 / 7.0,
+            0.0,
+            3.0,
+        );
+        let spp = settings.samples_per_pixel;
+        let pixel_count = (settings.image_width * settings.image_height) as usize;
+        let pass = Pass {
+            counts: vec![spp; pixel_count],
+            first: vec![0; pixel_count],
+            sampling: Sampling::new(SamplerKind::Independent, seed, spp),
+            round: 0,
+        };
+        let samples = render(
+            settings,
+            cam,
+            Background::Color(Vec3::new(1.0, 1.0, 1.0)),
+            Arc::new(world),
+            None,
+            Arc::new(pass),
+            &ProgressBar::hidden(),
+        );
+        samples
+            .sum
+            .iter()
+            .zip(&samples.weight)
+            .map(|(s, w)| *s / *w)
+            .collect()
+    }
+
+    #[test]
+    fn tiles_arrive_in_order_with_more_threads_than_tiles() {
+        let mut settings = settings(16);
+        settings.image_width = 5;
+        settings.image_height = 3;
+        settings.tile_size = 2;
+        let tiles = make_tiles(&settings);
+        assert_eq!(tiles.len(), 6);
+
+        // the later a tile, the sooner its worker finishes with it
+        let mut seen = Vec::new();
+        let states = run_tiles(
+            &settings,
+            || 0,
+            |count: &mut u32, tile: Tile| {
+                *count += 1;
+                let wait = 5 * (6 - tile.y0 / 2 * 3 - tile.x0 / 2) as u64;
+                thread::sleep(std::time::Duration::from_millis(wait));
+                (tile.x0, tile.y0)
+            },
+            |tile, at| {
+                assert_eq!(at, (tile.x0, tile.y0));
+                seen.push(tile);
+            },
+        );
+        assert_eq!(seen.len(), tiles.len());
+        for (a, b) in seen.iter().zip(&tiles) {
+            assert_eq!((a.x0, a.y0, a.x1, a.y1), (b.x0, b.y0, b.x1, b.y1));
+        }
+        assert_eq!(states.len(), 16);
+        assert_eq!(states.iter().sum::<u32>(), 6);
+    }
+
+    #[test]
+    fn a_seed_gives_the_same_image_on_any_thread_count() {
+        let one = image(&settings(1), 42);
+        assert_eq!(image(&settings(1), 42), one);
+        assert_eq!(image(&settings(5), 42), one);
+        assert_ne!(image(&settings(5), 43), one);
+    }
+}
@@ -0,0 +1,643 @@
+use std::sync::Arc;
+
+// where the sample values of the render path come from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub const NAMES: [&'static str; 5] =
+        ["independent", "stratified", "halton", "sobol", "blue_noise"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            "blue_noise" => Some(SamplerKind::BlueNoise),
+            _ => None,
+        }
+    }
+}
+
+// sample values in [0, 1), one dimension after the other; every pixel sample draws its
+// dimensions in the same order, the film position first, then the lens and the bounces, so a
+// sequence can stratify each dimension over the samples of a pixel
+pub trait Sampler: Send {
+    // begins sample `index` of pixel (i, j), dimensions count from the first again
+    fn start(&mut self, pixel: (u32, u32), index: u32);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+// hands out the same values every time, for tests that need a sample to land at a known spot
+#[cfg(test)]
+pub struct Fixed(pub f64, pub f64);
+
+#[cfg(test)]
+impl Sampler for Fixed {
+    fn start(&mut self, _pixel: (u32, u32), _index: u32) {}
+
+    fn get_1d(&mut self) -> f64 {
+        self.0
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.0, self.1)
+    }
+}
+
+// everything a worker needs to make its own sampler, built once per render
+#[derive(Clone)]
+pub struct Sampling {
+    kind: SamplerKind,
+    seed: u64,
+    // samples per pixel the stratified sampler divides each dimension into
+    spp: u32,
+    mask: Option<Arc<Vec<f64>>>,
+}
+
+impl Sampling {
+    pub fn new(kind: SamplerKind, seed: u64, spp: u32) -> Self {
+        Self {
+            kind,
+            seed,
+            spp: spp.max(1),
+            mask: if kind == SamplerKind::BlueNoise {
+                Some(Arc::new(blue_noise_mask()))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn sampler(&self) -> Box<dyn Sampler> {
+        let state = State::new(self.seed);
+        match self.kind {
+            SamplerKind::Independent => Box::new(Independent { state }),
+            SamplerKind::Stratified => {
+                let n = self.spp.max(1) as f64;
+                let nx = n.sqrt().ceil();
+                Box::new(Stratified {
+                    state,
+                    n: n as u32,
+                    nx: nx as u32,
+                    ny: (n / nx).ceil() as u32,
+                })
+            }
+            SamplerKind::Halton => Box::new(Halton { state }),
+            SamplerKind::Sobol => Box::new(Sobol {
+                state,
+                directions: sobol_directions(),
+            }),
+            SamplerKind::BlueNoise => Box::new(BlueNoise {
+                state,
+                directions: sobol_directions(),
+                mask: self.mask.clone().unwrap(),
+            }),
+        }
+    }
+}
+
+// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+pub fn hash(a: u64, b: u64) -> u64 {
+    mix(a ^ mix(b.wrapping_add(0x9e37_79b9_7f4a_7c15)))
+}
+
+// pcg32 (O'Neill 2014), small and fast with independent streams
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    // uniform in [0, 1) with all 53 bits of the mantissa
+    pub fn next_f64(&mut self) -> f64 {
+        let hi = self.next_u32() as u64;
+        let lo = self.next_u32() as u64;
+        ((hi << 21) | (lo >> 11)) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// the pixel sample a sampler is on; rng covers the dimensions a sequence runs out of
+struct State {
+    seed: u64,
+    pixel: (u32, u32),
+    // seed mixed with the pixel, so every pixel gets its own scrambling
+    pixel_seed: u64,
+    index: u32,
+    dim: u32,
+    rng: Pcg32,
+}
+
+impl State {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: (0, 0),
+            pixel_seed: seed,
+            index: 0,
+            dim: 0,
+            rng: Pcg32::new(seed, 0),
+        }
+    }
+
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.pixel = pixel;
+        self.pixel_seed = hash(hash(self.seed, pixel.0 as u64), pixel.1 as u64);
+        self.index = index;
+        self.dim = 0;
+        self.rng = Pcg32::new(hash(self.pixel_seed, index as u64), self.seed);
+    }
+
+    // the dimension about to be drawn
+    fn next_dim(&mut self) -> u32 {
+        self.dim += 1;
+        self.dim - 1
+    }
+}
+
+struct Independent {
+    state: State,
+}
+
+impl Sampler for Independent {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.state.rng.next_f64()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.state.rng.next_f64(), self.state.rng.next_f64())
+    }
+}
+
+// jittered strata, every n samples of a pixel visit the n strata of each dimension in a
+// fresh random order; 2d dimensions use an nx by ny grid of at least n cells
+struct Stratified {
+    state: State,
+    n: u32,
+    nx: u32,
+    ny: u32,
+}
+
+impl Sampler for Stratified {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let s = &mut self.state;
+        let dim = s.next_dim() as u64;
+        let round = (s.index / self.n) as u64;
+        let seed = hash(hash(s.pixel_seed, dim), round) as u32;
+        let stratum = permute(s.index % self.n, self.n, seed);
+        (stratum as f64 + s.rng.next_f64()) / self.n as f64
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let s = &mut self.state;
+        let cells = self.nx * self.ny;
+        let dim = s.next_dim() as u64;
+        let round = (s.index / cells) as u64;
+        let seed = hash(hash(s.pixel_seed, dim), round) as u32;
+        let cell = permute(s.index % cells, cells, seed);
+        (
+            ((cell % self.nx) as f64 + s.rng.next_f64()) / self.nx as f64,
+            ((cell / self.nx) as f64 + s.rng.next_f64()) / self.ny as f64,
+        )
+    }
+}
+
+// element i of a random permutation of 0..l picked by p (Kensler 2013)
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            return (i.wrapping_add(p)) % l;
+        }
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// the Halton sequence in the first 64 dimensions, the digits of each pixel shifted by their
+// own random amounts; independent values past that
+struct Halton {
+    state: State,
+}
+
+impl Sampler for Halton {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let s = &mut self.state;
+        let dim = s.next_dim();
+        match PRIMES.get(dim as usize) {
+            Some(&base) => radical_inverse(base, s.index, hash(s.pixel_seed, dim as u64)),
+            None => s.rng.next_f64(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// the digits of index in `base` mirrored around the point, each shifted mod base by an amount
+// picked by scramble
+fn radical_inverse(base: u32, index: u32, scramble: u64) -> f64 {
+    let b = base as u64;
+    let inv = 1.0 / base as f64;
+    let mut index = index as u64;
+    let mut factor = inv;
+    let mut ret = 0.0;
+    let mut k = 0;
+    // the digits past the last one of index are zeros, shifted they still matter
+    while factor > 1e-16 {
+        let digit = index % b;
+        index /= b;
+        let shift = hash(scramble, k) % b;
+        ret += ((digit + shift) % b) as f64 * factor;
+        factor *= inv;
+        k += 1;
+    }
+    ret.min(1.0 - f64::EPSILON / 2.0)
+}
+
+// 2d Sobol points, Owen scrambled by hashing and shuffled per dimension so that every pair of
+// dimensions gets its own independent copy (Burley 2020)
+struct Sobol {
+    state: State,
+    directions: [u32; 32],
+}
+
+impl Sampler for Sobol {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let s = &mut self.state;
+        let seed = hash(s.pixel_seed, s.next_dim() as u64);
+        sobol_2d(&self.directions, s.index, seed).0
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let s = &mut self.state;
+        let seed = hash(s.pixel_seed, s.next_dim() as u64);
+        sobol_2d(&self.directions, s.index, seed)
+    }
+}
+
+// direction numbers of the second Sobol dimension, from the primitive polynomial x + 1
+fn sobol_directions() -> [u32; 32] {
+    let mut v = [0; 32];
+    let mut m: u32 = 1;
+    for (k, v) in v.iter_mut().enumerate() {
+        *v = m << (31 - k);
+        m ^= m << 1;
+    }
+    v
+}
+
+fn laine_karras(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x
+}
+
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn sobol_2d(directions: &[u32; 32], index: u32, seed: u64) -> (f64, f64) {
+    let index = owen_scramble(index, seed as u32);
+    let x = index.reverse_bits();
+    let mut y = 0;
+    for (bit, v) in directions.iter().enumerate() {
+        if (index >> bit) & 1 == 1 {
+            y ^= v;
+        }
+    }
+    let scale = 1.0 / (1u64 << 32) as f64;
+    (
+        owen_scramble(x, mix(seed) as u32) as f64 * scale,
+        owen_scramble(y, (mix(seed) >> 32) as u32) as f64 * scale,
+    )
+}
+
+// the same Sobol points for every pixel, each dimension rotated toroidally by the value of a
+// blue noise mask at the pixel, so neighbouring pixels make errors of a different sign and
+// what is left of the noise sits at high frequencies (Heitz and Belcour 2019)
+struct BlueNoise {
+    state: State,
+    directions: [u32; 32],
+    mask: Arc<Vec<f64>>,
+}
+
+impl BlueNoise {
+    // the mask value at the pixel, the mask shifted around by `shift`
+    fn rotation(&self, shift: u64) -> f64 {
+        let (i, j) = self.state.pixel;
+        let col = (i as usize + (shift & 0xffff) as usize) % MASK_SIZE;
+        let row = (j as usize + ((shift >> 16) & 0xffff) as usize) % MASK_SIZE;
+        self.mask[row * MASK_SIZE + col]
+    }
+}
+
+impl Sampler for BlueNoise {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.get_2d().0
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let seed = hash(self.state.seed, self.state.next_dim() as u64);
+        let (x, y) = sobol_2d(&self.directions, self.state.index, seed);
+        let (rx, ry) = (self.rotation(mix(seed)), self.rotation(mix(seed) >> 32));
+        ((x + rx).fract(), (y + ry).fract())
+    }
+}
+
+const MASK_SIZE: usize = 64;
+
+// a tileable blue noise dither array made with the void-and-cluster method (Ulichney 1993):
+// pixels are ranked by repeatedly filling the largest gap in a growing point pattern, ranks
+// are scaled into [0, 1)
+fn blue_noise_mask() -> Vec<f64> {
+    const N: usize = MASK_SIZE;
+    const R: i64 = 6;
+    let len = N * N;
+    let sigma = 1.5;
+    let mut kernel = Vec::new();
+    for dy in -R..=R {
+        for dx in -R..=R {
+            let w = (-((dx * dx + dy * dy) as f64) / (2.0 * sigma * sigma)).exp();
+            kernel.push((dx, dy, w));
+        }
+    }
+    // energy is the gaussian weighted count of points around each pixel, wrapping around
+    let update = |energy: &mut Vec<f64>, p: usize, sign: f64| {
+        let (x, y) = ((p % N) as i64, (p / N) as i64);
+        for &(dx, dy, w) in &kernel {
+            let qx = (x + dx).rem_euclid(N as i64) as usize;
+            let qy = (y + dy).rem_euclid(N as i64) as usize;
+            energy[qy * N + qx] += sign * w;
+        }
+    };
+    // the point with the most energy, or the empty pixel with the least
+    let cluster = |on: &[bool], energy: &[f64]| {
+        (0..len)
+            .filter(|&p| on[p])
+            .fold(None, |best: Option<usize>, p| match best {
+                Some(b) if energy[b] >= energy[p] => Some(b),
+                _ => Some(p),
+            })
+            .unwrap()
+    };
+    let void = |on: &[bool], energy: &[f64]| {
+        (0..len)
+            .filter(|&p| !on[p])
+            .fold(None, |best: Option<usize>, p| match best {
+                Some(b) if energy[b] <= energy[p] => Some(b),
+                _ => Some(p),
+            })
+            .unwrap()
+    };
+
+    // a tenth of the pixels at random, then points moved from the tightest cluster into the
+    // largest void until they settle
+    let mut on = vec![false; len];
+    let mut energy = vec![0.0; len];
+    let initial = len / 10;
+    let mut rng = Pcg32::new(0, 0);
+    let mut placed = 0;
+    while placed < initial {
+        let p = rng.next_u32() as usize % len;
+        if !on[p] {
+            on[p] = true;
+            update(&mut energy, p, 1.0);
+            placed += 1;
+        }
+    }
+    loop {
+        let c = cluster(&on, &energy);
+        on[c] = false;
+        update(&mut energy, c, -1.0);
+        let v = void(&on, &energy);
+        on[v] = true;
+        update(&mut energy, v, 1.0);
+        if v == c {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; len];
+    // the initial points are ranked by taking the tightest clusters away
+    let (mut on1, mut energy1) = (on.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let c = cluster(&on1, &energy1);
+        on1[c] = false;
+        update(&mut energy1, c, -1.0);
+        rank[c] = r;
+    }
+    // and the rest by filling the largest voids
+    for r in initial..len {
+        let v = void(&on, &energy);
+        on[v] = true;
+        update(&mut energy, v, 1.0);
+        rank[v] = r;
+    }
+    rank.iter()
+        .map(|&r| (r as f64 + 0.5) / len as f64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the values of `samples` samples of a pixel, starting at sample `first`, one column per
+    // dimension; each sample draws a 2d, a 1d, a 2d and a 1d dimension
+    fn draw(kind: SamplerKind, spp: u32, first: u32, samples: u32) -> Vec<Vec<f64>> {
+        let mut sampler = Sampling::new(kind, 7, spp).sampler();
+        let mut columns = vec![Vec::new(); 6];
+        for index in first..first + samples {
+            sampler.start((5, 9), index);
+            let first_2d = sampler.get_2d();
+            let first_1d = sampler.get_1d();
+            let second_2d = sampler.get_2d();
+            let second_1d = sampler.get_1d();
+            let values = [
+                first_2d.0,
+                first_2d.1,
+                first_1d,
+                second_2d.0,
+                second_2d.1,
+                second_1d,
+            ];
+            for (column, &value) in columns.iter_mut().zip(values.iter()) {
+                assert!((0.0..1.0).contains(&value), "{:?} gave {}", kind, value);
+                column.push(value);
+            }
+        }
+        columns
+    }
+
+    // whether each of the n strata of [0, 1) holds exactly one of the n values
+    fn stratified(values: &[f64]) -> bool {
+        let n = values.len();
+        let mut strata: Vec<usize> = values.iter().map(|&v| (v * n as f64) as usize).collect();
+        strata.sort_unstable();
+        strata == (0..n).collect::<Vec<_>>()
+    }
+
+    // whether each cell of an nx by ny grid holds exactly one of the points
+    fn stratified_2d(x: &[f64], y: &[f64], nx: usize, ny: usize) -> bool {
+        let mut cells: Vec<usize> = x
+            .iter()
+            .zip(y)
+            .map(|(&x, &y)| (y * ny as f64) as usize * nx + (x * nx as f64) as usize)
+            .collect();
+        cells.sort_unstable();
+        cells == (0..nx * ny).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn values_are_in_range() {
+        for &kind in [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+            SamplerKind::BlueNoise,
+        ]
+        .iter()
+        {
+            draw(kind, 7, 0, 300);
+        }
+    }
+
+    #[test]
+    fn stratified_hits_every_stratum() {
+        for &first in [0, 16, 32].iter() {
+            let c = draw(SamplerKind::Stratified, 16, first, 16);
+            assert!(stratified(&c[2]) && stratified(&c[5]));
+            assert!(stratified_2d(&c[0], &c[1], 4, 4));
+            assert!(stratified_2d(&c[3], &c[4], 4, 4));
+        }
+    }
+
+    #[test]
+    fn halton_hits_every_stratum() {
+        // dimension k is stratified over the powers of the k-th prime
+        let c = draw(SamplerKind::Halton, 1, 0, 125);
+        for (&base, values) in PRIMES.iter().zip(&c) {
+            let mut n = base as usize;
+            while n * (base as usize) <= values.len() {
+                n *= base as usize;
+            }
+            assert!(stratified(&values[..n]), "base {}", base);
+        }
+    }
+
+    #[test]
+    fn sobol_hits_every_stratum() {
+        for &first in [0, 16, 32].iter() {
+            let c = draw(SamplerKind::Sobol, 16, first, 16);
+            assert!(c.iter().all(|values| stratified(values)));
+            assert!(stratified_2d(&c[0], &c[1], 4, 4));
+            assert!(stratified_2d(&c[3], &c[4], 4, 4));
+        }
+    }
+
+    #[test]
+    fn blue_noise_hits_every_stratum_up_to_its_rotation() {
+        // the sobol points turned around [0, 1) by the mask; if some turn stratifies them, so
+        // does one that puts a value on the edge of its stratum
+        for &first in [0, 16].iter() {
+            let c = draw(SamplerKind::BlueNoise, 16, first, 16);
+            for values in c.iter() {
+                assert!(values.iter().any(|&r| {
+                    let turned: Vec<f64> =
+                        values.iter().map(|&v| (v - r).rem_euclid(1.0)).collect();
+                    stratified(&turned)
+                }));
+            }
+        }
+    }
+}
@@ -0,0 +1,980 @@
+use crate::environment::{Background, Environment};
+use crate::json::{self, ParseError, Value};
+use crate::material::Dispersion;
+use crate::sky::Sky;
+use crate::spectrum;
+use crate::volume::{Grid, GridMedium};
+use crate::Box;
+use crate::BvhNode;
+use crate::Camera;
+use crate::CheckerTexture;
+use crate::ConstantMedium;
+use crate::Dielectric;
+use crate::DiffuseLight;
+use crate::FlipFace;
+use crate::HenyeyGreenstein;
+use crate::HittableList;
+use crate::Isotropic;
+use crate::Lambertian;
+use crate::Material;
+use crate::Metal;
+use crate::Motion;
+use crate::MovingSphere;
+use crate::Object;
+use crate::RotateY;
+use crate::SolidColor;
+use crate::Sphere;
+use crate::Texture;
+use crate::Translate;
+use crate::Vec3;
+use crate::XYRect;
+use crate::XZRect;
+use crate::YZRrect;
+use rand::rngs::StdRng;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct Scene {
+    pub world: Arc<dyn Object>,
+    pub lights: Option<Arc<dyn Object>>,
+    pub background: Background,
+    pub aspect_ratio: f64,
+    pub image_width: u32,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f64,
+    pub aperture: f64,
+    pub dist_to_focus: f64,
+    // when the shutter opens and closes
+    pub time0: f64,
+    pub time1: f64,
+    // whether the world holds participating media, which only the path integrator handles
+    pub media: bool,
+}
+
+impl Scene {
+    pub const NAMES: [&'static str; 5] = [
+        "random_scene",
+        "night",
+        "cornell_box",
+        "cornell_glass",
+        "cornell_smoke",
+    ];
+
+    // rng generates random_scene and night and picks where their bvh splits
+    pub fn builtin(name: &str, rng: &mut StdRng) -> Option<Self> {
+        match name {
+            "night" => Some(Self {
+                world: Arc::new(HittableList::night(rng)),
+                lights: None,
+                background: Background::Color(Vec3::new(0.0, 0.0, 0.0)),
+                aspect_ratio: 3.0 / 2.0,
+                image_width: 1600,
+                lookfrom: Vec3::new(13.0, 2.0, 3.0),
+                lookat: Vec3::new(0.0, 0.0, 0.0),
+                vup: Vec3::new(0.0, 1.0, 0.0),
+                vfov: 20.0,
+                aperture: 0.1,
+                dist_to_focus: 10.0,
+                time0: 0.0,
+                time1: 0.0,
+                media: false,
+            }),
+            "cornell_box" | "cornell_glass" | "cornell_smoke" => {
+                let (world, lights) = match name {
+                    "cornell_box" => HittableList::cornell_box(),
+                    "cornell_glass" => HittableList::cornell_glass(),
+                    _ => HittableList::cornell_smoke(),
+                };
+                Some(Self {
+                    world: Arc::new(world),
+                    lights: Some(Arc::new(lights)),
+                    background: Background::Color(Vec3::new(0.0, 0.0, 0.0)),
+                    aspect_ratio: 1.0,
+                    image_width: 200,
+                    lookfrom: Vec3::new(278.0, 278.0, -800.0),
+                    lookat: Vec3::new(278.0, 278.0, 0.0),
+                    vup: Vec3::new(0.0, 1.0, 0.0),
+                    vfov: 40.0,
+                    aperture: 0.0,
+                    dist_to_focus: 10.0,
+                    time0: 0.0,
+                    time1: 0.0,
+                    media: name == "cornell_smoke",
+                })
+            }
+            "random_scene" => Some(Self {
+                world: Arc::new(HittableList::random_scene(rng)),
+                lights: None,
+                background: Background::Color(Vec3::new(0.7, 0.8, 1.0)),
+                aspect_ratio: 3.0 / 2.0,
+                image_width: 200,
+                lookfrom: Vec3::new(13.0, 2.0, 3.0),
+                lookat: Vec3::new(0.0, 0.0, 0.0),
+                vup: Vec3::new(0.0, 1.0, 0.0),
+                vfov: 20.0,
+                aperture: 0.1,
+                dist_to_focus: 10.0,
+                time0: 0.0,
+                time1: 0.0,
+                media: false,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn image_height(&self) -> u32 {
+        ((self.image_width as f64) / self.aspect_ratio) as u32
+    }
+
+    pub fn cam(&self) -> Camera {
+        Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            self.aspect_ratio,
+            self.aperture,
+            self.dist_to_focus,
+        )
+        .with_shutter(self.time0, self.time1)
+    }
+}
+
+impl Scene {
+    pub fn load(path: &str, rng: &mut StdRng) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Scene::parse(&text, dir, rng).map_err(|e| format!("{}:{}: {}", path, e.line, e.msg))
+    }
+
+    // files the scene refers to are looked up relative to dir
+    pub fn parse(text: &str, dir: &Path, rng: &mut StdRng) -> Result<Self, ParseError> {
+        let root = json::parse(text)?;
+        check_fields(
+            &root,
+            "scene",
+            &[
+                "camera",
+                "background",
+                "environment",
+                "sky",
+                "bvh",
+                "textures",
+                "materials",
+                "objects",
+            ],
+        )?;
+        let cam = required(&root, "camera")?;
+        check_fields(
+            cam,
+            "camera",
+            &[
+                "lookfrom",
+                "lookat",
+                "vup",
+                "vfov",
+                "aperture",
+                "focus_dist",
+                "aspect_ratio",
+                "image_width",
+                "time0",
+                "time1",
+            ],
+        )?;
+        let time0 = number_or(cam, "time0", 0.0)?;
+        let time1 = number_or(cam, "time1", time0)?;
+        if time1 < time0 {
+            return Err(ParseError::new(
+                cam.get("time1").unwrap().line,
+                String::from("the shutter cannot close (time1) before it opens (time0)"),
+            ));
+        }
+        let mut loader = Loader {
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            media: Cell::new(false),
+            dir: dir.to_path_buf(),
+        };
+        if let Some(textures) = root.get("textures") {
+            for (name, v) in textures.as_object()? {
+                let tex = loader.texture(v)?;
+                loader.textures.insert(name.clone(), tex);
+            }
+        }
+        if let Some(materials) = root.get("materials") {
+            for (name, v) in materials.as_object()? {
+                let mat = loader.material(v)?;
+                loader.materials.insert(name.clone(), mat);
+            }
+        }
+
+        let mut world = HittableList::new();
+        let mut lights = HittableList::new();
+        for v in required(&root, "objects")?.as_array()? {
+            let obj = loader.object(v, true)?;
+            if let Some(light) = v.get("light") {
+                if light.as_bool()? {
+                    if !sampleable(v) {
+                        return Err(ParseError::new(
+                            light.line,
+                            format!(
+                                "`{}` cannot be sampled as a light, expected sphere, xy_rect, \
+                                 xz_rect, yz_rect or flip_face of one of them",
+                                type_name(v)?
+                            ),
+                        ));
+                    }
+                    lights.add(obj.clone());
+                }
+            }
+            world.add(obj);
+        }
+        if world.objects.is_empty() {
+            return Err(ParseError::new(
+                root.line,
+                String::from("the scene has no objects"),
+            ));
+        }
+
+        let world = world.tagged();
+        let bvh = match root.get("bvh") {
+            Some(v) => v.as_bool()?,
+            None => false,
+        };
+        let world: Arc<dyn Object> = if bvh {
+            if world.bounding_box(time0, time1).is_none() {
+                return Err(ParseError::new(
+                    root.line,
+                    String::from("\"bvh\" needs every object to have a bounding box"),
+                ));
+            }
+            Arc::new(BvhNode::new(world, time0, time1, rng))
+        } else {
+            Arc::new(world)
+        };
+
+        let image_width = number_or(cam, "image_width", 200.0)?;
+        if image_width < 1.0 || image_width.fract() != 0.0 {
+            return Err(ParseError::new(
+                cam.get("image_width").unwrap().line,
+                String::from("image_width must be a positive integer"),
+            ));
+        }
+        let aspect_ratio = number_or(cam, "aspect_ratio", 1.0)?;
+        if aspect_ratio <= 0.0 {
+            return Err(ParseError::new(
+                cam.get("aspect_ratio").unwrap().line,
+                String::from("aspect_ratio must be positive"),
+            ));
+        }
+
+        let mut given = ["background", "environment", "sky"]
+            .iter()
+            .filter_map(|&k| root.get(k).map(|v| (k, v)));
+        let background = match (given.next(), given.next()) {
+            (Some(_), Some((_, v))) => {
+                return Err(ParseError::new(
+                    v.line,
+                    String::from("a scene has only one of background, environment and sky"),
+                ))
+            }
+            (Some(("environment", v)), None) => Background::Map(Arc::new(loader.environment(v)?)),
+            (Some(("sky", v)), None) => Background::Sky(Arc::new(sky(v)?)),
+            (Some((_, v)), None) => Background::Color(vec3(v)?),
+            (None, _) => Background::Color(Vec3::zero()),
+        };
+
+        Ok(Self {
+            world,
+            lights: if lights.objects.is_empty() {
+                None
+            } else {
+                Some(Arc::new(lights))
+            },
+            background,
+            aspect_ratio,
+            image_width: image_width as u32,
+            lookfrom: vec3(required(cam, "lookfrom")?)?,
+            lookat: vec3(required(cam, "lookat")?)?,
+            vup: match cam.get("vup") {
+                Some(v) => vec3(v)?,
+                None => Vec3::new(0.0, 1.0, 0.0),
+            },
+            vfov: number(cam, "vfov")?,
+            aperture: number_or(cam, "aperture", 0.0)?,
+            dist_to_focus: number_or(cam, "focus_dist", 10.0)?,
+            time0,
+            time1,
+            media: loader.media.get(),
+        })
+    }
+}
+
+fn check_fields(v: &Value, what: &str, allowed: &[&str]) -> Result<(), ParseError> {
+    for (k, field) in v.as_object()? {
+        if !allowed.contains(&k.as_str()) {
+            return Err(ParseError::new(
+                field.line,
+                format!("unknown field `{}` in {}", k, what),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn required<'a>(v: &'a Value, key: &str) -> Result<&'a Value, ParseError> {
+    v.get(key)
+        .ok_or_else(|| ParseError::new(v.line, format!("missing field `{}`", key)))
+}
+
+fn number(v: &Value, key: &str) -> Result<f64, ParseError> {
+    required(v, key)?.as_f64()
+}
+
+fn number_or(v: &Value, key: &str, default: f64) -> Result<f64, ParseError> {
+    match v.get(key) {
+        Some(x) => x.as_f64(),
+        None => Ok(default),
+    }
+}
+
+fn vec3(v: &Value) -> Result<Vec3, ParseError> {
+    let a = v.as_array()?;
+    if a.len() != 3 {
+        return Err(ParseError::new(
+            v.line,
+            format!("expected an array of 3 numbers, found {} elements", a.len()),
+        ));
+    }
+    Ok(Vec3::new(a[0].as_f64()?, a[1].as_f64()?, a[2].as_f64()?))
+}
+
+fn sky(v: &Value) -> Result<Sky, ParseError> {
+    check_fields(
+        v,
+        "sky",
+        &[
+            "elevation",
+            "azimuth",
+            "turbidity",
+            "ground_albedo",
+            "intensity",
+        ],
+    )?;
+    Sky::new(
+        number(v, "elevation")?,
+        number_or(v, "azimuth", 0.0)?,
+        number_or(v, "turbidity", 3.0)?,
+        number_or(v, "ground_albedo", 0.3)?,
+        number_or(v, "intensity", 1.0)?,
+    )
+    .map_err(|e| ParseError::new(v.line, e))
+}
+
+// the times a moving object starts and stops, 0 and 1 unless given
+fn motion_times(v: &Value) -> Result<(f64, f64), ParseError> {
+    let time0 = number_or(v, "time0", 0.0)?;
+    let time1 = number_or(v, "time1", 1.0)?;
+    if time1 <= time0 {
+        return Err(ParseError::new(
+            v.line,
+            String::from("time1 must come after time0"),
+        ));
+    }
+    Ok((time0, time1))
+}
+
+fn type_name(v: &Value) -> Result<&str, ParseError> {
+    required(v, "type")?.as_str()
+}
+
+// whether the object implements pdf_value and random, so it can go into the light list
+fn sampleable(v: &Value) -> bool {
+    match v.get("type").and_then(|t| t.as_str().ok()) {
+        Some("sphere") | Some("xy_rect") | Some("xz_rect") | Some("yz_rect") => true,
+        Some("flip_face") => match v.get("object") {
+            Some(inner) => sampleable(inner),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+struct Loader {
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    // set once a constant_medium or grid_volume is loaded
+    media: Cell<bool>,
+    dir: PathBuf,
+}
+
+impl Loader {
+    fn environment(&self, v: &Value) -> Result<Environment, ParseError> {
+        check_fields(v, "environment", &["file", "intensity", "rotation"])?;
+        let file = required(v, "file")?;
+        let intensity = number_or(v, "intensity", 1.0)?;
+        if intensity < 0.0 {
+            return Err(ParseError::new(
+                required(v, "intensity")?.line,
+                String::from("intensity must not be negative"),
+            ));
+        }
+        Environment::load(
+            &self.dir.join(file.as_str()?),
+            intensity,
+            number_or(v, "rotation", 0.0)?,
+        )
+        .map_err(|e| ParseError::new(file.line, e))
+    }
+
+    // a texture is a color, the name of a texture or an inline definition
+    fn texture(&self, v: &Value) -> Result<Arc<dyn Texture>, ParseError> {
+        if let Ok(name) = v.as_str() {
+            return self
+                .textures
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ParseError::new(v.line, format!("unknown texture `{}`", name)));
+        }
+        if v.as_array().is_ok() {
+            return Ok(Arc::new(SolidColor::new(vec3(v)?)));
+        }
+        let ty = type_name(v)?;
+        match ty {
+            "solid" => {
+                check_fields(v, "solid texture", &["type", "color"])?;
+                Ok(Arc::new(SolidColor::new(vec3(required(v, "color")?)?)))
+            }
+            "checker" => {
+                check_fields(v, "checker texture", &["type", "odd", "even"])?;
+                Ok(Arc::new(CheckerTexture::new(
+                    self.texture(required(v, "odd")?)?,
+                    self.texture(required(v, "even")?)?,
+                )))
+            }
+            _ => Err(ParseError::new(
+                v.line,
+                format!("unknown texture type `{}`", ty),
+            )),
+        }
+    }
+
+    // a material is the name of a material or an inline definition
+    fn material(&self, v: &Value) -> Result<Arc<dyn Material>, ParseError> {
+        if let Ok(name) = v.as_str() {
+            return self
+                .materials
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ParseError::new(v.line, format!("unknown material `{}`", name)));
+        }
+        let ty = type_name(v)?;
+        match ty {
+            "lambertian" => {
+                check_fields(v, "lambertian", &["type", "albedo"])?;
+                Ok(Arc::new(Lambertian::new(
+                    self.texture(required(v, "albedo")?)?,
+                )))
+            }
+            "metal" => {
+                check_fields(v, "metal", &["type", "albedo", "fuzz"])?;
+                Ok(Arc::new(Metal::new(
+                    vec3(required(v, "albedo")?)?,
+                    number_or(v, "fuzz", 0.0)?,
+                )))
+            }
+            "dielectric" => {
+                check_fields(v, "dielectric", &["type", "ref_idx", "cauchy", "sellmeier"])?;
+                let (dispersion, coefficients) =
+                    match (v.get("ref_idx"), v.get("cauchy"), v.get("sellmeier")) {
+                        (Some(n), None, None) => return Ok(Arc::new(Dielectric::new(n.as_f64()?))),
+                        (None, Some(c), None) => {
+                            check_fields(c, "cauchy", &["a", "b"])?;
+                            (Dispersion::Cauchy(number(c, "a")?, number(c, "b")?), c)
+                        }
+                        (None, None, Some(s)) => {
+                            check_fields(s, "sellmeier", &["b", "c"])?;
+                            let b = vec3(required(s, "b")?)?;
+                            let c = vec3(required(s, "c")?)?;
+                            (Dispersion::Sellmeier([b.x, b.y, b.z], [c.x, c.y, c.z]), s)
+                        }
+                        _ => {
+                            return Err(ParseError::new(
+                                v.line,
+                                String::from(
+                                    "a dielectric takes exactly one of `ref_idx`, `cauchy` and \
+                                     `sellmeier`",
+                                ),
+                            ))
+                        }
+                    };
+                // spectral renders ask for the index anywhere in the range wavelengths are
+                // drawn from, every nm of which has to give a real index of at least one
+                let glass = Dielectric::with_dispersion(dispersion);
+                let lambda = spectrum::LAMBDA_MIN as u32..=spectrum::LAMBDA_MAX as u32;
+                for lambda in lambda.map(f64::from) {
+                    let n = glass.ior(lambda);
+                    if !(n >= 1.0 && n.is_finite()) {
+                        return Err(ParseError::new(
+                            coefficients.line,
+                            format!(
+                                "the coefficients give an index of refraction of {} at {} nm, \
+                                 expected a number of at least 1 from {} to {} nm",
+                                n,
+                                lambda,
+                                spectrum::LAMBDA_MIN,
+                                spectrum::LAMBDA_MAX
+                            ),
+                        ));
+                    }
+                }
+                Ok(Arc::new(glass))
+            }
+            "isotropic" => {
+                check_fields(v, "isotropic", &["type", "albedo"])?;
+                Ok(Arc::new(Isotropic::new(
+                    self.texture(required(v, "albedo")?)?,
+                )))
+            }
+            "henyey_greenstein" => {
+                check_fields(v, "henyey_greenstein", &["type", "albedo", "g"])?;
+                let g = number(v, "g")?;
+                if g <= -1.0 || g >= 1.0 {
+                    return Err(ParseError::new(
+                        required(v, "g")?.line,
+                        String::from("g must lie between -1 and 1"),
+                    ));
+                }
+                Ok(Arc::new(HenyeyGreenstein::new(
+                    self.texture(required(v, "albedo")?)?,
+                    g,
+                )))
+            }
+            "diffuse_light" => {
+                check_fields(v, "diffuse_light", &["type", "emit"])?;
+                Ok(Arc::new(DiffuseLight::new(
+                    self.texture(required(v, "emit")?)?,
+                )))
+            }
+            _ => Err(ParseError::new(
+                v.line,
+                format!("unknown material type `{}`", ty),
+            )),
+        }
+    }
+
+    // only objects at the top level of the scene may be flagged as lights
+    fn object(&self, v: &Value, top: bool) -> Result<Arc<dyn Object>, ParseError> {
+        let ty = type_name(v)?;
+        let mat = |v: &Value| self.material(required(v, "material")?);
+        let fields = |fields: &[&str]| -> Result<(), ParseError> {
+            let mut allowed = vec!["type"];
+            allowed.extend_from_slice(fields);
+            if top {
+                allowed.push("light");
+            }
+            check_fields(v, ty, &allowed)
+        };
+        match ty {
+            "sphere" => {
+                fields(&["center", "radius", "material"])?;
+                Ok(Arc::new(Sphere::new(
+                    vec3(required(v, "center")?)?,
+                    number(v, "radius")?,
+                    mat(v)?,
+                )))
+            }
+            "xy_rect" => {
+                fields(&["x0", "x1", "y0", "y1", "k", "material"])?;
+                Ok(Arc::new(XYRect::new(
+                    number(v, "x0")?,
+                    number(v, "x1")?,
+                    number(v, "y0")?,
+                    number(v, "y1")?,
+                    number(v, "k")?,
+                    mat(v)?,
+                )))
+            }
+            "xz_rect" => {
+                fields(&["x0", "x1", "z0", "z1", "k", "material"])?;
+                Ok(Arc::new(XZRect::new(
+                    number(v, "x0")?,
+                    number(v, "x1")?,
+                    number(v, "z0")?,
+                    number(v, "z1")?,
+                    number(v, "k")?,
+                    mat(v)?,
+                )))
+            }
+            "yz_rect" => {
+                fields(&["y0", "y1", "z0", "z1", "k", "material"])?;
+                Ok(Arc::new(YZRrect::new(
+                    number(v, "y0")?,
+                    number(v, "y1")?,
+                    number(v, "z0")?,
+                    number(v, "z1")?,
+                    number(v, "k")?,
+                    mat(v)?,
+                )))
+            }
+            "moving_sphere" => {
+                fields(&["center0", "center1", "time0", "time1", "radius", "material"])?;
+                let (time0, time1) = motion_times(v)?;
+                Ok(Arc::new(MovingSphere::new(
+                    vec3(required(v, "center0")?)?,
+                    vec3(required(v, "center1")?)?,
+                    time0,
+                    time1,
+                    number(v, "radius")?,
+                    mat(v)?,
+                )))
+            }
+            "box" => {
+                fields(&["min", "max", "material"])?;
+                Ok(Arc::new(Box::new(
+                    vec3(required(v, "min")?)?,
+                    vec3(required(v, "max")?)?,
+                    mat(v)?,
+                )))
+            }
+            "translate" => {
+                fields(&["offset", "object"])?;
+                Ok(Arc::new(Translate::new(
+                    self.object(required(v, "object")?, false)?,
+                    vec3(required(v, "offset")?)?,
+                )))
+            }
+            "rotate_y" => {
+                fields(&["angle", "object"])?;
+                Ok(Arc::new(RotateY::new(
+                    self.object(required(v, "object")?, false)?,
+                    number(v, "angle")?,
+                )))
+            }
+            "motion" => {
+                fields(&[
+                    "object", "time0", "time1", "offset0", "offset1", "angle0", "angle1",
+                ])?;
+                let (time0, time1) = motion_times(v)?;
+                let vec3_or_zero = |key: &str| match v.get(key) {
+                    Some(x) => vec3(x),
+                    None => Ok(Vec3::zero()),
+                };
+                Ok(Arc::new(Motion::new(
+                    self.object(required(v, "object")?, false)?,
+                    time0,
+                    time1,
+                    vec3_or_zero("offset0")?,
+                    vec3_or_zero("offset1")?,
+                    number_or(v, "angle0", 0.0)?,
+                    number_or(v, "angle1", 0.0)?,
+                )))
+            }
+            "flip_face" => {
+                fields(&["object"])?;
+                Ok(Arc::new(FlipFace::new(
+                    self.object(required(v, "object")?, false)?,
+                )))
+            }
+            "constant_medium" => {
+                fields(&["boundary", "density", "material"])?;
+                let density = number(v, "density")?;
+                if density <= 0.0 {
+                    return Err(ParseError::new(
+                        required(v, "density")?.line,
+                        String::from("density must be positive"),
+                    ));
+                }
+                self.media.set(true);
+                Ok(Arc::new(ConstantMedium::new(
+                    self.object(required(v, "boundary")?, false)?,
+                    density,
+                    mat(v)?,
+                )))
+            }
+            "grid_volume" => {
+                fields(&["file", "min", "max", "density", "emit", "material"])?;
+                let file = required(v, "file")?;
+                let grid = Grid::load(&self.dir.join(file.as_str()?))
+                    .map_err(|e| ParseError::new(file.line, e))?;
+                let min = vec3(required(v, "min")?)?;
+                let max = vec3(required(v, "max")?)?;
+                if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+                    return Err(ParseError::new(
+                        v.line,
+                        String::from("min must be below max on every axis"),
+                    ));
+                }
+                let scale = number_or(v, "density", 1.0)?;
+                if scale <= 0.0 {
+                    return Err(ParseError::new(
+                        required(v, "density")?.line,
+                        String::from("density must be positive"),
+                    ));
+                }
+                let emit = match v.get("emit") {
+                    Some(e) if !grid.has_emission() => {
+                        return Err(ParseError::new(
+                            e.line,
+                            String::from("emit needs a grid with an emission channel"),
+                        ))
+                    }
+                    Some(e) => vec3(e)?,
+                    None => Vec3::zero(),
+                };
+                self.media.set(true);
+                Ok(Arc::new(GridMedium::new(
+                    grid,
+                    min,
+                    max,
+                    scale,
+                    emit,
+                    mat(v)?,
+                )))
+            }
+            _ => Err(ParseError::new(
+                v.line,
+                format!("unknown object type `{}`", ty),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ray;
+    use rand::SeedableRng;
+
+    // a scene file with one line per entry, so errors can be checked against their line
+    fn parse(lines: &[&str]) -> Result<Scene, ParseError> {
+        let mut rng = StdRng::seed_from_u64(0);
+        Scene::parse(&lines.join("\n"), Path::new(""), &mut rng)
+    }
+
+    fn error(lines: &[&str]) -> (usize, String) {
+        match parse(lines) {
+            Err(e) => (e.line, e.msg),
+            Ok(_) => panic!("the scene loaded"),
+        }
+    }
+
+    const CAMERA: &str =
+        r#"  "camera": { "lookfrom": [0, 0, 5], "lookat": [0, 0, 0], "vfov": 40 },"#;
+
+    #[test]
+    fn a_lit_sphere() {
+        let scene = parse(&[
+            "{",
+            CAMERA,
+            r#"  "background": [0.1, 0.2, 0.3],"#,
+            r#"  "materials": { "gray": { "type": "lambertian", "albedo": [0.5, 0.5, 0.5] } },"#,
+            r#"  "objects": ["#,
+            r#"    { "type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "gray" },"#,
+            r#"    { "type": "xz_rect", "x0": -1, "x1": 1, "z0": -1, "z1": 1, "k": 3,"#,
+            r#"      "material": { "type": "diffuse_light", "emit": [4, 4, 4] }, "light": true }"#,
+            "  ]",
+            "}",
+        ])
+        .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(scene.lookfrom, Vec3::new(0.0, 0.0, 5.0));
+        assert_eq!((scene.image_width, scene.media), (200, false));
+        match scene.background {
+            Background::Color(c) => assert_eq!(c, Vec3::new(0.1, 0.2, 0.3)),
+            _ => panic!("the background is not a color"),
+        }
+
+        // the world holds both objects, numbered in order, and the lights only the rect
+        let towards_sphere = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let up = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let rec = scene
+            .world
+            .hit(towards_sphere, 0.001, crate::INFINITY)
+            .unwrap();
+        assert_eq!((rec.id, rec.p), (1, Vec3::new(0.0, 0.0, 1.0)));
+        let rec = scene.world.hit(up, 0.001, crate::INFINITY).unwrap();
+        assert_eq!((rec.id, rec.p), (2, Vec3::new(0.0, 3.0, 0.0)));
+        let lights = scene.lights.unwrap();
+        assert!(lights.hit(towards_sphere, 0.001, crate::INFINITY).is_none());
+        assert!(lights.hit(up, 0.001, crate::INFINITY).is_some());
+        assert!(lights.pdf_value(up.beg, up.dir) > 0.0);
+    }
+
+    #[test]
+    fn unknown_fields_point_at_their_line() {
+        let sphere =
+            r#"    { "type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "gray" }"#;
+        let materials =
+            r#"  "materials": { "gray": { "type": "lambertian", "albedo": [1, 1, 1] } },"#;
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                materials,
+                r#"  "fog": true,"#,
+                r#"  "objects": ["#,
+                sphere,
+                "  ]",
+                "}",
+            ]),
+            (4, String::from("unknown field `fog` in scene"))
+        );
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                materials,
+                r#"  "objects": ["#,
+                &format!("{},", sphere),
+                r#"    { "type": "sphere", "center": [0, 0, 0],"#,
+                r#"      "radius": 1, "spin": 2, "material": "gray" }"#,
+                "  ]",
+                "}",
+            ]),
+            (7, String::from("unknown field `spin` in sphere"))
+        );
+    }
+
+    #[test]
+    fn unknown_names_point_at_their_line() {
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                r#"  "materials": {"#,
+                r#"    "floor": { "type": "lambertian", "albedo": "marble" }"#,
+                "  },",
+                r#"  "objects": []"#,
+                "}",
+            ]),
+            (4, String::from("unknown texture `marble`"))
+        );
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                r#"  "objects": ["#,
+                r#"    { "type": "sphere", "center": [0, 0, 0], "radius": 1,"#,
+                r#"      "material": "chrome" }"#,
+                "  ]",
+                "}",
+            ]),
+            (5, String::from("unknown material `chrome`"))
+        );
+    }
+
+    #[test]
+    fn only_sampleable_objects_can_be_lights() {
+        let (line, msg) = error(&[
+            "{",
+            CAMERA,
+            r#"  "objects": ["#,
+            r#"    { "type": "box", "min": [0, 0, 0], "max": [1, 1, 1],"#,
+            r#"      "material": { "type": "diffuse_light", "emit": [1, 1, 1] },"#,
+            r#"      "light": true }"#,
+            "  ]",
+            "}",
+        ]);
+        assert_eq!(line, 6);
+        assert!(
+            msg.starts_with("`box` cannot be sampled as a light"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn a_camera_is_required() {
+        assert_eq!(
+            error(&["{", r#"  "objects": []"#, "}"]),
+            (1, String::from("missing field `camera`"))
+        );
+    }
+
+    #[test]
+    fn only_one_background() {
+        let sphere = r#"    { "type": "sphere", "center": [0, 0, 0], "radius": 1,"#;
+        let gray = r#"      "material": { "type": "lambertian", "albedo": [1, 1, 1] } }"#;
+        let msg = String::from("a scene has only one of background, environment and sky");
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                r#"  "background": [1, 1, 1],"#,
+                r#"  "sky": { "elevation": 30 },"#,
+                r#"  "objects": ["#,
+                sphere,
+                gray,
+                "  ]",
+                "}",
+            ]),
+            (4, msg.clone())
+        );
+        assert_eq!(
+            error(&[
+                "{",
+                CAMERA,
+                r#"  "environment": { "file": "sky.hdr" },"#,
+                r#"  "objects": ["#,
+                sphere,
+                gray,
+                "  ],",
+                r#"  "sky": { "elevation": 30 }"#,
+                "}",
+            ]),
+            (8, msg)
+        );
+    }
+
+    #[test]
+    fn dispersion_needs_a_real_index() {
+        let glass = |coefficients: &str| {
+            error(&[
+                "{",
+                CAMERA,
+                r#"  "materials": {"#,
+                r#"    "glass": {"#,
+                r#"      "type": "dielectric","#,
+                coefficients,
+                "    }",
+                "  },",
+                r#"  "objects": []"#,
+                "}",
+            ])
+        };
+        // an index below one
+        let (line, msg) = glass(r#"      "cauchy": { "a": 0.9, "b": 0.004 }"#);
+        assert_eq!(line, 6);
+        assert!(msg.contains("at 380 nm"), "{}", msg);
+        // a resonance at 550 nm, inside the visible range, below which the index falls under
+        // one and then has no real value
+        let (line, msg) =
+            glass(r#"      "sellmeier": { "b": [1.0, 0.2, 1.0], "c": [0.3025, 0.02, 100] }"#);
+        assert_eq!(line, 6);
+        assert!(
+            msg.starts_with("the coefficients give an index of refraction of"),
+            "{}",
+            msg
+        );
+        let (_, msg) =
+            glass(r#"      "sellmeier": { "b": [4.0, 0.0, 0.0], "c": [0.3025, 0.0, 0.0] }"#);
+        assert!(msg.contains("index of refraction of NaN"), "{}", msg);
+    }
+
+    #[test]
+    fn the_example_scenes_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let mut rng = StdRng::seed_from_u64(0);
+        for name in &["cornell_box", "dispersion", "fog", "motion", "sky"] {
+            let path = dir.join(format!("{}.json", name));
+            if let Err(e) = Scene::load(path.to_str().unwrap(), &mut rng) {
+                panic!("{}", e);
+            }
+        }
+    }
+}
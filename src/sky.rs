@@ -0,0 +1,327 @@
+use crate::environment::{direction, Environment};
+use crate::sampler::Sampler;
+use crate::spectrum::xyz_to_srgb;
+use crate::tonemap::luminance;
+use crate::Onb;
+use crate::Vec3;
+use std::f64::consts::PI;
+
+// angular radius of the sun in radians
+const SUN_RADIUS: f64 = 0.004_65;
+// luminance of the sun above the atmosphere, in kcd/m^2 like the sky
+const SUN_LUMINANCE: f64 = 2.0e6;
+// wavelengths in micrometres that stand for red, green and blue when the atmosphere dims the sun
+const WAVELENGTHS: [f64; 3] = [0.61, 0.55, 0.465];
+// the map the sky is drawn from; an even height keeps the horizon between two rows
+const MAP_WIDTH: usize = 128;
+const MAP_HEIGHT: usize = 64;
+
+// the clear sky of Preetham, Shirley and Smits (1999), in kcd/m^2 times an intensity, with
+// the sun as a disk dimmed by the air it shines through and a diffuse ground below the horizon
+// lit by both; directions are drawn towards the sun or from a map of the sky without it
+pub struct Sky {
+    model: Model,
+    // towards the middle of the sun and the cosine of its angular radius
+    sun: Vec3,
+    cos_sun: f64,
+    sun_radiance: Vec3,
+    ground: Vec3,
+    map: Environment,
+    // probability of drawing a direction towards the sun
+    sun_weight: f64,
+}
+
+// the luminance and chromaticity of the sky by the formulas of the paper
+struct Model {
+    sun: Vec3,
+    // coefficients A to E of the Perez function for Y, x and y
+    perez: [[f64; 5]; 3],
+    // Y, x and y at the zenith divided by the Perez function there
+    zenith: [f64; 3],
+    intensity: f64,
+}
+
+impl Model {
+    fn radiance(&self, d: Vec3) -> Vec3 {
+        let gamma = crate::clamp(d * self.sun, -1.0, 1.0).acos();
+        let mut yxy = [0.0; 3];
+        for (k, c) in yxy.iter_mut().enumerate() {
+            *c = self.zenith[k] * perez(&self.perez[k], d.y, gamma);
+        }
+        let [lum, x, y] = yxy;
+        let rgb = xyz_to_srgb(Vec3::new(x / y * lum, lum, (1.0 - x - y) / y * lum));
+        Vec3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0)) * self.intensity
+    }
+}
+
+// relative brightness at cos_theta from the zenith and gamma from the sun
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / cos_theta.max(1e-3)).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+impl Sky {
+    // angles in degrees, azimuth 0 puts the sun towards +x and 90 towards +z; turbidity is
+    // the haze of the air, 2 for a very clear day to 10 for a hazy one
+    pub fn new(
+        elevation: f64,
+        azimuth: f64,
+        turbidity: f64,
+        albedo: f64,
+        intensity: f64,
+    ) -> Result<Self, String> {
+        if !(0.0..=90.0).contains(&elevation) {
+            return Err(String::from(
+                "the sun elevation must be from 0 to 90 degrees",
+            ));
+        }
+        if !(2.0..=10.0).contains(&turbidity) {
+            return Err(String::from("the turbidity must be from 2 to 10"));
+        }
+        if !(0.0..=1.0).contains(&albedo) {
+            return Err(String::from("the ground albedo must be from 0 to 1"));
+        }
+        if intensity.is_nan() || intensity < 0.0 {
+            return Err(String::from("the sky intensity must not be negative"));
+        }
+        // of the sun from the zenith
+        let theta = (90.0 - elevation).to_radians();
+        let (elev, azim) = (elevation.to_radians(), azimuth.to_radians());
+        let sun = Vec3::new(elev.cos() * azim.cos(), elev.sin(), elev.cos() * azim.sin());
+
+        let perez_coeffs = [
+            [
+                0.1787 * turbidity - 1.4630,
+                -0.3554 * turbidity + 0.4275,
+                -0.0227 * turbidity + 5.3251,
+                0.1206 * turbidity - 2.5771,
+                -0.0670 * turbidity + 0.3703,
+            ],
+            [
+                -0.0193 * turbidity - 0.2592,
+                -0.0665 * turbidity + 0.0008,
+                -0.0004 * turbidity + 0.2125,
+                -0.0641 * turbidity - 0.8989,
+                -0.0033 * turbidity + 0.0452,
+            ],
+            [
+                -0.0167 * turbidity - 0.2608,
+                -0.0950 * turbidity + 0.0092,
+                -0.0079 * turbidity + 0.2102,
+                -0.0441 * turbidity - 1.6537,
+                -0.0109 * turbidity + 0.0529,
+            ],
+        ];
+        let chi = (4.0 / 9.0 - turbidity / 120.0) * (PI - 2.0 * theta);
+        let poly = |c: [f64; 4]| ((c[0] * theta + c[1]) * theta + c[2]) * theta + c[3];
+        let mut zenith = [
+            (4.0453 * turbidity - 4.9710) * chi.tan() - 0.2155 * turbidity + 2.4192,
+            turbidity * turbidity * poly([0.00166, -0.00375, 0.00209, 0.0])
+                + turbidity * poly([-0.02903, 0.06377, -0.03202, 0.00394])
+                + poly([0.11693, -0.21196, 0.06052, 0.25886]),
+            turbidity * turbidity * poly([0.00275, -0.00610, 0.00317, 0.0])
+                + turbidity * poly([-0.04214, 0.08970, -0.04153, 0.00516])
+                + poly([0.15346, -0.26756, 0.06670, 0.26688]),
+        ];
+        for (z, c) in zenith.iter_mut().zip(perez_coeffs.iter()) {
+            *z /= perez(c, 1.0, theta);
+        }
+        let model = Model {
+            sun,
+            perez: perez_coeffs,
+            zenith,
+            intensity,
+        };
+
+        // rayleigh scattering and aerosols over the air mass of Kasten and Young
+        let beta = 0.04608 * turbidity - 0.04586;
+        let air_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let tau = |l: f64| (-air_mass * (0.008_735 * l.powf(-4.08) + beta * l.powf(-1.3))).exp();
+        let sun_radiance = Vec3::new(
+            tau(WAVELENGTHS[0]),
+            tau(WAVELENGTHS[1]),
+            tau(WAVELENGTHS[2]),
+        ) * (SUN_LUMINANCE * intensity);
+        let cos_sun = SUN_RADIUS.cos();
+        let sun_solid_angle = 2.0 * PI * (1.0 - cos_sun);
+
+        // the sky at the texel centres, and what it and the sun shine onto the ground
+        let mut texels = Vec::with_capacity(MAP_WIDTH * MAP_HEIGHT);
+        let mut solid_angles = Vec::with_capacity(MAP_WIDTH * MAP_HEIGHT);
+        let mut irradiance = sun_radiance * (sun_solid_angle * sun.y);
+        for j in 0..MAP_HEIGHT {
+            for i in 0..MAP_WIDTH {
+                let dir = direction(
+                    (i as f64 + 0.5) / MAP_WIDTH as f64,
+                    1.0 - (j as f64 + 0.5) / MAP_HEIGHT as f64,
+                );
+                let solid_angle =
+                    2.0 * PI * PI / (MAP_WIDTH * MAP_HEIGHT) as f64 * (1.0 - dir.y * dir.y).sqrt();
+                let color = if dir.y > 0.0 {
+                    model.radiance(dir)
+                } else {
+                    Vec3::zero()
+                };
+                irradiance += color * (dir.y.max(0.0) * solid_angle);
+                texels.push(color);
+                solid_angles.push(solid_angle);
+            }
+        }
+        let ground = irradiance * (albedo / PI);
+        for c in texels.iter_mut().skip(MAP_WIDTH * MAP_HEIGHT / 2) {
+            *c = ground;
+        }
+
+        let sky_power: f64 = texels
+            .iter()
+            .zip(&solid_angles)
+            .map(|(&c, &w)| luminance(c) * w)
+            .sum();
+        let sun_power = luminance(sun_radiance) * sun_solid_angle;
+        // both stay in use however unevenly the power is split
+        let sun_weight = if sun_power + sky_power > 0.0 {
+            crate::clamp(sun_power / (sun_power + sky_power), 0.1, 0.9)
+        } else {
+            0.5
+        };
+        Ok(Self {
+            model,
+            sun,
+            cos_sun,
+            sun_radiance,
+            ground,
+            map: Environment::new(MAP_WIDTH, MAP_HEIGHT, texels, 1.0, 0.0),
+            sun_weight,
+        })
+    }
+
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let d = dir.unit();
+        if d.y <= 0.0 {
+            return self.ground;
+        }
+        let sky = self.model.radiance(d);
+        if d * self.sun >= self.cos_sun {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    // pdf per solid angle with which sample picks dir
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let d = dir.unit();
+        let cone = if d * self.sun >= self.cos_sun {
+            1.0 / (2.0 * PI * (1.0 - self.cos_sun))
+        } else {
+            0.0
+        };
+        self.sun_weight * cone + (1.0 - self.sun_weight) * self.map.pdf(d)
+    }
+
+    pub fn sample(&self, s: &mut dyn Sampler) -> Vec3 {
+        if s.get_1d() >= self.sun_weight {
+            return self.map.sample(s);
+        }
+        // uniformly within the cone of the sun
+        let (a, b) = s.get_2d();
+        let cos = 1.0 - a * (1.0 - self.cos_sun);
+        let sin = (1.0 - cos * cos).sqrt();
+        let phi = 2.0 * PI * b;
+        Onb::build_from_w(self.sun).local(Vec3::new(phi.cos() * sin, phi.sin() * sin, cos))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerKind, Sampling};
+
+    fn sky(elevation: f64, turbidity: f64, albedo: f64) -> Sky {
+        Sky::new(elevation, 0.0, turbidity, albedo, 1.0).unwrap()
+    }
+
+    // towards elevation and azimuth in degrees, azimuth 0 along +x as for the sun
+    fn towards(elevation: f64, azimuth: f64) -> Vec3 {
+        let (elev, azim) = (elevation.to_radians(), azimuth.to_radians());
+        Vec3::new(elev.cos() * azim.cos(), elev.sin(), elev.cos() * azim.sin())
+    }
+
+    #[test]
+    fn zenith_has_the_luminance_of_the_paper() {
+        for &(elevation, turbidity) in [(30.0, 2.0), (60.0, 5.0), (80.0, 10.0)].iter() {
+            let theta = (90.0 - elevation as f64).to_radians();
+            let chi = (4.0 / 9.0 - turbidity / 120.0) * (PI - 2.0 * theta);
+            let expected = (4.0453 * turbidity - 4.9710) * chi.tan() - 0.2155 * turbidity + 2.4192;
+            let lum = luminance(sky(elevation, turbidity, 0.3).radiance(towards(90.0, 0.0)));
+            assert!(
+                (lum - expected).abs() < 1e-3 * expected,
+                "{} against {} at elevation {} and turbidity {}",
+                lum,
+                expected,
+                elevation,
+                turbidity
+            );
+        }
+    }
+
+    #[test]
+    fn sky_is_brightest_around_the_sun() {
+        let sky = sky(30.0, 3.0, 0.3);
+        let near = luminance(sky.radiance(towards(35.0, 0.0)));
+        let far = luminance(sky.radiance(towards(35.0, 180.0)));
+        assert!(near > 2.0 * far, "{} near the sun, {} opposite", near, far);
+    }
+
+    #[test]
+    fn ground_reflects_its_albedo() {
+        let down = towards(-30.0, 45.0);
+        assert_eq!(luminance(sky(45.0, 3.0, 0.0).radiance(down)), 0.0);
+        let half = sky(45.0, 3.0, 0.5).radiance(down);
+        let full = sky(45.0, 3.0, 1.0).radiance(down);
+        assert!(luminance(half) > 0.0);
+        assert!((luminance(full) - 2.0 * luminance(half)).abs() < 1e-9 * luminance(full));
+    }
+
+    #[test]
+    fn sun_is_a_disk_reddened_near_the_horizon() {
+        let clear = sky(40.0, 3.0, 0.3);
+        let inside = clear.radiance(towards(40.0 + 0.2, 0.0));
+        let outside = clear.radiance(towards(40.0 + 0.4, 0.0));
+        let sun = inside - outside;
+        assert!((luminance(sun) - luminance(clear.sun_radiance)).abs() < 0.01 * luminance(sun));
+
+        let low = sky(5.0, 3.0, 0.3).sun_radiance;
+        let high = sky(60.0, 3.0, 0.3).sun_radiance;
+        assert!(low.x / low.z > high.x / high.z);
+        assert!(luminance(low) < luminance(high));
+    }
+
+    #[test]
+    fn samples_find_the_sun_as_often_as_the_pdf_says() {
+        let sky = sky(20.0, 3.0, 0.3);
+        let mut sampler = Sampling::new(SamplerKind::Independent, 3, 1).sampler();
+        let cone = 1.0 / (2.0 * PI * (1.0 - sky.cos_sun));
+        let n = 4000;
+        let mut in_sun = 0;
+        for k in 0..n {
+            sampler.start((0, 0), k);
+            let dir = sky.sample(&mut *sampler);
+            let pdf = sky.pdf(dir);
+            assert!(pdf > 0.0, "a sample towards {} has pdf 0", dir);
+            if dir.unit() * sky.sun >= sky.cos_sun {
+                in_sun += 1;
+                assert!(pdf >= sky.sun_weight * cone);
+            }
+        }
+        let fraction = in_sun as f64 / n as f64;
+        assert!(
+            (fraction - sky.sun_weight).abs() < 0.03,
+            "{} of the samples in the sun, drawn with weight {}",
+            fraction,
+            sky.sun_weight
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::Vec3;
+
+// the visible range wavelengths are drawn from, in nm
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// linear srgb of the constant spectrum of one under the matching functions below; results are
+// divided by it, so white stays white
+const WHITE: [f64; 3] = [128.361_023, 101.538_081, 97.064_801];
+
+// the wavelengths in nm a path carries, one for each channel of its radiance; the first is the
+// hero wavelength, the one that is kept when a dispersive surface sends the others elsewhere
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    pub lambda: [f64; 3],
+    single: bool,
+}
+
+impl Wavelengths {
+    // evenly spaced over the visible range, rotated by the sample value u
+    pub fn sample(u: f64) -> Self {
+        let mut lambda = [0.0; 3];
+        for (k, l) in lambda.iter_mut().enumerate() {
+            let t = (u + k as f64 / 3.0).fract();
+            *l = LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * t;
+        }
+        Self {
+            lambda,
+            single: false,
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    // the path goes on with the hero wavelength alone, which stands in for all three
+    pub fn drop_secondary(&mut self, throughput: Vec3) -> Vec3 {
+        if self.single {
+            return throughput;
+        }
+        self.single = true;
+        Vec3::new(3.0 * throughput.x, 0.0, 0.0)
+    }
+
+    // a reflectance or emission given in rgb, as a spectrum sampled at the wavelengths
+    pub fn upsample(&self, rgb: Vec3) -> Vec3 {
+        let [a, b, c] = self.lambda;
+        Vec3::new(smits(rgb, a), smits(rgb, b), smits(rgb, c))
+    }
+
+    // CIE XYZ of the radiance carried at the wavelengths, an estimate of its integral against
+    // the matching functions over the visible range
+    pub fn to_xyz(self, radiance: Vec3) -> Vec3 {
+        let mut ret = Vec3::zero();
+        for (k, &lambda) in self.lambda.iter().enumerate() {
+            ret += cmf(lambda) * (radiance.get(k as u32) * (LAMBDA_MAX - LAMBDA_MIN) / 3.0);
+        }
+        ret
+    }
+}
+
+// linear srgb balanced so the constant spectrum is white
+pub fn xyz_to_rgb(c: Vec3) -> Vec3 {
+    let c = xyz_to_srgb(c);
+    Vec3::new(c.x / WHITE[0], c.y / WHITE[1], c.z / WHITE[2])
+}
+
+// linear srgb with its own d65 white point
+pub fn xyz_to_srgb(c: Vec3) -> Vec3 {
+    Vec3::new(
+        3.240_454_2 * c.x - 1.537_138_5 * c.y - 0.498_531_4 * c.z,
+        -0.969_266_0 * c.x + 1.876_010_8 * c.y + 0.041_556_0 * c.z,
+        0.055_643_4 * c.x - 0.204_025_9 * c.y + 1.057_225_2 * c.z,
+    )
+}
+
+// the CIE 1931 matching functions as sums of piecewise gaussians (Wyman, Sloan and Shirley 2013)
+fn cmf(lambda: f64) -> Vec3 {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let t = (lambda - mu) / if lambda < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// the basis spectra of Smits 1999, sampled evenly from 380 to 720 nm
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// value of an rgb color at lambda: white up to its smallest channel, then the secondary and
+// primary that take it to the other two
+fn smits(rgb: Vec3, lambda: f64) -> f64 {
+    let at = |basis: &[f64; 10]| {
+        let x = crate::clamp((lambda - 380.0) / (720.0 - 380.0) * 9.0, 0.0, 9.0);
+        let k = (x as usize).min(8);
+        let f = x - k as f64;
+        basis[k] * (1.0 - f) + basis[k + 1] * f
+    };
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    if r <= g && r <= b {
+        r * at(&SMITS_WHITE)
+            + if g <= b {
+                (g - r) * at(&SMITS_CYAN) + (b - g) * at(&SMITS_BLUE)
+            } else {
+                (b - r) * at(&SMITS_CYAN) + (g - b) * at(&SMITS_GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * at(&SMITS_WHITE)
+            + if r <= b {
+                (r - g) * at(&SMITS_MAGENTA) + (b - r) * at(&SMITS_BLUE)
+            } else {
+                (b - g) * at(&SMITS_MAGENTA) + (r - b) * at(&SMITS_RED)
+            }
+    } else {
+        b * at(&SMITS_WHITE)
+            + if r <= g {
+                (r - b) * at(&SMITS_YELLOW) + (g - r) * at(&SMITS_GREEN)
+            } else {
+                (g - b) * at(&SMITS_YELLOW) + (r - g) * at(&SMITS_RED)
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tonemap::luminance;
+
+    // the mean over evenly spread hero wavelengths of what `rgb` comes back as after being
+    // upsampled and integrated against the matching functions
+    fn round_trip(rgb: Vec3) -> Vec3 {
+        let n = 1000;
+        let mut sum = Vec3::zero();
+        for k in 0..n {
+            let wl = Wavelengths::sample((k as f64 + 0.5) / n as f64);
+            sum += xyz_to_rgb(wl.to_xyz(wl.upsample(rgb)));
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let white = round_trip(Vec3::new(1.0, 1.0, 1.0));
+        for k in 0..3 {
+            assert!((white.get(k) - 1.0).abs() < 2e-3, "{}", white);
+        }
+        let gray = round_trip(Vec3::new(0.25, 0.25, 0.25));
+        assert!((gray - white * 0.25).length() < 1e-12);
+    }
+
+    #[test]
+    fn primaries_keep_their_hue() {
+        let primaries = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        for (k, &rgb) in primaries.iter().enumerate() {
+            let back = round_trip(rgb);
+            for c in 0..3 {
+                if c == k as u32 {
+                    assert!(back.get(c) > 0.75, "{} from {}", back, rgb);
+                } else {
+                    assert!(back.get(c) < 0.25, "{} from {}", back, rgb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matching_functions_fit_cie_1931() {
+        // the luminous efficiency peaks at one near 555 nm, and x at 1.06 near 600 nm
+        assert!((cmf(555.0).y - 1.0).abs() < 0.01);
+        assert!((cmf(600.0).x - 1.06).abs() < 0.02);
+        // the tabulated y sums to 106.86 over the visible range in steps of 1 nm
+        let y: f64 = (380..=780).map(|l| cmf(l as f64).y).sum();
+        assert!((y - 106.86).abs() < 0.01 * 106.86, "{}", y);
+        // which is what WHITE holds as luminance
+        let white = Vec3::new(WHITE[0], WHITE[1], WHITE[2]);
+        assert!((luminance(white) - y).abs() < 0.01 * y);
+    }
+}
@@ -1,10 +1,21 @@
 use crate::Vec3;
+use std::sync::Arc;
 
-pub trait Texture {
+pub trait Texture: Send + Sync {
     fn color(&self, u: f64, v: f64, p: Vec3) -> Vec3;
     fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3;
 }
 
+impl Texture for Arc<dyn Texture> {
+    fn color(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        (**self).color(u, v, p)
+    }
+
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        (**self).value(u, v, p)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SolidColor {
     color: Vec3,
@@ -0,0 +1,216 @@
+use crate::clamp;
+use crate::Vec3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Linear,
+    Reinhard,
+    ReinhardExtended,
+    Aces,
+    Hable,
+}
+
+impl Operator {
+    pub const NAMES: [&'static str; 5] =
+        ["linear", "reinhard", "reinhard_extended", "aces", "hable"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(Operator::Linear),
+            "reinhard" => Some(Operator::Reinhard),
+            "reinhard_extended" => Some(Operator::ReinhardExtended),
+            "aces" => Some(Operator::Aces),
+            "hable" => Some(Operator::Hable),
+            _ => None,
+        }
+    }
+}
+
+// maps scene-referred linear radiance to display-referred linear values in [0, 1]
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMapper {
+    pub op: Operator,
+    pub exposure: f64,
+    // radiance that maps to white; reinhard_extended defaults to the brightest pixel
+    pub white: Option<f64>,
+}
+
+// radiance the filmic curve maps to white by default, its linear white point of 11.2 taken
+// back through the exposure bias of 2 it applies first
+const HABLE_WHITE: f64 = 5.6;
+
+pub fn luminance(c: Vec3) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn scale_luminance(c: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    let l = luminance(c);
+    if l <= 0.0 {
+        return Vec3::zero();
+    }
+    c * (f(l) / l)
+}
+
+fn per_channel(c: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    Vec3::new(f(c.x), f(c.y), f(c.z))
+}
+
+// Narkowicz's fit of the ACES reference rendering transform
+fn aces(x: f64) -> f64 {
+    let x = x * 0.6;
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+// filmic curve from Uncharted 2
+const SHOULDER: f64 = 0.15;
+const LINEAR: f64 = 0.50;
+const LINEAR_ANGLE: f64 = 0.10;
+const TOE: f64 = 0.20;
+const TOE_NUMERATOR: f64 = 0.02;
+const TOE_DENOMINATOR: f64 = 0.30;
+
+fn hable_partial(x: f64) -> f64 {
+    let num = x * (SHOULDER * x + LINEAR_ANGLE * LINEAR) + TOE * TOE_NUMERATOR;
+    let den = x * (SHOULDER * x + LINEAR) + TOE * TOE_DENOMINATOR;
+    num / den - TOE_NUMERATOR / TOE_DENOMINATOR
+}
+
+impl ToneMapper {
+    pub fn new(op: Operator, exposure: f64, white: Option<f64>) -> Self {
+        Self {
+            op,
+            exposure,
+            white,
+        }
+    }
+
+    fn map(&self, c: Vec3, white: f64) -> Vec3 {
+        let c = c * 2f64.powf(self.exposure);
+        match self.op {
+            Operator::Linear => c,
+            Operator::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            Operator::ReinhardExtended => {
+                scale_luminance(c, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            Operator::Aces => per_channel(c, aces),
+            Operator::Hable => {
+                per_channel(c, |x| hable_partial(2.0 * x) / hable_partial(2.0 * white))
+            }
+        }
+    }
+
+    pub fn apply(&self, buf: &[Vec3]) -> Vec<Vec3> {
+        let white = match self.white {
+            Some(w) => w,
+            None if self.op == Operator::Hable => HABLE_WHITE,
+            None => {
+                let scale = 2f64.powf(self.exposure);
+                let max = buf
+                    .iter()
+                    .map(|c| luminance(*c) * scale)
+                    .fold(0.0, f64::max);
+                if max > 0.0 {
+                    max
+                } else {
+                    1.0
+                }
+            }
+        };
+        buf.iter()
+            .map(|c| per_channel(self.map(*c, white), |x| clamp(x, 0.0, 1.0)))
+            .collect()
+    }
+}
+
+pub fn srgb_oetf(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn to_srgb8(c: Vec3) -> [u8; 3] {
+    let q = |x: f64| (clamp(srgb_oetf(x), 0.0, 0.999) * 256.0) as u8;
+    [q(c.x), q(c.y), q(c.z)]
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    fn gray(x: f64) -> Vec3 {
+        Vec3::new(x, x, x)
+    }
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-12
+    }
+
+    #[test]
+    fn linear_at_exposure_zero_is_the_identity() {
+        let tm = ToneMapper::new(Operator::Linear, 0.0, None);
+        let buf = vec![gray(0.0), Vec3::new(0.1, 0.5, 0.9), gray(1.0)];
+        let out = tm.apply(&buf);
+        assert!(buf.iter().zip(&out).all(|(&a, &b)| close(a, b)));
+        // one stop up doubles, and what ends up above one is clipped
+        let tm = ToneMapper::new(Operator::Linear, 1.0, None);
+        let out = tm.apply(&buf);
+        assert!(close(out[1], Vec3::new(0.2, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn reinhard_halves_unit_luminance() {
+        let tm = ToneMapper::new(Operator::Reinhard, 0.0, None);
+        let out = tm.apply(&[gray(1.0), Vec3::new(2.0, 0.8, 1.5)]);
+        assert!(close(out[0], gray(0.5)));
+        let color = Vec3::new(2.0, 0.8, 1.5);
+        let lum = luminance(color);
+        assert!(close(out[1], color * (1.0 / (1.0 + lum))));
+    }
+
+    #[test]
+    fn white_maps_to_one() {
+        for &op in [Operator::ReinhardExtended, Operator::Hable].iter() {
+            let tm = ToneMapper::new(op, 0.0, Some(4.0));
+            let out = tm.apply(&[gray(4.0), gray(2.0)]);
+            assert!((out[0].x - 1.0).abs() < 1e-12, "{:?} gives {}", op, out[0]);
+            assert!(out[1].x > 0.0 && out[1].x < 1.0);
+        }
+        // with no white given, hable keeps the white point of its filmic curve
+        let tm = ToneMapper::new(Operator::Hable, 0.0, None);
+        assert!((tm.apply(&[gray(HABLE_WHITE)])[0].x - 1.0).abs() < 1e-12);
+        // and reinhard_extended takes the brightest pixel
+        let tm = ToneMapper::new(Operator::ReinhardExtended, 0.0, None);
+        let out = tm.apply(&[gray(0.25), gray(3.0)]);
+        assert!((out[1].x - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn curves_rise_from_zero() {
+        for name in Operator::NAMES.iter() {
+            let tm = ToneMapper::new(Operator::from_name(name).unwrap(), 0.0, Some(8.0));
+            let xs: Vec<f64> = (0..=40).map(|k| k as f64 * 0.25).collect();
+            let out = tm.apply(&xs.iter().map(|&x| gray(x)).collect::<Vec<_>>());
+            assert!(out[0].x.abs() < 1e-12, "{} at 0 gives {}", name, out[0]);
+            for pair in out.windows(2) {
+                assert!(pair[1].x >= pair[0].x, "{} falls", name);
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_encoding() {
+        let knee = 0.003_130_8;
+        let (below, above) = (srgb_oetf(knee), 1.055 * knee.powf(1.0 / 2.4) - 0.055);
+        assert!((below - above).abs() < 1e-6, "{} against {}", below, above);
+        assert!((srgb_oetf(knee + 1e-9) - below).abs() < 1e-6);
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-12);
+
+        assert_eq!(to_srgb8(gray(0.0)), [0, 0, 0]);
+        assert_eq!(to_srgb8(gray(1.0)), [255, 255, 255]);
+        assert_eq!(to_srgb8(Vec3::new(-1.0, 0.5, 2.0)), [0, 188, 255]);
+    }
+}
@@ -1,8 +1,10 @@
+use crate::sampler::Sampler;
 use std::ops::{Add, AddAssign};
 
 //use crate::rand;
 
 extern crate rand;
+use rand::rngs::StdRng;
 use rand::Rng;
 
 #[derive(Clone, Debug, PartialEq, Copy)]
@@ -12,6 +14,12 @@ pub struct Vec3 {
     pub z: f64,
 }
 
+impl std::fmt::Display for Vec3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
 impl Vec3 {
     pub fn new(x: f64, y: f64, z: f64) -> Self {
         Self { x, y, z }
@@ -29,6 +37,10 @@ impl Vec3 {
         self.x * self.x + self.y * self.y + self.z * self.z
     }
 
+    pub fn max_component(self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+
     pub fn get(self, i: u32) -> f64 {
         if i == 0 {
             self.x
@@ -231,16 +243,11 @@ impl Vec3 {
 }
 
 impl Vec3 {
-    pub fn random1() -> Vec3 {
-        Vec3::new(
-            rand::random::<f64>(),
-            rand::random::<f64>(),
-            rand::random::<f64>(),
-        )
+    pub fn random1(rng: &mut StdRng) -> Vec3 {
+        Vec3::new(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>())
     }
 
-    pub fn random2(mi: f64, ma: f64) -> Vec3 {
-        let mut rng = rand::thread_rng();
+    pub fn random2(mi: f64, ma: f64, rng: &mut StdRng) -> Vec3 {
         Vec3::new(
             rng.gen_range(mi, ma),
             rng.gen_range(mi, ma),
@@ -248,44 +255,38 @@ impl Vec3 {
         )
     }
 
-    pub fn random_in_unit_sphere() -> Vec3 {
-        loop {
-            let p = Vec3::random2(-1.0, 1.0);
-            if p.length_squared() < 1.0 {
-                return p;
-            }
-        }
+    // uniform in the unit ball, from three sample dimensions
+    pub fn random_in_unit_sphere(s: &mut dyn Sampler) -> Vec3 {
+        Vec3::random_unit_vec(s) * s.get_1d().cbrt()
     }
 
-    pub fn random_unit_vec() -> Vec3 {
-        let mut rng = rand::thread_rng();
-        let a = rng.gen_range(0.0, 2.0 * std::f64::consts::PI);
-        let z = rng.gen_range(-1.0, 1.0);
+    pub fn random_unit_vec(s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
+        let a = 2.0 * std::f64::consts::PI * r1;
+        let z = 2.0 * r2 - 1.0;
         let r: f64 = ((1.0 - z * z) as f64).sqrt();
         Vec3::new(r * a.cos(), r * a.sin(), z)
     }
 
-    pub fn _random_in_hemisphere(normal: &Vec3) -> Vec3 {
-        let in_unit_sphere = Vec3::random_in_unit_sphere();
+    pub fn _random_in_hemisphere(normal: &Vec3, s: &mut dyn Sampler) -> Vec3 {
+        let in_unit_sphere = Vec3::random_in_unit_sphere(s);
         if in_unit_sphere * *normal > 0.0 {
             return in_unit_sphere;
         }
         -in_unit_sphere
     }
 
-    pub fn random_in_unit_disk() -> Vec3 {
-        let mut rng = rand::thread_rng();
-        loop {
-            let p = Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0);
-            if p.length_squared() < 1.0 {
-                return p;
-            }
-        }
+    // uniform in the unit disk; a direct mapping rather than rejection, so each sample uses
+    // exactly two dimensions
+    pub fn random_in_unit_disk(s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
+        let r = r1.sqrt();
+        let phi = 2.0 * std::f64::consts::PI * r2;
+        Vec3::new(r * phi.cos(), r * phi.sin(), 0.0)
     }
 
-    pub fn random_to_sphere(radius: f64, dis_sq: f64) -> Vec3 {
-        let r1 = rand::random::<f64>();
-        let r2 = rand::random::<f64>();
+    pub fn random_to_sphere(radius: f64, dis_sq: f64, s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
         let z = 1.0 + r2 * ((1.0 - radius * radius / dis_sq).sqrt() - 1.0);
 
         let phi = 2.0 * r1 * std::f64::consts::PI;
@@ -295,9 +296,8 @@ impl Vec3 {
         Vec3::new(x, y, z)
     }
 
-    pub fn random_cosine_direction() -> Vec3 {
-        let r1 = rand::random::<f64>();
-        let r2 = rand::random::<f64>();
+    pub fn random_cosine_direction(s: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = s.get_2d();
         let z = (1.0 - r2).sqrt();
         let phi = 2.0 * std::f64::consts::PI * r1;
         let x = phi.cos() * r2.sqrt();
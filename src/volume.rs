@@ -0,0 +1,439 @@
+use crate::object::{medium_id, medium_seed, ray_random};
+use crate::HitRecord;
+use crate::Material;
+use crate::Object;
+use crate::Ray;
+use crate::Vec3;
+use crate::AABB;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// a voxel grid of densities over the unit cube, with an optional second channel of emission;
+// values sit at the cell centres and are interpolated trilinearly in between
+//
+// grids are read either from the text format below or from the binary .vol files of Mitsuba
+// (version 3, float32 data, one or two channels), told apart by the "VOL" magic
+//
+//     # comments run to the end of the line
+//     grid dense              dense or sparse
+//     nx ny nz channels       channels is 1 (density) or 2 (density and emission)
+//     ...
+//
+// a dense grid then lists nx * ny * nz voxels, x varying fastest, then y, then z, each with
+// its channels; a sparse grid lists `i j k` and the channels of every voxel that is not zero
+pub struct Grid {
+    res: [usize; 3],
+    density: Vec<f32>,
+    emission: Option<Vec<f32>>,
+}
+
+impl Grid {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let grid = if bytes.starts_with(b"VOL") {
+            Grid::from_vol(&bytes)
+        } else {
+            match String::from_utf8(bytes) {
+                Ok(text) => Grid::from_text(&text),
+                Err(_) => Err(String::from("neither a text grid nor a .vol file")),
+            }
+        };
+        grid.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn from_text(text: &str) -> Result<Self, String> {
+        let mut tokens = text
+            .lines()
+            .enumerate()
+            .flat_map(|(i, line)| {
+                let line = line.split('#').next().unwrap_or("");
+                line.split_whitespace().map(move |t| (i + 1, t))
+            })
+            .peekable();
+        if next::<String>(&mut tokens, "`grid`")? != "grid" {
+            return Err(String::from("expected a `grid` header"));
+        }
+        let sparse = match next::<String>(&mut tokens, "dense or sparse")?.as_str() {
+            "dense" => false,
+            "sparse" => true,
+            other => return Err(format!("expected dense or sparse, found `{}`", other)),
+        };
+        let mut res = [0; 3];
+        for n in res.iter_mut() {
+            *n = next(&mut tokens, "a resolution")?;
+        }
+        let channels: usize = next(&mut tokens, "a channel count")?;
+        // every value of a dense grid takes at least a byte, so a resolution the text cannot
+        // hold is caught before it is allocated
+        let dense_values = voxels(res).and_then(|n| n.checked_mul(channels));
+        if !sparse && !matches!(dense_values, Some(n) if n <= text.len()) {
+            return Err(String::from(
+                "unexpected end of file, the grid holds more voxels",
+            ));
+        }
+        let mut values = Values::new(res, channels)?;
+        if sparse {
+            while let Some(&(line, _)) = tokens.peek() {
+                let mut at = [0; 3];
+                for (x, &n) in at.iter_mut().zip(res.iter()) {
+                    *x = next(&mut tokens, "a voxel index")?;
+                    if *x >= n {
+                        return Err(format!("line {}: voxel index {} out of range", line, x));
+                    }
+                }
+                let voxel = (at[2] * res[1] + at[1]) * res[0] + at[0];
+                for c in 0..channels {
+                    values.set(voxel, c, next(&mut tokens, "a voxel value")?)?;
+                }
+            }
+        } else {
+            for voxel in 0..values.len() {
+                for c in 0..channels {
+                    values.set(voxel, c, next(&mut tokens, "a voxel value")?)?;
+                }
+            }
+            if let Some((line, t)) = tokens.next() {
+                return Err(format!(
+                    "line {}: more voxels than the grid holds: `{}`",
+                    line, t
+                ));
+            }
+        }
+        Ok(values.into_grid())
+    }
+
+    fn from_vol(b: &[u8]) -> Result<Self, String> {
+        let word = |at: usize| [b[at], b[at + 1], b[at + 2], b[at + 3]];
+        if b.len() < 48 {
+            return Err(String::from("truncated .vol header"));
+        }
+        if b[3] != 3 {
+            return Err(format!("unsupported .vol version {}", b[3]));
+        }
+        if i32::from_le_bytes(word(4)) != 1 {
+            return Err(String::from("only float32 .vol data is supported"));
+        }
+        let mut res = [0; 3];
+        for (a, n) in res.iter_mut().enumerate() {
+            *n = i32::from_le_bytes(word(8 + 4 * a)).max(0) as usize;
+        }
+        let channels = i32::from_le_bytes(word(20)).max(0) as usize;
+        // the bounding box that follows is ignored, scenes place the grid themselves;
+        // the data has to be there before anything is allocated for it
+        let size = voxels(res)
+            .and_then(|n| n.checked_mul(4 * channels))
+            .and_then(|n| n.checked_add(48));
+        match size {
+            Some(size) if size <= b.len() => {}
+            _ => return Err(String::from("truncated .vol data")),
+        }
+        let mut values = Values::new(res, channels)?;
+        for voxel in 0..values.len() {
+            for c in 0..channels {
+                let at = 48 + 4 * (voxel * channels + c);
+                values.set(voxel, c, f32::from_le_bytes(word(at)))?;
+            }
+        }
+        Ok(values.into_grid())
+    }
+
+    pub fn has_emission(&self) -> bool {
+        self.emission.is_some()
+    }
+
+    pub fn max_density(&self) -> f64 {
+        self.density.iter().fold(0.0f32, |m, &d| m.max(d)) as f64
+    }
+
+    // at q in the unit cube
+    pub fn density(&self, q: Vec3) -> f64 {
+        self.lookup(&self.density, q)
+    }
+
+    pub fn emission(&self, q: Vec3) -> f64 {
+        match &self.emission {
+            Some(e) => self.lookup(e, q),
+            None => 0.0,
+        }
+    }
+
+    fn lookup(&self, values: &[f32], q: Vec3) -> f64 {
+        // the two voxels around q along each axis and how far it is towards the second
+        let mut lo = [0; 3];
+        let mut hi = [0; 3];
+        let mut f = [0.0; 3];
+        for a in 0..3 {
+            let n = self.res[a];
+            let x = crate::clamp(q.get(a as u32) * n as f64 - 0.5, 0.0, (n - 1) as f64);
+            lo[a] = (x as usize).min(n - 1);
+            hi[a] = (lo[a] + 1).min(n - 1);
+            f[a] = x - lo[a] as f64;
+        }
+        let mut ret = 0.0;
+        for corner in 0..8 {
+            let mut w = 1.0;
+            let mut at = [0; 3];
+            for a in 0..3 {
+                if corner >> a & 1 == 1 {
+                    at[a] = hi[a];
+                    w *= f[a];
+                } else {
+                    at[a] = lo[a];
+                    w *= 1.0 - f[a];
+                }
+            }
+            ret += w * values[(at[2] * self.res[1] + at[1]) * self.res[0] + at[0]] as f64;
+        }
+        ret
+    }
+}
+
+fn next<T: FromStr>(
+    tokens: &mut dyn Iterator<Item = (usize, &str)>,
+    what: &str,
+) -> Result<T, String> {
+    match tokens.next() {
+        Some((line, t)) => t
+            .parse()
+            .map_err(|_| format!("line {}: expected {}, found `{}`", line, what, t)),
+        None => Err(format!("unexpected end of file, expected {}", what)),
+    }
+}
+
+// nx * ny * nz, None if that does not fit
+fn voxels(res: [usize; 3]) -> Option<usize> {
+    res[0].checked_mul(res[1])?.checked_mul(res[2])
+}
+
+// the channels of a grid while it is read
+struct Values {
+    res: [usize; 3],
+    channels: Vec<Vec<f32>>,
+}
+
+impl Values {
+    fn new(res: [usize; 3], channels: usize) -> Result<Self, String> {
+        if res.contains(&0) {
+            return Err(String::from("the resolution must be positive"));
+        }
+        if channels != 1 && channels != 2 {
+            return Err(format!(
+                "expected 1 or 2 channels (density and emission), found {}",
+                channels
+            ));
+        }
+        let len = voxels(res).ok_or_else(|| String::from("the grid is too large"))?;
+        Ok(Self {
+            res,
+            channels: vec![vec![0.0; len]; channels],
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.channels[0].len()
+    }
+
+    fn set(&mut self, voxel: usize, channel: usize, value: f32) -> Result<(), String> {
+        if value < 0.0 || !value.is_finite() {
+            return Err(format!(
+                "voxel values must be finite and not negative, found {}",
+                value
+            ));
+        }
+        self.channels[channel][voxel] = value;
+        Ok(())
+    }
+
+    fn into_grid(mut self) -> Grid {
+        let emission = if self.channels.len() == 2 {
+            self.channels.pop()
+        } else {
+            None
+        };
+        Grid {
+            res: self.res,
+            density: self.channels.pop().unwrap(),
+            emission,
+        }
+    }
+}
+
+// a medium whose density, and emission, vary through a box as given by a grid; free flights
+// are sampled by delta tracking and shadow rays estimate transmittance by ratio tracking, both
+// against the largest density in the grid, so both stay unbiased however the density varies
+pub struct GridMedium<M: Material> {
+    grid: Grid,
+    bounds: AABB,
+    // multiplies the densities of the grid
+    scale: f64,
+    majorant: f64,
+    // radiance per unit length that an emission value of one adds
+    emit: Vec3,
+    phase: M,
+    id: u64,
+}
+
+impl<M: Material> GridMedium<M> {
+    pub fn new(grid: Grid, min: Vec3, max: Vec3, scale: f64, emit: Vec3, phase: M) -> Self {
+        let majorant = scale * grid.max_density();
+        let bounds = AABB::new(min, max);
+        Self {
+            grid,
+            bounds,
+            scale,
+            majorant,
+            emit,
+            phase,
+            id: medium_id(Some(bounds), scale),
+        }
+    }
+
+    // p in the unit cube the grid covers
+    fn local(&self, p: Vec3) -> Vec3 {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        Vec3::new(
+            (p.x - min.x) / (max.x - min.x),
+            (p.y - min.y) / (max.y - min.y),
+            (p.z - min.z) / (max.z - min.z),
+        )
+    }
+}
+
+impl<M: Material> Object for GridMedium<M> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if self.majorant <= 0.0 {
+            return None;
+        }
+        let (t_in, t_out) = self.bounds.clip(r, t_min.max(0.0), t_max)?;
+        let step = 1.0 / (self.majorant * r.dir.length());
+        let seed = medium_seed(r, self.id, t_in);
+        let mut t = t_in;
+        let mut k = 0;
+        loop {
+            t -= step * (1.0 - ray_random(seed, k)).ln();
+            if t >= t_out {
+                return None;
+            }
+            let q = self.local(r.at(t));
+            let density = self.scale * self.grid.density(q);
+            // a real collision with probability density / majorant, otherwise a null one that
+            // the ray passes straight through
+            if ray_random(seed, k + 1) * self.majorant < density {
+                return Some(HitRecord {
+                    p: r.at(t),
+                    // arbitrary, phase functions do not look at it
+                    normal: Vec3::new(1.0, 0.0, 0.0),
+                    t,
+                    front_face: true,
+                    mat: Some(&self.phase),
+                    u: 0.0,
+                    v: 0.0,
+                    id: 0,
+                    emission: self.emit * (self.grid.emission(q) / density),
+                });
+            }
+            k += 2;
+        }
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(self.bounds)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> f64 {
+        let (t_in, t_out) = match self.bounds.clip(r, t_min.max(0.0), t_max) {
+            Some(segment) if self.majorant > 0.0 => segment,
+            _ => return 1.0,
+        };
+        let step = 1.0 / (self.majorant * r.dir.length());
+        let seed = medium_seed(r, self.id, t_in);
+        let mut t = t_in;
+        let mut k = 0;
+        let mut tr = 1.0;
+        loop {
+            t -= step * (1.0 - ray_random(seed, k)).ln();
+            k += 1;
+            if t >= t_out {
+                return tr;
+            }
+            let density = self.scale * self.grid.density(self.local(r.at(t)));
+            tr *= 1.0 - density / self.majorant;
+            // russian roulette once little gets through, so dense media end early
+            if tr < 0.1 {
+                if ray_random(seed, k) < 0.5 {
+                    return 0.0;
+                }
+                k += 1;
+                tr *= 2.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Pcg32;
+    use crate::{HittableList, Isotropic, SolidColor};
+    use std::sync::Arc;
+
+    // a grid of density one over the slab from z0 to z1
+    fn slab(z0: f64, z1: f64) -> Arc<dyn Object> {
+        let grid = Grid::from_text("grid dense 1 1 1 1 1").unwrap();
+        let white = Isotropic::new(SolidColor::new(Vec3::new(1.0, 1.0, 1.0)));
+        Arc::new(GridMedium::new(
+            grid,
+            Vec3::new(-1.0, -1.0, z0),
+            Vec3::new(1.0, 1.0, z1),
+            1.0,
+            Vec3::zero(),
+            white,
+        ))
+    }
+
+    fn rays(n: usize) -> Vec<Ray> {
+        let mut rng = Pcg32::new(11, 0);
+        (0..n)
+            .map(|_| {
+                let beg = Vec3::new(rng.next_f64() - 0.5, rng.next_f64() - 0.5, -1.0);
+                Ray::new(beg, Vec3::new(0.0, 0.0, 1.0), 0.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oversized_headers_are_errors() {
+        let mut vol = b"VOL".to_vec();
+        vol.push(3);
+        for x in [1, i32::MAX, i32::MAX, i32::MAX, 1].iter() {
+            vol.extend_from_slice(&x.to_le_bytes());
+        }
+        vol.resize(48, 0);
+        assert!(Grid::from_vol(&vol).is_err());
+        assert!(Grid::from_text("grid dense 100000 100000 100000 1 0").is_err());
+        assert!(Grid::from_text("grid sparse 2 2 2 1 1 1 1 0.5").is_ok());
+    }
+
+    #[test]
+    fn stacked_grids_draw_independently() {
+        let mut world = HittableList::new();
+        world.add(slab(0.0, 1.0));
+        world.add(slab(1.5, 2.5));
+        let rays = rays(40_000);
+        let n = rays.len() as f64;
+        let expected = (-2.0f64).exp();
+
+        let passed = rays
+            .iter()
+            .filter(|&&r| world.hit(r, 0.001, crate::INFINITY).is_none())
+            .count();
+        assert!((passed as f64 / n - expected).abs() < 0.01);
+
+        let tr: f64 = rays
+            .iter()
+            .map(|&r| world.transmittance(r, 0.001, crate::INFINITY))
+            .sum();
+        assert!((tr / n - expected).abs() < 0.01);
+    }
+}